@@ -53,3 +53,15 @@ pub fn read_into_array<const N: usize>(input: &[u8], offset: usize) -> [u8; N] {
     buffer.copy_from_slice(&input[offset..offset + N]);
     buffer
 }
+
+pub fn write_u64_le(output: &mut [u8], offset: usize, value: u64) {
+    output[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32_le(output: &mut [u8], offset: usize, value: u32) {
+    output[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u16_le(output: &mut [u8], offset: usize, value: u16) {
+    output[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,354 @@
+use core::{
+    fmt::Debug,
+    future::{ready, Future},
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use crate::BlockDevice;
+
+/// A block device whose operations complete asynchronously, such as an interrupt-driven disk
+/// controller
+pub trait AsyncBlockDevice {
+    type Error: Debug;
+
+    fn block_size(&self) -> u64;
+
+    /// The total number of blocks on the device
+    fn num_blocks(&self) -> u64;
+
+    fn read_block(
+        &mut self,
+        lba: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+
+    fn write_block(
+        &mut self,
+        lba: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Reads `count` consecutive blocks starting at `lba` into `buffer`
+    fn read_blocks(
+        &mut self,
+        lba: u64,
+        count: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        async move {
+            let block_size = self.block_size() as usize;
+
+            for i in 0..count {
+                // A buffer that is too short hands the device a short block, which it rejects
+                let start = (i as usize * block_size).min(buffer.len());
+                let end = (start + block_size).min(buffer.len());
+                self.read_block(lba + i, &mut buffer[start..end]).await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Writes `count` consecutive blocks starting at `lba` from `buffer`
+    fn write_blocks(
+        &mut self,
+        lba: u64,
+        count: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        async move {
+            let block_size = self.block_size() as usize;
+
+            for i in 0..count {
+                // A buffer that is too short hands the device a short block, which it rejects
+                let start = (i as usize * block_size).min(buffer.len());
+                let end = (start + block_size).min(buffer.len());
+                self.write_block(lba + i, &buffer[start..end]).await?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Makes any synchronous block device usable as an asynchronous one. Every operation is already
+/// complete the first time it is polled.
+pub struct AsyncAdapter<D: BlockDevice> {
+    device: D,
+}
+
+impl<D: BlockDevice> AsyncAdapter<D> {
+    pub fn new(device: D) -> Self {
+        Self { device }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: BlockDevice> AsyncBlockDevice for AsyncAdapter<D> {
+    type Error = D::Error;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(
+        &mut self,
+        lba: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        ready(self.device.read_block(lba, buffer))
+    }
+
+    fn write_block(
+        &mut self,
+        lba: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        ready(self.device.write_block(lba, buffer))
+    }
+
+    fn read_blocks(
+        &mut self,
+        lba: u64,
+        count: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        ready(self.device.read_blocks(lba, count, buffer))
+    }
+
+    fn write_blocks(
+        &mut self,
+        lba: u64,
+        count: u64,
+        buffer: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        ready(self.device.write_blocks(lba, count, buffer))
+    }
+}
+
+/// Makes an asynchronous block device usable by code that expects a [`BlockDevice`], by waiting
+/// for each operation with [`block_on`]
+pub struct BlockingAdapter<A: AsyncBlockDevice> {
+    device: A,
+}
+
+impl<A: AsyncBlockDevice> BlockingAdapter<A> {
+    pub fn new(device: A) -> Self {
+        Self { device }
+    }
+
+    pub fn into_inner(self) -> A {
+        self.device
+    }
+}
+
+impl<A: AsyncBlockDevice> BlockDevice for BlockingAdapter<A> {
+    type Error = A::Error;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        block_on(self.device.read_block(lba, buffer))
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        block_on(self.device.write_block(lba, buffer))
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        block_on(self.device.read_blocks(lba, count, buffer))
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        block_on(self.device.write_blocks(lba, count, buffer))
+    }
+}
+
+/// Runs a future to completion by polling it in a loop, without needing an executor
+///
+/// This spins while the future is pending, so it is only meant for futures that finish quickly,
+/// or for early boot code that has nothing better to do.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::MemoryBlockDevice, Error};
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// Only implements the single-block operations, so the default multi-block loops run. Each
+    /// operation is pending once before it completes, like a controller that raises an
+    /// interrupt when it is done.
+    struct SingleBlockDevice {
+        memory: MemoryBlockDevice<[u8; BLOCK_SIZE * 4]>,
+        operations: usize,
+    }
+
+    impl SingleBlockDevice {
+        fn new() -> Self {
+            Self {
+                memory: MemoryBlockDevice::new([0u8; BLOCK_SIZE * 4], BLOCK_SIZE as u64).unwrap(),
+                operations: 0,
+            }
+        }
+    }
+
+    async fn yield_once() {
+        let mut pending = true;
+
+        core::future::poll_fn(|_| {
+            if core::mem::take(&mut pending) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    impl AsyncBlockDevice for SingleBlockDevice {
+        type Error = Error<core::convert::Infallible>;
+
+        fn block_size(&self) -> u64 {
+            self.memory.block_size()
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.memory.num_blocks()
+        }
+
+        async fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            yield_once().await;
+            self.operations += 1;
+            self.memory.read_block(lba, buffer)
+        }
+
+        async fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+            yield_once().await;
+            self.operations += 1;
+            self.memory.write_block(lba, buffer)
+        }
+    }
+
+    fn pattern(seed: u8) -> [u8; BLOCK_SIZE * 2] {
+        core::array::from_fn(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+    }
+
+    #[test]
+    fn async_adapter_round_trip() {
+        let mut device = AsyncAdapter::new(
+            MemoryBlockDevice::new([0u8; BLOCK_SIZE * 4], BLOCK_SIZE as u64).unwrap(),
+        );
+        let data = pattern(7);
+
+        block_on(device.write_block(3, &data[..BLOCK_SIZE])).unwrap();
+        block_on(device.write_blocks(1, 2, &data)).unwrap();
+
+        let mut buffer = [0u8; BLOCK_SIZE * 2];
+        block_on(device.read_blocks(1, 2, &mut buffer)).unwrap();
+        assert_eq!(buffer, data);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        block_on(device.read_block(3, &mut block)).unwrap();
+        assert_eq!(block, data[..BLOCK_SIZE]);
+
+        block_on(device.read_block(0, &mut block)).unwrap();
+        assert_eq!(block, [0u8; BLOCK_SIZE]);
+
+        assert!(block_on(device.read_block(4, &mut block)).is_err());
+    }
+
+    #[test]
+    fn blocking_adapter_round_trip() {
+        let memory = MemoryBlockDevice::new([0u8; BLOCK_SIZE * 4], BLOCK_SIZE as u64).unwrap();
+        let mut device = BlockingAdapter::new(AsyncAdapter::new(memory));
+        let data = pattern(42);
+
+        assert_eq!(device.block_size(), BLOCK_SIZE as u64);
+        assert_eq!(device.num_blocks(), 4);
+
+        device.write_blocks(2, 2, &data).unwrap();
+        device.write_block(0, &data[BLOCK_SIZE..]).unwrap();
+
+        let mut buffer = [0u8; BLOCK_SIZE * 2];
+        device.read_blocks(2, 2, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        device.read_block(0, &mut block).unwrap();
+        assert_eq!(block, data[BLOCK_SIZE..]);
+
+        let memory = device.into_inner().into_inner();
+        assert_eq!(memory.as_slice()[BLOCK_SIZE * 2..], data);
+    }
+
+    #[test]
+    fn default_loops_go_block_by_block() {
+        let mut device = SingleBlockDevice::new();
+        let data = pattern(3);
+
+        block_on(device.write_blocks(1, 2, &data)).unwrap();
+        assert_eq!(device.operations, 2);
+        assert_eq!(device.memory.as_slice()[BLOCK_SIZE..BLOCK_SIZE * 3], data);
+
+        let mut buffer = [0u8; BLOCK_SIZE * 2];
+        block_on(device.read_blocks(1, 2, &mut buffer)).unwrap();
+        assert_eq!(device.operations, 4);
+        assert_eq!(buffer, data);
+
+        // Through the blocking adapter, which forwards to the same loops
+        let mut device = BlockingAdapter::new(device);
+        let mut buffer = [0u8; BLOCK_SIZE * 3];
+        device.read_blocks(0, 3, &mut buffer).unwrap();
+        assert_eq!(buffer[..BLOCK_SIZE], [0u8; BLOCK_SIZE]);
+        assert_eq!(buffer[BLOCK_SIZE..], data);
+        assert_eq!(device.into_inner().operations, 7);
+    }
+
+    #[test]
+    fn default_loops_reject_short_buffers() {
+        let mut device = SingleBlockDevice::new();
+        let data = pattern(9);
+
+        let mut buffer = [0u8; BLOCK_SIZE * 2 - 1];
+        assert!(matches!(
+            block_on(device.read_blocks(0, 2, &mut buffer)),
+            Err(Error::WrongBufferSize(511))
+        ));
+        assert!(matches!(
+            block_on(device.write_blocks(0, 2, &data[..BLOCK_SIZE + 1])),
+            Err(Error::WrongBufferSize(1))
+        ));
+        // Running out of buffer entirely hands the device an empty block
+        assert!(matches!(
+            block_on(device.read_blocks(0, 3, &mut [0u8; BLOCK_SIZE * 2])),
+            Err(Error::WrongBufferSize(0))
+        ));
+    }
+}
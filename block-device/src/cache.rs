@@ -0,0 +1,412 @@
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use crate::{BlockDevice, Error, MAX_BLOCK_SIZE};
+
+/// One cached block. A cache is made up of as many of these as the caller gives it.
+pub struct CacheSlot {
+    lba: Option<u64>,
+    dirty: bool,
+    last_used: u64,
+    data: [u8; MAX_BLOCK_SIZE],
+}
+
+impl CacheSlot {
+    pub const fn empty() -> Self {
+        Self {
+            lba: None,
+            dirty: false,
+            last_used: 0,
+            data: [0u8; MAX_BLOCK_SIZE],
+        }
+    }
+}
+
+impl Default for CacheSlot {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of cached blocks that had to be dropped to make room for another
+    pub evictions: u64,
+    /// Number of dirty blocks written back to the device
+    pub write_backs: u64,
+}
+
+/// A write-back cache in front of a block device, which evicts the least recently used block
+/// when it is full
+///
+/// The slots can be a fixed-size array (see [`CachedBlockDevice::fixed`]), a borrowed slice, or
+/// with `std` a `Vec` (see [`CachedBlockDevice::with_capacity`]). Writes are only guaranteed to
+/// reach the device after [`CachedBlockDevice::flush`].
+pub struct CachedBlockDevice<D, S>
+where
+    D: BlockDevice,
+    S: AsRef<[CacheSlot]> + AsMut<[CacheSlot]>,
+{
+    device: D,
+    slots: S,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<D, S> CachedBlockDevice<D, S>
+where
+    D: BlockDevice,
+    S: AsRef<[CacheSlot]> + AsMut<[CacheSlot]>,
+{
+    pub fn new(device: D, slots: S) -> Result<Self, Error<D::Error>> {
+        let block_size = device.block_size();
+
+        if block_size as usize > MAX_BLOCK_SIZE {
+            return Err(Error::BlockSizeTooLarge(block_size));
+        }
+
+        Ok(Self {
+            device,
+            slots,
+            clock: 0,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.as_ref().len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Writes every dirty block back to the device. A block that fails to be written stays dirty,
+    /// and the others are still written before the first error is returned.
+    pub fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        let block_size = self.device.block_size() as usize;
+        let mut result = Ok(());
+
+        for slot in self.slots.as_mut() {
+            if let (Some(lba), true) = (slot.lba, slot.dirty) {
+                match self.device.write_block(lba, &slot.data[..block_size]) {
+                    Ok(()) => {
+                        slot.dirty = false;
+                        self.stats.write_backs += 1;
+                    }
+                    Err(e) => {
+                        if result.is_ok() {
+                            result = Err(Error::Device(e));
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Drops every cached block without writing anything back
+    pub fn invalidate(&mut self) {
+        for slot in self.slots.as_mut() {
+            slot.lba = None;
+            slot.dirty = false;
+        }
+    }
+
+    /// Flushes the cache and gives back the underlying device
+    pub fn into_inner(mut self) -> Result<D, Error<D::Error>> {
+        self.flush()?;
+
+        Ok(self.device)
+    }
+
+    fn find(&self, lba: u64) -> Option<usize> {
+        self.slots.as_ref().iter().position(|s| s.lba == Some(lba))
+    }
+
+    /// Picks the slot to reuse for a new block, writing back its old contents if needed
+    fn evict(&mut self) -> Result<usize, Error<D::Error>> {
+        let block_size = self.device.block_size() as usize;
+        let slots = self.slots.as_mut();
+
+        let victim = match slots.iter().position(|s| s.lba.is_none()) {
+            Some(empty) => empty,
+            None => {
+                let (victim, _) = slots
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, s)| s.last_used)
+                    .expect("Cache has no slots");
+
+                victim
+            }
+        };
+
+        let slot = &mut slots[victim];
+
+        if let Some(lba) = slot.lba {
+            if slot.dirty {
+                if let Err(e) = self.device.write_block(lba, &slot.data[..block_size]) {
+                    // Keep the block, but as the most recently used one, so that the next
+                    // eviction tries another slot instead of failing on this one again
+                    self.touch(victim);
+                    return Err(Error::Device(e));
+                }
+
+                self.stats.write_backs += 1;
+            }
+
+            self.stats.evictions += 1;
+        }
+
+        slot.lba = None;
+        slot.dirty = false;
+
+        Ok(victim)
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.clock += 1;
+        self.slots.as_mut()[index].last_used = self.clock;
+    }
+}
+
+impl<D: BlockDevice, const N: usize> CachedBlockDevice<D, [CacheSlot; N]> {
+    /// Creates a cache of `N` blocks that needs no allocation
+    pub fn fixed(device: D) -> Result<Self, Error<D::Error>> {
+        Self::new(device, [const { CacheSlot::empty() }; N])
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> CachedBlockDevice<D, Vec<CacheSlot>> {
+    /// Creates a cache of `capacity` blocks on the heap
+    pub fn with_capacity(device: D, capacity: usize) -> Result<Self, Error<D::Error>> {
+        Self::new(device, (0..capacity).map(|_| CacheSlot::empty()).collect())
+    }
+}
+
+impl<D, S> BlockDevice for CachedBlockDevice<D, S>
+where
+    D: BlockDevice,
+    S: AsRef<[CacheSlot]> + AsMut<[CacheSlot]>,
+{
+    type Error = Error<D::Error>;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let block_size = self.device.block_size() as usize;
+
+        if buffer.len() != block_size {
+            return Err(Error::WrongBufferSize(buffer.len()));
+        }
+
+        if lba >= self.device.num_blocks() {
+            return Err(Error::OutOfBounds(lba));
+        }
+
+        if self.capacity() == 0 {
+            return self.device.read_block(lba, buffer).map_err(Error::Device);
+        }
+
+        let index = match self.find(lba) {
+            Some(index) => {
+                self.stats.hits += 1;
+                index
+            }
+            None => {
+                self.stats.misses += 1;
+
+                let index = self.evict()?;
+                let slot = &mut self.slots.as_mut()[index];
+
+                self.device
+                    .read_block(lba, &mut slot.data[..block_size])
+                    .map_err(Error::Device)?;
+                slot.lba = Some(lba);
+
+                index
+            }
+        };
+
+        self.touch(index);
+        buffer.copy_from_slice(&self.slots.as_ref()[index].data[..block_size]);
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        let block_size = self.device.block_size() as usize;
+
+        if buffer.len() != block_size {
+            return Err(Error::WrongBufferSize(buffer.len()));
+        }
+
+        // Blocks past the end must not get into the cache, where they would only fail once they
+        // are written back
+        if lba >= self.device.num_blocks() {
+            return Err(Error::OutOfBounds(lba));
+        }
+
+        if self.capacity() == 0 {
+            return self.device.write_block(lba, buffer).map_err(Error::Device);
+        }
+
+        let index = match self.find(lba) {
+            Some(index) => {
+                self.stats.hits += 1;
+                index
+            }
+            None => {
+                // The whole block is overwritten, so there is no need to read it first
+                self.stats.misses += 1;
+                self.evict()?
+            }
+        };
+
+        let slot = &mut self.slots.as_mut()[index];
+
+        slot.data[..block_size].copy_from_slice(buffer);
+        slot.lba = Some(lba);
+        slot.dirty = true;
+
+        self.touch(index);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{faulty::FaultyBlockDevice, memory::MemoryBlockDevice};
+
+    const BLOCK_SIZE: usize = 512;
+
+    type Memory = MemoryBlockDevice<[u8; BLOCK_SIZE * 8]>;
+
+    fn memory() -> Memory {
+        MemoryBlockDevice::new([0u8; BLOCK_SIZE * 8], BLOCK_SIZE as u64).unwrap()
+    }
+
+    fn block_on_device(device: &Memory, lba: usize) -> &[u8] {
+        &device.as_slice()[lba * BLOCK_SIZE..(lba + 1) * BLOCK_SIZE]
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = CachedBlockDevice::<_, [CacheSlot; 2]>::fixed(memory()).unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+
+        cache.read_block(0, &mut block).unwrap();
+        cache.read_block(1, &mut block).unwrap();
+        // 0 is now more recently used than 1
+        cache.read_block(0, &mut block).unwrap();
+        cache.read_block(2, &mut block).unwrap();
+
+        assert!(cache.find(0).is_some());
+        assert!(cache.find(1).is_none());
+        assert!(cache.find(2).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.write_backs, 0);
+    }
+
+    #[test]
+    fn writes_back_dirty_blocks_on_eviction() {
+        let mut cache = CachedBlockDevice::<_, [CacheSlot; 2]>::fixed(memory()).unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+
+        cache.write_block(0, &[0xAA; BLOCK_SIZE]).unwrap();
+        cache.write_block(1, &[0xBB; BLOCK_SIZE]).unwrap();
+        cache.write_block(1, &[0xCC; BLOCK_SIZE]).unwrap();
+        assert_eq!(block_on_device(&cache.device, 0), [0u8; BLOCK_SIZE]);
+
+        cache.read_block(2, &mut block).unwrap();
+        assert_eq!(block_on_device(&cache.device, 0), [0xAA; BLOCK_SIZE]);
+        assert_eq!(block_on_device(&cache.device, 1), [0u8; BLOCK_SIZE]);
+
+        // The evicted block is read back from the device
+        cache.read_block(0, &mut block).unwrap();
+        assert_eq!(block, [0xAA; BLOCK_SIZE]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.write_backs, 2);
+
+        let memory = cache.into_inner().unwrap();
+        assert_eq!(block_on_device(&memory, 1), [0xCC; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn rejects_blocks_past_the_end() {
+        let mut cache = CachedBlockDevice::<_, [CacheSlot; 2]>::fixed(memory()).unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+
+        assert!(matches!(
+            cache.write_block(100, &block),
+            Err(Error::OutOfBounds(100))
+        ));
+        assert!(matches!(
+            cache.read_block(8, &mut block),
+            Err(Error::OutOfBounds(8))
+        ));
+
+        cache.write_block(2, &[1; BLOCK_SIZE]).unwrap();
+        cache.read_block(3, &mut block).unwrap();
+        cache.read_block(4, &mut block).unwrap();
+        cache.flush().unwrap();
+
+        assert_eq!(cache.stats().misses, 3);
+    }
+
+    #[test]
+    fn failed_write_back_does_not_block_eviction() {
+        let mut cache =
+            CachedBlockDevice::<_, [CacheSlot; 2]>::fixed(FaultyBlockDevice::new(memory()))
+                .unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+
+        cache.device.fail_write_at(0);
+        cache.write_block(0, &[0xAA; BLOCK_SIZE]).unwrap();
+        cache.write_block(1, &[0xBB; BLOCK_SIZE]).unwrap();
+
+        // Block 0 is the least recently used, and can't be written back
+        assert!(cache.read_block(2, &mut block).is_err());
+        // So block 1 goes instead
+        cache.read_block(3, &mut block).unwrap();
+
+        cache.write_block(3, &[0xDD; BLOCK_SIZE]).unwrap();
+        assert!(cache.flush().is_err());
+        // The other dirty block was still written
+        assert_eq!(
+            block_on_device(cache.device.get_mut(), 3),
+            [0xDD; BLOCK_SIZE]
+        );
+
+        cache.device.clear_faults();
+        let memory = cache.into_inner().unwrap().into_inner();
+
+        assert_eq!(block_on_device(&memory, 0), [0xAA; BLOCK_SIZE]);
+        assert_eq!(block_on_device(&memory, 1), [0xBB; BLOCK_SIZE]);
+    }
+}
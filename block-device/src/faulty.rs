@@ -0,0 +1,130 @@
+#![cfg(feature = "std")]
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    vec,
+};
+
+use crate::{BlockDevice, Error, Operation};
+
+/// Wraps a device and makes chosen operations fail, for exercising error handling in filesystem
+/// drivers
+///
+/// Read and write faults stay in place until [`FaultyBlockDevice::clear_faults`] is called. A torn
+/// write only happens once.
+pub struct FaultyBlockDevice<D: BlockDevice> {
+    device: D,
+    failing_reads: BTreeSet<u64>,
+    failing_writes: BTreeSet<u64>,
+    torn_writes: BTreeMap<u64, usize>,
+    fail_after: Option<u64>,
+    operations: u64,
+}
+
+impl<D: BlockDevice> FaultyBlockDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            failing_reads: BTreeSet::new(),
+            failing_writes: BTreeSet::new(),
+            torn_writes: BTreeMap::new(),
+            fail_after: None,
+            operations: 0,
+        }
+    }
+
+    /// Makes every read of `lba` fail
+    pub fn fail_read_at(&mut self, lba: u64) {
+        self.failing_reads.insert(lba);
+    }
+
+    /// Makes every write to `lba` fail without writing anything
+    pub fn fail_write_at(&mut self, lba: u64) {
+        self.failing_writes.insert(lba);
+    }
+
+    /// Makes the next write to `lba` only write its first `bytes` bytes, then fail
+    pub fn tear_write_at(&mut self, lba: u64, bytes: usize) {
+        self.torn_writes.insert(lba, bytes);
+    }
+
+    /// Lets `operations` more block reads or writes succeed, then fails all the rest
+    pub fn fail_after(&mut self, operations: u64) {
+        self.fail_after = Some(self.operations + operations);
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.failing_reads.clear();
+        self.failing_writes.clear();
+        self.torn_writes.clear();
+        self.fail_after = None;
+    }
+
+    /// The number of single-block operations attempted so far
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check(&mut self, operation: Operation, lba: u64) -> Result<(), Error<D::Error>> {
+        self.operations += 1;
+
+        let failing = match operation {
+            Operation::Read => &self.failing_reads,
+            Operation::Write => &self.failing_writes,
+        };
+
+        let exhausted = self.fail_after.is_some_and(|limit| self.operations > limit);
+
+        if exhausted || failing.contains(&lba) {
+            return Err(Error::InjectedFault(operation, lba));
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for FaultyBlockDevice<D> {
+    type Error = Error<D::Error>;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(Operation::Read, lba)?;
+
+        self.device.read_block(lba, buffer).map_err(Error::Device)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check(Operation::Write, lba)?;
+
+        if let Some(bytes) = self.torn_writes.remove(&lba) {
+            let mut torn = vec![0u8; buffer.len()];
+            self.device
+                .read_block(lba, &mut torn)
+                .map_err(Error::Device)?;
+
+            let bytes = bytes.min(buffer.len());
+            torn[..bytes].copy_from_slice(&buffer[..bytes]);
+
+            self.device.write_block(lba, &torn).map_err(Error::Device)?;
+
+            return Err(Error::InjectedFault(Operation::Write, lba));
+        }
+
+        self.device.write_block(lba, buffer).map_err(Error::Device)
+    }
+}
@@ -1,23 +1,59 @@
 #![cfg(feature = "std")]
 
-use std::{fs::File, io::{Read, Seek, Write}};
+use std::{
+    format,
+    fs::File,
+    io::{Read, Seek, Write},
+};
 
 use crate::BlockDevice;
 
 // Arbitrary, but reasonable
 const FILE_BLOCK_SIZE: u64 = 512;
+const MIN_FILE_BLOCK_SIZE: u64 = 512;
 
 pub struct FileBlockDevice {
     file: File,
-    buffer: [u8; FILE_BLOCK_SIZE as usize]
+    block_size: u64,
+    num_blocks: u64,
 }
 
 impl FileBlockDevice {
     pub fn new(file: File) -> Self {
-        Self {
+        Self::with_block_size(file, FILE_BLOCK_SIZE)
+            .expect("The default block size is always valid")
+    }
+
+    /// Creates a device with the given block size, which must be a power of two of at least 512
+    /// bytes
+    pub fn with_block_size(file: File, block_size: u64) -> std::io::Result<Self> {
+        if block_size < MIN_FILE_BLOCK_SIZE || !block_size.is_power_of_two() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid block size {block_size}, must be a power of two of at least {MIN_FILE_BLOCK_SIZE}"),
+            ));
+        }
+
+        let num_blocks = file.metadata()?.len() / block_size;
+
+        Ok(Self {
             file,
-            buffer: [0u8; FILE_BLOCK_SIZE as usize]
+            block_size,
+            num_blocks,
+        })
+    }
+
+    fn check_buffer_size(&self, count: u64, len: usize) -> std::io::Result<()> {
+        let expected = count * self.block_size;
+
+        if len as u64 != expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("buffer is {len} bytes, but {count} block(s) are {expected} bytes"),
+            ));
         }
+
+        Ok(())
     }
 }
 
@@ -25,36 +61,43 @@ impl BlockDevice for FileBlockDevice {
     type Error = std::io::Error;
 
     fn block_size(&self) -> u64 {
-        FILE_BLOCK_SIZE
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
     }
 
     fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
-        let block_size = self.block_size();
+        self.read_blocks(lba, 1, buffer)
+    }
 
-        let byte_offset = lba * block_size;
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.write_blocks(lba, 1, buffer)
+    }
 
-        self.file.seek(std::io::SeekFrom::Start(byte_offset))?;
-        self.file.read_exact(&mut self.buffer)?;
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_buffer_size(count, buffer.len())?;
 
-        for i in 0..buffer.len() {
-            buffer[i] = self.buffer[i];
-        }
+        let byte_offset = lba * self.block_size;
+
+        self.file.seek(std::io::SeekFrom::Start(byte_offset))?;
+        self.file.read_exact(buffer)?;
 
         Ok(())
     }
 
-    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
-        let block_size = self.block_size();
-
-        let byte_offset = lba * block_size;
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check_buffer_size(count, buffer.len())?;
 
-        for i in 0..self.buffer.len() {
-            self.buffer[i] = buffer[i];
-        }
+        let byte_offset = lba * self.block_size;
 
         self.file.seek(std::io::SeekFrom::Start(byte_offset))?;
-        self.file.write_all(&self.buffer)?;
-        
+        self.file.write_all(buffer)?;
+
+        // Writing past the end grows the file
+        self.num_blocks = self.num_blocks.max(lba + count);
+
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,113 @@
 #![no_std]
 
 use core::fmt::Debug;
+use core::fmt::Display;
 
 #[cfg(feature = "std")]
 extern crate std;
 
+pub mod async_device;
+pub mod cache;
+pub mod faulty;
 pub mod impls;
+pub mod memory;
+pub mod overlay;
+pub mod partition;
+pub mod qcow2;
+pub mod tracing;
+
+/// The largest block size that the byte-granular adapters can bounce through the stack
+pub const MAX_BLOCK_SIZE: usize = 4096;
 
 pub trait BlockDevice {
     type Error: Debug;
 
     fn block_size(&self) -> u64;
 
+    /// The total number of blocks on the device
+    fn num_blocks(&self) -> u64;
+
     fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error>;
 
     fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error>;
+
+    /// Reads `count` consecutive blocks starting at `lba` into `buffer`
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size() as usize;
+
+        for i in 0..count {
+            // A buffer that is too short hands the device a short block, which it rejects
+            let start = (i as usize * block_size).min(buffer.len());
+            let end = (start + block_size).min(buffer.len());
+            self.read_block(lba + i, &mut buffer[start..end])?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `count` consecutive blocks starting at `lba` from `buffer`
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size() as usize;
+
+        for i in 0..count {
+            // A buffer that is too short hands the device a short block, which it rejects
+            let start = (i as usize * block_size).min(buffer.len());
+            let end = (start + block_size).min(buffer.len());
+            self.write_block(lba + i, &buffer[start..end])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// The underlying device returned an error
+    Device(E),
+    /// The device's block size is larger than MAX_BLOCK_SIZE
+    BlockSizeTooLarge(u64),
+    /// The LBA lies outside of the device
+    OutOfBounds(u64),
+    /// The block size is not supported by the device
+    InvalidBlockSize(u64),
+    /// The buffer is not a whole number of blocks long
+    WrongBufferSize(usize),
+    /// A fault deliberately caused by a FaultyBlockDevice
+    InjectedFault(Operation, u64),
+}
+
+impl<E: Debug> Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => {
+                write!(f, "Block device error: {e:?}")
+            }
+            Self::BlockSizeTooLarge(size) => {
+                write!(
+                    f,
+                    "Block size too large, was {size} bytes but the maximum is {MAX_BLOCK_SIZE}."
+                )
+            }
+            Self::OutOfBounds(lba) => {
+                write!(f, "LBA {lba} is out of bounds.")
+            }
+            Self::InvalidBlockSize(size) => {
+                write!(f, "Invalid block size of {size} bytes.")
+            }
+            Self::WrongBufferSize(size) => {
+                write!(f, "Wrong buffer size, was {size} bytes.")
+            }
+            Self::InjectedFault(operation, lba) => {
+                write!(f, "Injected {operation:?} fault at LBA {lba}.")
+            }
+        }
+    }
 }
 
 pub trait OffsetRead {
@@ -25,14 +118,104 @@ pub trait OffsetRead {
     fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<(), Self::Error>;
 }
 
-// impl<T: BlockDevice> OffsetRead for T {
-//     type Error = T::Error;
-//
-//     fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
-//         todo!()
-//     }
-//
-//     fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<(), Self::Error> {
-//         todo!()
-//     }
-// }
+impl<T: BlockDevice> OffsetRead for T {
+    type Error = Error<T::Error>;
+
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size();
+
+        if block_size == 0 {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+
+        if block_size as usize > MAX_BLOCK_SIZE {
+            return Err(Error::BlockSizeTooLarge(block_size));
+        }
+
+        let mut bounce = [0u8; MAX_BLOCK_SIZE];
+        let bounce = &mut bounce[..block_size as usize];
+
+        let mut lba = offset / block_size;
+        let mut offset_in_block = (offset % block_size) as usize;
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let remaining = buffer.len() - done;
+
+            if offset_in_block == 0 && remaining >= block_size as usize {
+                // Whole, aligned blocks, so there is no need to go through the bounce buffer
+                let count = remaining as u64 / block_size;
+                let chunk = (count * block_size) as usize;
+
+                self.read_blocks(lba, count, &mut buffer[done..done + chunk])
+                    .map_err(Error::Device)?;
+
+                done += chunk;
+                lba += count;
+            } else {
+                let chunk = remaining.min(block_size as usize - offset_in_block);
+
+                self.read_block(lba, bounce).map_err(Error::Device)?;
+
+                buffer[done..done + chunk]
+                    .copy_from_slice(&bounce[offset_in_block..offset_in_block + chunk]);
+
+                done += chunk;
+                lba += 1;
+                offset_in_block = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        let block_size = self.block_size();
+
+        if block_size == 0 {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+
+        if block_size as usize > MAX_BLOCK_SIZE {
+            return Err(Error::BlockSizeTooLarge(block_size));
+        }
+
+        let mut bounce = [0u8; MAX_BLOCK_SIZE];
+        let bounce = &mut bounce[..block_size as usize];
+
+        let mut lba = offset / block_size;
+        let mut offset_in_block = (offset % block_size) as usize;
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let remaining = buffer.len() - done;
+
+            if offset_in_block == 0 && remaining >= block_size as usize {
+                let count = remaining as u64 / block_size;
+                let chunk = (count * block_size) as usize;
+
+                self.write_blocks(lba, count, &buffer[done..done + chunk])
+                    .map_err(Error::Device)?;
+
+                done += chunk;
+                lba += count;
+            } else {
+                // Partial block, so the rest of the block has to be preserved
+                let chunk = remaining.min(block_size as usize - offset_in_block);
+
+                self.read_block(lba, bounce).map_err(Error::Device)?;
+
+                bounce[offset_in_block..offset_in_block + chunk]
+                    .copy_from_slice(&buffer[done..done + chunk]);
+
+                self.write_block(lba, bounce).map_err(Error::Device)?;
+
+                done += chunk;
+                lba += 1;
+                offset_in_block = 0;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use core::convert::Infallible;
+
+#[cfg(feature = "std")]
+use std::{vec, vec::Vec};
+
+use crate::{BlockDevice, Error};
+
+/// A block device backed by memory, such as a `&mut [u8]` or, with `std`, a `Vec<u8>`
+///
+/// Any trailing bytes that do not make up a whole block are not accessible.
+pub struct MemoryBlockDevice<B> {
+    storage: B,
+    block_size: u64,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MemoryBlockDevice<B> {
+    pub fn new(storage: B, block_size: u64) -> Result<Self, Error<Infallible>> {
+        if !block_size.is_power_of_two() {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+
+        Ok(Self {
+            storage,
+            block_size,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.storage.as_ref()
+    }
+
+    pub fn into_inner(self) -> B {
+        self.storage
+    }
+
+    fn range(
+        &self,
+        lba: u64,
+        count: u64,
+        len: usize,
+    ) -> Result<core::ops::Range<usize>, Error<Infallible>> {
+        if len as u64 != count * self.block_size {
+            return Err(Error::WrongBufferSize(len));
+        }
+
+        match lba.checked_add(count) {
+            Some(end) if end <= self.num_blocks() => {
+                let start = (lba * self.block_size) as usize;
+                Ok(start..start + len)
+            }
+            _ => Err(Error::OutOfBounds(lba)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl MemoryBlockDevice<Vec<u8>> {
+    /// Creates a zero-filled device with room for `num_blocks` blocks
+    pub fn zeroed(num_blocks: u64, block_size: u64) -> Result<Self, Error<Infallible>> {
+        Self::new(vec![0u8; (num_blocks * block_size) as usize], block_size)
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> BlockDevice for MemoryBlockDevice<B> {
+    type Error = Error<Infallible>;
+
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.storage.as_ref().len() as u64 / self.block_size
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.read_blocks(lba, 1, buffer)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.write_blocks(lba, 1, buffer)
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(lba, count, buffer.len())?;
+
+        buffer.copy_from_slice(&self.storage.as_ref()[range]);
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(lba, count, buffer.len())?;
+
+        self.storage.as_mut()[range].copy_from_slice(buffer);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+#![cfg(feature = "std")]
+
+use std::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    vec,
+    vec::Vec,
+};
+
+use core::fmt::Debug;
+
+use crate::{BlockDevice, Error};
+
+/// Where an [`OverlayBlockDevice`] keeps the blocks that have been written to it
+pub trait Delta {
+    type Error: Debug;
+
+    /// Reads `lba` into `buffer` and returns true if the delta has a copy of it
+    fn read(&mut self, lba: u64, buffer: &mut [u8]) -> Result<bool, Self::Error>;
+
+    fn write(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error>;
+
+    /// Every LBA in the delta, in ascending order
+    fn lbas(&self) -> Vec<u64>;
+
+    /// The number of blocks in the delta
+    fn block_count(&self) -> usize;
+
+    fn clear(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A delta that keeps written blocks on the heap
+#[derive(Default)]
+pub struct MemoryDelta {
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl MemoryDelta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Delta for MemoryDelta {
+    type Error = core::convert::Infallible;
+
+    fn read(&mut self, lba: u64, buffer: &mut [u8]) -> Result<bool, Self::Error> {
+        match self.blocks.get(&lba) {
+            Some(block) => {
+                buffer.copy_from_slice(block);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn write(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.blocks.insert(lba, Box::from(buffer));
+        Ok(())
+    }
+
+    fn lbas(&self) -> Vec<u64> {
+        self.blocks.keys().copied().collect()
+    }
+
+    fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn clear(&mut self) -> Result<(), Self::Error> {
+        self.blocks.clear();
+        Ok(())
+    }
+}
+
+/// A delta that keeps written blocks at their own offset in a file, which stays sparse on
+/// filesystems that support it
+pub struct FileDelta {
+    file: File,
+    block_size: u64,
+    present: BTreeSet<u64>,
+}
+
+impl FileDelta {
+    /// Uses `file` as the delta, discarding anything already in it
+    pub fn new(file: File, block_size: u64) -> std::io::Result<Self> {
+        file.set_len(0)?;
+
+        Ok(Self {
+            file,
+            block_size,
+            present: BTreeSet::new(),
+        })
+    }
+}
+
+impl Delta for FileDelta {
+    type Error = std::io::Error;
+
+    fn read(&mut self, lba: u64, buffer: &mut [u8]) -> Result<bool, Self::Error> {
+        if !self.present.contains(&lba) {
+            return Ok(false);
+        }
+
+        self.file.seek(SeekFrom::Start(lba * self.block_size))?;
+        self.file.read_exact(buffer)?;
+
+        Ok(true)
+    }
+
+    fn write(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.file.seek(SeekFrom::Start(lba * self.block_size))?;
+        self.file.write_all(buffer)?;
+
+        self.present.insert(lba);
+
+        Ok(())
+    }
+
+    fn lbas(&self) -> Vec<u64> {
+        self.present.iter().copied().collect()
+    }
+
+    fn block_count(&self) -> usize {
+        self.present.len()
+    }
+
+    fn clear(&mut self) -> Result<(), Self::Error> {
+        self.file.set_len(0)?;
+        self.present.clear();
+
+        Ok(())
+    }
+}
+
+/// Whether the base device or the delta failed
+#[derive(Debug)]
+pub enum OverlayError<B, S> {
+    Base(B),
+    Delta(S),
+}
+
+impl<B, S> OverlayError<B, S> {
+    fn base(error: B) -> Error<Self> {
+        Error::Device(Self::Base(error))
+    }
+
+    fn delta(error: S) -> Error<Self> {
+        Error::Device(Self::Delta(error))
+    }
+}
+
+/// A copy-on-write view of a device. Writes go to a delta and reads are served from the delta
+/// before the base device, which is only modified by [`OverlayBlockDevice::commit`].
+pub struct OverlayBlockDevice<D: BlockDevice, S: Delta = MemoryDelta> {
+    base: D,
+    delta: S,
+}
+
+impl<D: BlockDevice> OverlayBlockDevice<D, MemoryDelta> {
+    pub fn in_memory(base: D) -> Self {
+        Self::new(base, MemoryDelta::new())
+    }
+}
+
+impl<D: BlockDevice> OverlayBlockDevice<D, FileDelta> {
+    pub fn file_backed(base: D, delta_file: File) -> std::io::Result<Self> {
+        let block_size = base.block_size();
+
+        Ok(Self::new(base, FileDelta::new(delta_file, block_size)?))
+    }
+}
+
+impl<D: BlockDevice, S: Delta> OverlayBlockDevice<D, S> {
+    pub fn new(base: D, delta: S) -> Self {
+        Self { base, delta }
+    }
+
+    /// The number of blocks that differ from the base device
+    pub fn changed_blocks(&self) -> usize {
+        self.delta.block_count()
+    }
+
+    /// Writes every changed block to the base device and empties the delta
+    pub fn commit(&mut self) -> Result<(), <Self as BlockDevice>::Error> {
+        let mut buffer = vec![0u8; self.base.block_size() as usize];
+
+        for lba in self.delta.lbas() {
+            self.delta
+                .read(lba, &mut buffer)
+                .map_err(OverlayError::delta)?;
+
+            self.base
+                .write_block(lba, &buffer)
+                .map_err(OverlayError::base)?;
+        }
+
+        self.delta.clear().map_err(OverlayError::delta)
+    }
+
+    /// Throws away every change, leaving the base device as it was
+    pub fn discard(&mut self) -> Result<(), S::Error> {
+        self.delta.clear()
+    }
+
+    pub fn base(&self) -> &D {
+        &self.base
+    }
+
+    /// Gives back the base device, without any uncommitted changes
+    pub fn into_inner(self) -> D {
+        self.base
+    }
+
+    fn check_access(
+        &self,
+        lba: u64,
+        buffer_len: usize,
+    ) -> Result<(), <Self as BlockDevice>::Error> {
+        if lba >= self.base.num_blocks() {
+            return Err(Error::OutOfBounds(lba));
+        }
+
+        if buffer_len as u64 != self.base.block_size() {
+            return Err(Error::WrongBufferSize(buffer_len));
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice, S: Delta> BlockDevice for OverlayBlockDevice<D, S> {
+    type Error = Error<OverlayError<D::Error, S::Error>>;
+
+    fn block_size(&self) -> u64 {
+        self.base.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.base.num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_access(lba, buffer.len())?;
+
+        if self.delta.read(lba, buffer).map_err(OverlayError::delta)? {
+            return Ok(());
+        }
+
+        self.base
+            .read_block(lba, buffer)
+            .map_err(OverlayError::base)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check_access(lba, buffer.len())?;
+
+        self.delta.write(lba, buffer).map_err(OverlayError::delta)
+    }
+}
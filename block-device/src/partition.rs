@@ -0,0 +1,90 @@
+use crate::{BlockDevice, Error};
+
+/// Exposes the blocks `[first_lba, last_lba]` of a parent device as a device of its own, starting
+/// at LBA 0
+pub struct PartitionBlockDevice<D: BlockDevice> {
+    device: D,
+    first_lba: u64,
+    num_blocks: u64,
+}
+
+impl<D: BlockDevice> PartitionBlockDevice<D> {
+    pub fn new(device: D, first_lba: u64, last_lba: u64) -> Result<Self, Error<D::Error>> {
+        if first_lba > last_lba {
+            return Err(Error::OutOfBounds(first_lba));
+        }
+
+        if last_lba >= device.num_blocks() {
+            return Err(Error::OutOfBounds(last_lba));
+        }
+
+        Ok(Self {
+            device,
+            first_lba,
+            num_blocks: last_lba - first_lba + 1,
+        })
+    }
+
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    pub fn last_lba(&self) -> u64 {
+        self.first_lba + self.num_blocks - 1
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check_bounds(&self, lba: u64, count: u64) -> Result<(), Error<D::Error>> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.num_blocks => Ok(()),
+            _ => Err(Error::OutOfBounds(lba)),
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionBlockDevice<D> {
+    type Error = Error<D::Error>;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_bounds(lba, 1)?;
+
+        self.device
+            .read_block(self.first_lba + lba, buffer)
+            .map_err(Error::Device)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check_bounds(lba, 1)?;
+
+        self.device
+            .write_block(self.first_lba + lba, buffer)
+            .map_err(Error::Device)
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_bounds(lba, count)?;
+
+        self.device
+            .read_blocks(self.first_lba + lba, count, buffer)
+            .map_err(Error::Device)
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check_bounds(lba, count)?;
+
+        self.device
+            .write_blocks(self.first_lba + lba, count, buffer)
+            .map_err(Error::Device)
+    }
+}
@@ -0,0 +1,753 @@
+#![cfg(feature = "std")]
+
+use core::fmt::Display;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    vec,
+    vec::Vec,
+};
+
+use crate::{BlockDevice, Error};
+
+const QCOW2_MAGIC: u32 = 0x514649FB; // "QFI\xfb"
+const QCOW2_SECTOR_SIZE: u64 = 512;
+
+const L1_OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const L2_OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xFFFF_FFFF_FFFF_FE00;
+
+/// Set in L1 and L2 entries when the cluster's refcount is exactly one, so it may be written in
+/// place
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+/// Set in version 3 L2 entries when the cluster reads as all zeros
+const ZERO_FLAG: u64 = 1 << 0;
+
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+const INCOMPATIBLE_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPATIBLE_EXTENDED_L2: u64 = 1 << 4;
+
+#[derive(Debug)]
+pub enum Qcow2Error {
+    Io(std::io::Error),
+    /// The file does not start with the qcow2 magic number, or its header is nonsense
+    InvalidHeader,
+    UnsupportedVersion(u32),
+    /// The image uses a feature this implementation does not handle
+    Unsupported(&'static str),
+}
+
+impl Display for Qcow2Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::InvalidHeader => write!(f, "Not a valid qcow2 image."),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported qcow2 version {version}.")
+            }
+            Self::Unsupported(feature) => write!(f, "Unsupported qcow2 feature: {feature}."),
+        }
+    }
+}
+
+impl std::error::Error for Qcow2Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Qcow2Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    /// offset 0x04
+    version: u32,
+    /// offset 0x14
+    cluster_bits: u32,
+    /// offset 0x18
+    size: u64,
+    /// offset 0x24
+    l1_size: u32,
+    /// offset 0x28
+    l1_table_offset: u64,
+    /// offset 0x30
+    refcount_table_offset: u64,
+    /// offset 0x38
+    refcount_table_clusters: u32,
+    /// offset 0x60, version 3 only
+    refcount_order: u32,
+}
+
+impl Header {
+    const V2_SIZE: usize = 72;
+    const V3_SIZE: usize = 104;
+
+    fn read(buffer: &[u8]) -> Result<Self, Qcow2Error> {
+        if read_u32_be(buffer, 0x00) != QCOW2_MAGIC {
+            return Err(Qcow2Error::InvalidHeader);
+        }
+
+        let version = read_u32_be(buffer, 0x04);
+
+        if version != 2 && version != 3 {
+            return Err(Qcow2Error::UnsupportedVersion(version));
+        }
+
+        if read_u64_be(buffer, 0x08) != 0 {
+            return Err(Qcow2Error::Unsupported("backing files"));
+        }
+
+        if read_u32_be(buffer, 0x20) != 0 {
+            return Err(Qcow2Error::Unsupported("encryption"));
+        }
+
+        let mut refcount_order = 4;
+
+        if version == 3 {
+            let incompatible_features = read_u64_be(buffer, 0x48);
+
+            if incompatible_features & INCOMPATIBLE_DIRTY != 0 {
+                return Err(Qcow2Error::Unsupported("dirty images with stale refcounts"));
+            }
+
+            if incompatible_features & INCOMPATIBLE_CORRUPT != 0 {
+                return Err(Qcow2Error::Unsupported("images marked as corrupt"));
+            }
+
+            if incompatible_features & INCOMPATIBLE_EXTERNAL_DATA_FILE != 0 {
+                return Err(Qcow2Error::Unsupported("external data files"));
+            }
+
+            if incompatible_features & INCOMPATIBLE_EXTENDED_L2 != 0 {
+                return Err(Qcow2Error::Unsupported("extended L2 entries"));
+            }
+
+            // The compression type only matters for compressed clusters, which are rejected
+            // when they are read
+            if incompatible_features & !INCOMPATIBLE_COMPRESSION_TYPE != 0 {
+                return Err(Qcow2Error::Unsupported("unknown incompatible features"));
+            }
+
+            refcount_order = read_u32_be(buffer, 0x60);
+        }
+
+        let header = Self {
+            version,
+            cluster_bits: read_u32_be(buffer, 0x14),
+            size: read_u64_be(buffer, 0x18),
+            l1_size: read_u32_be(buffer, 0x24),
+            l1_table_offset: read_u64_be(buffer, 0x28),
+            refcount_table_offset: read_u64_be(buffer, 0x30),
+            refcount_table_clusters: read_u32_be(buffer, 0x38),
+            refcount_order,
+        };
+
+        if !(9..=21).contains(&header.cluster_bits) || header.refcount_order > 6 {
+            return Err(Qcow2Error::InvalidHeader);
+        }
+
+        Ok(header)
+    }
+}
+
+/// A read/write view of a qcow2 (version 2 or 3) disk image, as used by QEMU
+///
+/// Backing files, compression, encryption, and snapshots that share clusters are not supported.
+/// Unallocated clusters read as zeros, and writing to one allocates a new cluster at the end of
+/// the file.
+pub struct Qcow2BlockDevice {
+    file: File,
+    header: Header,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    /// The most recently used L2 table, and its offset in the file
+    l2_cache: Option<(u64, Vec<u64>)>,
+    /// Offset in the file where the next cluster will be allocated
+    next_free_cluster: u64,
+}
+
+impl Qcow2BlockDevice {
+    pub fn new(mut file: File) -> Result<Self, Qcow2Error> {
+        let mut buffer = [0u8; Header::V3_SIZE];
+
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buffer[..Header::V2_SIZE])?;
+
+        if read_u32_be(&buffer, 0x04) == 3 {
+            file.read_exact(&mut buffer[Header::V2_SIZE..])?;
+        }
+
+        let header = Header::read(&buffer)?;
+
+        let l1_table = read_table(&mut file, header.l1_table_offset, header.l1_size as usize)?;
+
+        let refcount_table_entries =
+            ((header.refcount_table_clusters as u64) << header.cluster_bits) / 8;
+        let refcount_table = read_table(
+            &mut file,
+            header.refcount_table_offset,
+            refcount_table_entries as usize,
+        )?;
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let file_size = file.metadata()?.len();
+        let next_free_cluster = file_size.div_ceil(cluster_size) * cluster_size;
+
+        Ok(Self {
+            file,
+            header,
+            l1_table,
+            refcount_table,
+            l2_cache: None,
+            next_free_cluster,
+        })
+    }
+
+    /// The size of the virtual disk in bytes
+    pub fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.header.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Splits a guest offset into its L1 index, L2 index, and offset into the cluster
+    fn split_offset(&self, offset: u64) -> (usize, usize, u64) {
+        let cluster_bits = self.header.cluster_bits as u64;
+        let l2_bits = cluster_bits - 3;
+
+        let l1_index = (offset >> (cluster_bits + l2_bits)) as usize;
+        let l2_index = ((offset >> cluster_bits) & (self.l2_entries() - 1)) as usize;
+        let offset_in_cluster = offset & (self.cluster_size() - 1);
+
+        (l1_index, l2_index, offset_in_cluster)
+    }
+
+    fn l1_entry(&self, l1_index: usize) -> Result<u64, Qcow2Error> {
+        // A valid image always has enough L1 entries to cover its whole size
+        self.l1_table
+            .get(l1_index)
+            .copied()
+            .ok_or(Qcow2Error::InvalidHeader)
+    }
+
+    fn load_l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>, Qcow2Error> {
+        let cached = matches!(self.l2_cache, Some((offset, _)) if offset == l2_offset);
+
+        if !cached {
+            let entries = self.l2_entries() as usize;
+            let table = read_table(&mut self.file, l2_offset, entries)?;
+
+            self.l2_cache = Some((l2_offset, table));
+        }
+
+        Ok(&mut self.l2_cache.as_mut().expect("L2 table was just loaded").1)
+    }
+
+    /// Finds the host offset of a guest offset, or None if it reads as zeros
+    fn host_offset(&mut self, guest_offset: u64) -> Result<Option<u64>, Qcow2Error> {
+        let (l1_index, l2_index, offset_in_cluster) = self.split_offset(guest_offset);
+
+        let l1_entry = self.l1_entry(l1_index)?;
+        let l2_offset = l1_entry & L1_OFFSET_MASK;
+
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+
+        let l2_entry = self.load_l2_table(l2_offset)?[l2_index];
+
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Qcow2Error::Unsupported("compressed clusters"));
+        }
+
+        let cluster_offset = l2_entry & L2_OFFSET_MASK;
+
+        if l2_entry & ZERO_FLAG != 0 || cluster_offset == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(cluster_offset + offset_in_cluster))
+    }
+
+    /// Finds the host offset of a guest offset, allocating an L2 table and data cluster if needed
+    fn host_offset_for_write(&mut self, guest_offset: u64) -> Result<u64, Qcow2Error> {
+        let (l1_index, l2_index, offset_in_cluster) = self.split_offset(guest_offset);
+
+        let l1_entry = self.l1_entry(l1_index)?;
+        let mut l2_offset = l1_entry & L1_OFFSET_MASK;
+
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+
+            self.l1_table[l1_index] = l2_offset | COPIED_FLAG;
+            self.write_table_entry(
+                self.header.l1_table_offset,
+                l1_index,
+                self.l1_table[l1_index],
+            )?;
+        } else if l1_entry & COPIED_FLAG == 0 {
+            return Err(Qcow2Error::Unsupported("L2 tables shared with snapshots"));
+        }
+
+        let l2_entry = self.load_l2_table(l2_offset)?[l2_index];
+
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Qcow2Error::Unsupported("compressed clusters"));
+        }
+
+        let mut cluster_offset = l2_entry & L2_OFFSET_MASK;
+
+        if cluster_offset != 0 && l2_entry & COPIED_FLAG == 0 {
+            return Err(Qcow2Error::Unsupported("clusters shared with snapshots"));
+        }
+
+        let needs_update = if cluster_offset == 0 {
+            // New clusters are past the old end of the file, so they are already zeroed
+            cluster_offset = self.allocate_cluster()?;
+            true
+        } else if l2_entry & ZERO_FLAG != 0 {
+            // A preallocated cluster that has to read as zeros, except for what is written now
+            let zeros = vec![0u8; self.cluster_size() as usize];
+            self.file.seek(SeekFrom::Start(cluster_offset))?;
+            self.file.write_all(&zeros)?;
+            true
+        } else {
+            false
+        };
+
+        if needs_update {
+            let new_entry = cluster_offset | COPIED_FLAG;
+
+            self.load_l2_table(l2_offset)?[l2_index] = new_entry;
+            self.write_table_entry(l2_offset, l2_index, new_entry)?;
+        }
+
+        Ok(cluster_offset + offset_in_cluster)
+    }
+
+    /// Adds a zeroed cluster to the end of the file and gives it a refcount of one
+    fn allocate_cluster(&mut self) -> Result<u64, Qcow2Error> {
+        let offset = self.append_cluster()?;
+
+        self.set_refcount(offset, 1)?;
+
+        Ok(offset)
+    }
+
+    fn append_cluster(&mut self) -> Result<u64, Qcow2Error> {
+        let offset = self.next_free_cluster;
+        self.next_free_cluster += self.cluster_size();
+
+        if self.file.metadata()?.len() < self.next_free_cluster {
+            self.file.set_len(self.next_free_cluster)?;
+        }
+
+        Ok(offset)
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, value: u64) -> Result<(), Qcow2Error> {
+        let refcount_bits = 1u64 << self.header.refcount_order;
+        let entries_per_block = (self.cluster_size() * 8) / refcount_bits;
+
+        let cluster_index = cluster_offset >> self.header.cluster_bits;
+        let table_index = (cluster_index / entries_per_block) as usize;
+        let block_index = cluster_index % entries_per_block;
+
+        if table_index >= self.refcount_table.len() {
+            return Err(Qcow2Error::Unsupported("growing the refcount table"));
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+
+        if block_offset == 0 {
+            block_offset = self.append_cluster()?;
+
+            self.refcount_table[table_index] = block_offset;
+            self.write_table_entry(self.header.refcount_table_offset, table_index, block_offset)?;
+
+            // The new refcount block has to be counted as well, possibly by itself
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let entry_bit = block_index * refcount_bits;
+        let byte_offset = block_offset + entry_bit / 8;
+
+        if refcount_bits >= 8 {
+            let bytes = (refcount_bits / 8) as usize;
+            let encoded = value.to_be_bytes();
+
+            self.file.seek(SeekFrom::Start(byte_offset))?;
+            self.file.write_all(&encoded[8 - bytes..])?;
+        } else {
+            // Several refcounts share a byte, starting from the least significant bit
+            let shift = entry_bit % 8;
+            let mask = ((1u8 << refcount_bits) - 1) << shift;
+
+            let mut byte = [0u8; 1];
+            self.file.seek(SeekFrom::Start(byte_offset))?;
+            self.file.read_exact(&mut byte)?;
+
+            byte[0] = (byte[0] & !mask) | (((value as u8) << shift) & mask);
+
+            self.file.seek(SeekFrom::Start(byte_offset))?;
+            self.file.write_all(&byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_table_entry(
+        &mut self,
+        table_offset: u64,
+        index: usize,
+        value: u64,
+    ) -> Result<(), Qcow2Error> {
+        self.file
+            .seek(SeekFrom::Start(table_offset + index as u64 * 8))?;
+        self.file.write_all(&value.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn check_access(&self, lba: u64, buffer_len: usize) -> Result<(), Error<Qcow2Error>> {
+        if lba >= self.num_blocks() {
+            return Err(Error::OutOfBounds(lba));
+        }
+
+        if buffer_len as u64 != QCOW2_SECTOR_SIZE {
+            return Err(Error::WrongBufferSize(buffer_len));
+        }
+
+        Ok(())
+    }
+
+    fn read_sector(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Qcow2Error> {
+        match self.host_offset(lba * QCOW2_SECTOR_SIZE)? {
+            Some(host_offset) => {
+                self.file.seek(SeekFrom::Start(host_offset))?;
+                self.file.read_exact(buffer)?;
+            }
+            None => buffer.fill(0),
+        }
+
+        Ok(())
+    }
+
+    fn write_sector(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Qcow2Error> {
+        let host_offset = self.host_offset_for_write(lba * QCOW2_SECTOR_SIZE)?;
+
+        self.file.seek(SeekFrom::Start(host_offset))?;
+        self.file.write_all(buffer)?;
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for Qcow2BlockDevice {
+    type Error = Error<Qcow2Error>;
+
+    fn block_size(&self) -> u64 {
+        QCOW2_SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.header.size / QCOW2_SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_access(lba, buffer.len())?;
+        self.read_sector(lba, buffer).map_err(Error::Device)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.check_access(lba, buffer.len())?;
+        self.write_sector(lba, buffer).map_err(Error::Device)
+    }
+}
+
+fn read_table(file: &mut File, offset: u64, entries: usize) -> Result<Vec<u64>, Qcow2Error> {
+    let mut bytes = vec![0u8; entries * 8];
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|entry| read_u64_be(entry, 0))
+        .collect())
+}
+
+fn read_u32_be(input: &[u8], offset: usize) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(&input[offset..offset + 4]);
+    u32::from_be_bytes(buffer)
+}
+
+fn read_u64_be(input: &[u8], offset: usize) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&input[offset..offset + 8]);
+    u64::from_be_bytes(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{format, fs, fs::OpenOptions, path::PathBuf};
+
+    use super::*;
+
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    /// Each L2 table of 64 entries covers this many bytes
+    const L2_COVERAGE: u64 = CLUSTER_SIZE * CLUSTER_SIZE / 8;
+    const IMAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+    const L1_TABLE: u64 = CLUSTER_SIZE;
+    const REFCOUNT_TABLE: u64 = 2 * CLUSTER_SIZE;
+    const REFCOUNT_BLOCK: u64 = 3 * CLUSTER_SIZE;
+    const FIRST_FREE: u64 = 4 * CLUSTER_SIZE;
+
+    /// A qcow2 image in the temporary directory, which is removed when dropped
+    struct Image(PathBuf);
+
+    impl Image {
+        /// Makes an empty image with 512-byte clusters: the header, the L1 table, a refcount
+        /// table and one refcount block, which counts those four clusters
+        fn new(name: &str, version: u32, refcount_order: u32) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("block-device-qcow2-{}-{name}", std::process::id()));
+
+            let mut bytes = vec![0u8; FIRST_FREE as usize];
+
+            bytes[0x00..0x04].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+            bytes[0x04..0x08].copy_from_slice(&version.to_be_bytes());
+            bytes[0x14..0x18].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+            bytes[0x18..0x20].copy_from_slice(&IMAGE_SIZE.to_be_bytes());
+            let l1_size = IMAGE_SIZE.div_ceil(L2_COVERAGE) as u32;
+            bytes[0x24..0x28].copy_from_slice(&l1_size.to_be_bytes());
+            bytes[0x28..0x30].copy_from_slice(&L1_TABLE.to_be_bytes());
+            bytes[0x30..0x38].copy_from_slice(&REFCOUNT_TABLE.to_be_bytes());
+            bytes[0x38..0x3C].copy_from_slice(&1u32.to_be_bytes());
+
+            if version == 3 {
+                bytes[0x60..0x64].copy_from_slice(&refcount_order.to_be_bytes());
+                bytes[0x64..0x68].copy_from_slice(&(Header::V3_SIZE as u32).to_be_bytes());
+            }
+
+            let table = REFCOUNT_TABLE as usize;
+            bytes[table..table + 8].copy_from_slice(&REFCOUNT_BLOCK.to_be_bytes());
+
+            fs::write(&path, &bytes).unwrap();
+
+            let image = Self(path);
+            let mut device = image.open();
+
+            for cluster in 0..4 {
+                device.set_refcount(cluster * CLUSTER_SIZE, 1).unwrap();
+            }
+
+            image
+        }
+
+        fn open(&self) -> Qcow2BlockDevice {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.0)
+                .unwrap();
+
+            Qcow2BlockDevice::new(file).unwrap()
+        }
+
+        fn write_u64(&self, offset: u64, value: u64) {
+            let mut file = OpenOptions::new().write(true).open(&self.0).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&value.to_be_bytes()).unwrap();
+        }
+
+        /// Decodes a cluster's refcount straight from the file
+        fn refcount(&self, refcount_order: u32, cluster_offset: u64) -> u64 {
+            let bytes = fs::read(&self.0).unwrap();
+            let refcount_bits = 1u64 << refcount_order;
+            let entries_per_block = CLUSTER_SIZE * 8 / refcount_bits;
+
+            let cluster_index = cluster_offset / CLUSTER_SIZE;
+            let table_entry = REFCOUNT_TABLE + cluster_index / entries_per_block * 8;
+            let block = read_u64_be(&bytes, table_entry as usize);
+
+            if block == 0 {
+                return 0;
+            }
+
+            let bit = (block * 8 + (cluster_index % entries_per_block) * refcount_bits) as usize;
+
+            (0..refcount_bits as usize)
+                .map(|i| {
+                    // Big-endian bytes, with the bits of each byte from the least significant
+                    let bit = if refcount_bits >= 8 {
+                        bit + refcount_bits as usize - 8 - i / 8 * 8 + i % 8
+                    } else {
+                        bit + i
+                    };
+                    (((bytes[bit / 8] >> (bit % 8)) & 1) as u64) << i
+                })
+                .sum()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn sector(seed: u64) -> [u8; QCOW2_SECTOR_SIZE as usize] {
+        core::array::from_fn(|i| (i as u64 ^ seed.wrapping_mul(0x9E37)) as u8)
+    }
+
+    #[test]
+    fn round_trip() {
+        for (version, refcount_order) in [(2, 4), (3, 0), (3, 4), (3, 6)] {
+            let image = Image::new(
+                &format!("round-trip-{refcount_order}"),
+                version,
+                refcount_order,
+            );
+            let lbas = [0, 1, 63, 64, 4000, IMAGE_SIZE / QCOW2_SECTOR_SIZE - 1];
+
+            let mut device = image.open();
+            for lba in lbas {
+                device.write_block(lba, &sector(lba)).unwrap();
+            }
+            drop(device);
+
+            let mut device = image.open();
+            let mut buffer = [0u8; QCOW2_SECTOR_SIZE as usize];
+
+            for lba in lbas {
+                device.read_block(lba, &mut buffer).unwrap();
+                assert_eq!(
+                    buffer,
+                    sector(lba),
+                    "LBA {lba}, refcount order {refcount_order}"
+                );
+            }
+
+            // Every cluster in the file is in use by exactly one thing
+            let file_size = fs::metadata(&image.0).unwrap().len();
+            for cluster in (0..file_size).step_by(CLUSTER_SIZE as usize) {
+                assert_eq!(image.refcount(refcount_order, cluster), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn unallocated_and_zero_clusters_read_as_zeros() {
+        let image = Image::new("zeros", 3, 4);
+        let mut device = image.open();
+
+        let mut buffer = [0xFFu8; QCOW2_SECTOR_SIZE as usize];
+        device.read_block(10, &mut buffer).unwrap();
+        assert_eq!(buffer, [0u8; QCOW2_SECTOR_SIZE as usize]);
+
+        // Allocate the L2 table for the start of the disk, and a cluster for LBA 0
+        device.write_block(0, &sector(1)).unwrap();
+        drop(device);
+
+        let l2_table = FIRST_FREE;
+        let data_cluster = FIRST_FREE + CLUSTER_SIZE;
+
+        // LBA 0 keeps its cluster but has to read as zeros, LBA 1 has no cluster at all
+        image.write_u64(l2_table, data_cluster | COPIED_FLAG | ZERO_FLAG);
+        image.write_u64(l2_table + 8, ZERO_FLAG);
+
+        let mut device = image.open();
+
+        for lba in [0, 1] {
+            buffer.fill(0xFF);
+            device.read_block(lba, &mut buffer).unwrap();
+            assert_eq!(buffer, [0u8; QCOW2_SECTOR_SIZE as usize], "LBA {lba}");
+        }
+
+        // Writing to the zero cluster reuses it
+        device.write_block(0, &sector(2)).unwrap();
+        device.read_block(0, &mut buffer).unwrap();
+        assert_eq!(buffer, sector(2));
+
+        let mut entry = [0u8; 8];
+        device.file.seek(SeekFrom::Start(l2_table)).unwrap();
+        device.file.read_exact(&mut entry).unwrap();
+        assert_eq!(u64::from_be_bytes(entry), data_cluster | COPIED_FLAG);
+    }
+
+    #[test]
+    fn allocates_l2_tables_and_refcount_blocks() {
+        // 64-bit refcounts, so one refcount block only covers 64 clusters
+        let image = Image::new("grow", 3, 6);
+        let mut device = image.open();
+
+        // Every write needs a new L2 table as well as a data cluster
+        let lbas: Vec<u64> = (0..40)
+            .map(|i| i * L2_COVERAGE / QCOW2_SECTOR_SIZE + i % 3)
+            .collect();
+
+        for &lba in &lbas {
+            device.write_block(lba, &sector(lba)).unwrap();
+        }
+
+        assert!(device.refcount_table[1] != 0);
+        assert_eq!(device.refcount_table[2], 0);
+        drop(device);
+
+        let mut device = image.open();
+        let mut buffer = [0u8; QCOW2_SECTOR_SIZE as usize];
+
+        for &lba in &lbas {
+            device.read_block(lba, &mut buffer).unwrap();
+            assert_eq!(buffer, sector(lba), "LBA {lba}");
+        }
+
+        let file_size = fs::metadata(&image.0).unwrap().len();
+        assert_eq!(file_size, FIRST_FREE + (2 * 40 + 1) * CLUSTER_SIZE);
+
+        for cluster in (0..file_size).step_by(CLUSTER_SIZE as usize) {
+            assert_eq!(image.refcount(6, cluster), 1, "cluster at {cluster:#x}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_access() {
+        let image = Image::new("bounds", 3, 4);
+        let mut device = image.open();
+        let mut buffer = [0u8; QCOW2_SECTOR_SIZE as usize];
+        let num_blocks = device.num_blocks();
+
+        assert!(matches!(
+            device.read_block(num_blocks, &mut buffer),
+            Err(Error::OutOfBounds(lba)) if lba == num_blocks
+        ));
+        assert!(matches!(
+            device.write_block(0, &buffer[..100]),
+            Err(Error::WrongBufferSize(100))
+        ));
+    }
+}
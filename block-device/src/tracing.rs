@@ -0,0 +1,84 @@
+#![cfg(feature = "std")]
+
+use std::vec::Vec;
+
+use crate::{BlockDevice, Operation};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub operation: Operation,
+    pub lba: u64,
+    /// Number of blocks
+    pub len: u64,
+}
+
+/// Wraps a device and records every access made to it
+pub struct TracingBlockDevice<D: BlockDevice> {
+    device: D,
+    accesses: Vec<Access>,
+}
+
+impl<D: BlockDevice> TracingBlockDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+    }
+
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn record(&mut self, operation: Operation, lba: u64, len: u64) {
+        self.accesses.push(Access {
+            operation,
+            lba,
+            len,
+        });
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for TracingBlockDevice<D> {
+    type Error = D::Error;
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_block(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.record(Operation::Read, lba, 1);
+        self.device.read_block(lba, buffer)
+    }
+
+    fn write_block(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.record(Operation::Write, lba, 1);
+        self.device.write_block(lba, buffer)
+    }
+
+    fn read_blocks(&mut self, lba: u64, count: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.record(Operation::Read, lba, count);
+        self.device.read_blocks(lba, count, buffer)
+    }
+
+    fn write_blocks(&mut self, lba: u64, count: u64, buffer: &[u8]) -> Result<(), Self::Error> {
+        self.record(Operation::Write, lba, count);
+        self.device.write_blocks(lba, count, buffer)
+    }
+}
@@ -1,11 +1,11 @@
-use clap::Parser;
-use std::{
-    fs::File,
-    io::{self, Read, Seek, SeekFrom},
-    path::PathBuf,
+use block_device::{
+    impls::FileBlockDevice, partition::PartitionBlockDevice, BlockDevice, OffsetRead,
 };
+use clap::Parser;
+use gpt_reader::gpt::{entry::PartitionEntry, header::PartitionTableHeader};
+use std::{fs::File, path::PathBuf};
 
-use ext4_core::{groups::GroupDescriptor, inode::Inode, superblock::SuperBlock};
+use ext4_driver::{DriverError, Ext4Driver};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -14,6 +14,9 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Args {
     #[arg(value_name = "FILE")]
     file: PathBuf,
+    /// Read the filesystem from this GPT partition of FILE, counting from 1
+    #[arg(short, long, value_name = "N")]
+    partition: Option<usize>,
 }
 
 fn main() {
@@ -21,107 +24,144 @@ fn main() {
 
     println!("dumpe4fs {VERSION}");
 
-    match File::open(&args.file) {
-        Ok(mut opened) => {
-            let mut block = [0u8; 1024];
-
-            if let Err(e) = read_block(&mut opened, 1, 1024, &mut block) {
-                eprintln!("Error reading file: {e}");
-                return;
-            }
+    let disk = match File::open(&args.file) {
+        Ok(opened) => FileBlockDevice::new(opened),
+        Err(e) => {
+            eprintln!("Error opening file: {e}");
+            return;
+        }
+    };
+
+    match args.partition {
+        Some(n) => match open_partition(disk, n) {
+            Ok(partition) => dump(partition, &args),
+            Err(e) => eprintln!("Error opening partition {n}: {e}"),
+        },
+        None => dump(disk, &args),
+    }
+}
 
-            match SuperBlock::read(&block) {
-                Ok(superblock) => {
-                    if superblock.magic() != ext4_core::EXT4_MAGIC {
-                        eprintln!(
-                            "Bad magic number in superblock while trying to open {}",
-                            &args.file.display()
-                        );
-                        eprintln!("Couldn't find valid filesystem superblock.");
-                        return;
-                    }
-
-                    println!("Filesystem volume name:   {}", superblock.volume_label());
-                    println!(
-                        "Last mounted on:          {}",
-                        superblock.last_mounted().unwrap_or("<not available>")
-                    );
-                    println!("Filesystem UUID:          {}", superblock.filesystem_uuid());
-                    println!("Filesystem magic number:  0x{:04X}", superblock.magic());
-                    println!(
-                        "Filesystem revision #:    {}",
-                        superblock.filesystem_revision()
-                    );
-                    println!("Filesystem features:      {}", "");
-                    println!("Filesystem flags:         {}", "");
-                    println!("Default mount options:    {}", "");
-                    println!(
-                        "Filesystem state:         {:016b}",
-                        superblock.filesystem_state().raw_value()
-                    );
-                    println!(
-                        "Filesystem clean:         {}",
-                        superblock.filesystem_state().cleanly_unmounted()
-                    );
-                    println!(
-                        "Filesystem errors:        {}",
-                        superblock.filesystem_state().errors_detected()
-                    );
-                    println!(
-                        "Filesystem orphans:       {}",
-                        superblock.filesystem_state().orphans_being_recovered()
-                    );
-                    println!("Errors behavior:          {}", "");
-                    println!("Filesystem OS type:       {}", superblock.creator_os());
-                    println!(
-                        "Group descriptor size:    {}",
-                        superblock.group_descriptor_size()
-                    );
-
-                    let mut gdt_block = [0u8; 1024];
-
-                    if let Err(e) = read_block(&mut opened, 1, 4096, &mut gdt_block) {
-                        eprintln!("Error reading file: {e}");
-                        return;
-                    }
-
-                    let first_gd = GroupDescriptor::read(&gdt_block[0..64]);
-
-                    println!("{:#?}", first_gd);
-
-                    let mut inode_table_block = [0u8; 1024];
-
-                    if let Err(e) = read_block(
-                        &mut opened,
-                        u32::from(first_gd.inode_table_block()) as u64,
-                        4096,
-                        &mut inode_table_block,
-                    ) {
-                        eprintln!("Error reading file: {e}");
-                        return;
-                    }
-
-                    for i in 0..3 {
-                        let start = (i * superblock.inode_size()) as usize;
-                        let end = start + (superblock.inode_size() as usize);
-                        let inode = Inode::read(&inode_table_block[start..end]);
-
-                        println!("{:#?}", inode);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error reading filesystem superblock: {e:?}");
-                }
-            }
+fn dump<D: BlockDevice>(device: D, args: &Args) {
+    let mut driver = match Ext4Driver::new(device) {
+        Ok(driver) => driver,
+        Err(DriverError::BadMagic) => {
+            eprintln!(
+                "Bad magic number in superblock while trying to open {}",
+                &args.file.display()
+            );
+            eprintln!("Couldn't find valid filesystem superblock.");
+            return;
         }
         Err(e) => {
-            eprintln!("Error opening file: {e}");
+            eprintln!("Error reading filesystem: {e}");
+            return;
+        }
+    };
+
+    let superblock = driver.superblock();
+
+    println!("Filesystem volume name:   {}", superblock.volume_label());
+    println!(
+        "Last mounted on:          {}",
+        superblock.last_mounted().unwrap_or("<not available>")
+    );
+    println!("Filesystem UUID:          {}", superblock.filesystem_uuid());
+    println!("Filesystem magic number:  0x{:04X}", superblock.magic());
+    println!(
+        "Filesystem revision #:    {}",
+        superblock.filesystem_revision()
+    );
+    println!("Filesystem features:      {}", "");
+    println!("Filesystem flags:         {}", "");
+    println!("Default mount options:    {}", "");
+    println!(
+        "Filesystem state:         {:016b}",
+        superblock.filesystem_state().raw_value()
+    );
+    println!(
+        "Filesystem clean:         {}",
+        superblock.filesystem_state().cleanly_unmounted()
+    );
+    println!(
+        "Filesystem errors:        {}",
+        superblock.filesystem_state().errors_detected()
+    );
+    println!(
+        "Filesystem orphans:       {}",
+        superblock.filesystem_state().orphans_being_recovered()
+    );
+    println!("Errors behavior:          {}", "");
+    println!("Filesystem OS type:       {}", superblock.creator_os());
+    println!("Inode count:              {}", superblock.inodes_count());
+    println!("Block count:              {}", superblock.blocks_count());
+    println!(
+        "First block:              {}",
+        superblock.first_data_block()
+    );
+    println!(
+        "Block size:               {}",
+        superblock.block_size().unwrap_or(0)
+    );
+    println!(
+        "Group descriptor size:    {}",
+        superblock.group_descriptor_size()
+    );
+    println!(
+        "Blocks per group:         {}",
+        superblock.blocks_per_group()
+    );
+    println!(
+        "Inodes per group:         {}",
+        superblock.inodes_per_group()
+    );
+    println!("Inode size:               {}", superblock.inode_size());
+
+    println!("{:#?}", driver.group_descriptors()[0]);
+
+    for inode_number in 1..=3 {
+        match driver.read_inode(inode_number) {
+            Ok(inode) => println!("{:#?}", inode),
+            Err(e) => {
+                eprintln!("Error reading inode {inode_number}: {e}");
+                return;
+            }
         }
     }
 }
 
-fn read_block(file: &mut File, lba: u64, block_size: u64, buffer: &mut [u8]) -> io::Result<()> {
-    file.seek(SeekFrom::Start((lba * block_size) + 316669952))?;
-    file.read(buffer)?;
-    Ok(())
+fn open_partition(
+    mut disk: FileBlockDevice,
+    n: usize,
+) -> Result<PartitionBlockDevice<FileBlockDevice>, String> {
+    let sector_size = disk.block_size();
+
+    let mut header_sector = vec![0u8; sector_size as usize];
+    disk.read(sector_size, &mut header_sector)
+        .map_err(|e| e.to_string())?;
+
+    let table = PartitionTableHeader::read(&header_sector);
+
+    if !table.is_signature_valid() {
+        return Err(String::from("not a GPT partitioned disk image"));
+    }
+
+    if n == 0 || n > table.num_partition_table_entries() as usize {
+        return Err(String::from("no such partition"));
+    }
+
+    let entry_size = table.partition_table_entry_size() as u64;
+    let entry_offset =
+        table.partition_table_entries_start_lba() * sector_size + (n as u64 - 1) * entry_size;
+
+    let mut entry_buffer = vec![0u8; entry_size as usize];
+    disk.read(entry_offset, &mut entry_buffer)
+        .map_err(|e| e.to_string())?;
+
+    let entry = PartitionEntry::read(&entry_buffer);
+
+    if entry.partition_type_guid().is_zero() {
+        return Err(String::from("no such partition"));
+    }
+
+    entry.block_device(disk).map_err(|e| e.to_string())
 }
@@ -0,0 +1,475 @@
+use bin_tools::{read_u16_le, read_u32_le, read_u64_le};
+
+use crate::time::Timestamp;
+
+pub const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+/// An entry type of 0 marks the end of the directory, nothing after it is in use
+pub const END_OF_DIRECTORY: u8 = 0x00;
+
+/// Set in the entry type of entries that are in use, it is cleared when an entry is deleted
+pub const ENTRY_IN_USE: u8 = 0x80;
+/// Set in the entry type of secondary entries, which belong to the primary entry before them
+pub const SECONDARY_ENTRY: u8 = 0x40;
+
+pub const ALLOCATION_BITMAP_ENTRY: u8 = 0x81;
+pub const UPCASE_TABLE_ENTRY: u8 = 0x82;
+pub const VOLUME_LABEL_ENTRY: u8 = 0x83;
+pub const FILE_ENTRY: u8 = 0x85;
+pub const STREAM_EXTENSION_ENTRY: u8 = 0xC0;
+pub const FILE_NAME_ENTRY: u8 = 0xC1;
+
+/// How many UTF-16 code units of the name each File Name entry holds
+pub const NAME_CHARS_PER_ENTRY: usize = 15;
+
+/// How many UTF-16 code units a file name can have
+pub const MAX_NAME_LEN: usize = 255;
+
+/// How many UTF-16 code units a volume label can have
+pub const MAX_LABEL_LEN: usize = 11;
+
+/// Set in the general secondary flags when the entry has clusters allocated to it
+const ALLOCATION_POSSIBLE: u8 = 0x01;
+/// Set in the general secondary flags when the clusters are contiguous and the FAT entries for
+/// them are not used
+const NO_FAT_CHAIN: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u16)]
+pub enum Attributes {
+    ReadOnly = 0x01,
+    Hidden = 0x02,
+    System = 0x04,
+    Directory = 0x10,
+    Archive = 0x20,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DirectoryEntry {
+    EndOfDirectory,
+    /// A deleted entry of any type, which can be reused
+    Unused(u8),
+    AllocationBitmap(AllocationBitmapEntry),
+    UpcaseTable(UpcaseTableEntry),
+    VolumeLabel(VolumeLabelEntry),
+    File(FileEntry),
+    StreamExtension(StreamExtensionEntry),
+    FileName(FileNameEntry),
+    /// An entry in use that this crate doesn't know, like the volume GUID or vendor extensions
+    Other(u8),
+}
+
+impl DirectoryEntry {
+    pub fn read(input: &[u8]) -> Self {
+        match input[0] {
+            END_OF_DIRECTORY => Self::EndOfDirectory,
+            entry_type if entry_type & ENTRY_IN_USE == 0 => Self::Unused(entry_type),
+            ALLOCATION_BITMAP_ENTRY => Self::AllocationBitmap(AllocationBitmapEntry::read(input)),
+            UPCASE_TABLE_ENTRY => Self::UpcaseTable(UpcaseTableEntry::read(input)),
+            VOLUME_LABEL_ENTRY => Self::VolumeLabel(VolumeLabelEntry::read(input)),
+            FILE_ENTRY => Self::File(FileEntry::read(input)),
+            STREAM_EXTENSION_ENTRY => Self::StreamExtension(StreamExtensionEntry::read(input)),
+            FILE_NAME_ENTRY => Self::FileName(FileNameEntry::read(input)),
+            entry_type => Self::Other(entry_type),
+        }
+    }
+
+    pub fn entry_type(input: &[u8]) -> u8 {
+        input[0]
+    }
+
+    pub fn is_secondary(input: &[u8]) -> bool {
+        input[0] & SECONDARY_ENTRY != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationBitmapEntry {
+    /// offset 0x01, bit 0 is set for the second bitmap
+    bitmap_flags: u8,
+    /// offset 0x14
+    first_cluster: u32,
+    /// offset 0x18
+    data_length: u64,
+}
+
+impl AllocationBitmapEntry {
+    pub fn read(input: &[u8]) -> Self {
+        Self {
+            bitmap_flags: input[0x01],
+            first_cluster: read_u32_le(input, 0x14),
+            data_length: read_u64_le(input, 0x18),
+        }
+    }
+
+    /// 0 for the first bitmap and 1 for the second, which only volumes with two FATs have
+    pub fn bitmap_index(&self) -> u8 {
+        self.bitmap_flags & 1
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    pub fn data_length(&self) -> u64 {
+        self.data_length
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UpcaseTableEntry {
+    /// offset 0x04
+    table_checksum: u32,
+    /// offset 0x14
+    first_cluster: u32,
+    /// offset 0x18
+    data_length: u64,
+}
+
+impl UpcaseTableEntry {
+    pub fn read(input: &[u8]) -> Self {
+        Self {
+            table_checksum: read_u32_le(input, 0x04),
+            first_cluster: read_u32_le(input, 0x14),
+            data_length: read_u64_le(input, 0x18),
+        }
+    }
+
+    pub fn table_checksum(&self) -> u32 {
+        self.table_checksum
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    pub fn data_length(&self) -> u64 {
+        self.data_length
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeLabelEntry {
+    /// offset 0x01
+    character_count: u8,
+    /// offset 0x02
+    volume_label: [u16; MAX_LABEL_LEN],
+}
+
+impl VolumeLabelEntry {
+    pub fn read(input: &[u8]) -> Self {
+        let mut volume_label = [0; MAX_LABEL_LEN];
+
+        for (i, c) in volume_label.iter_mut().enumerate() {
+            *c = read_u16_le(input, 0x02 + i * 2);
+        }
+
+        Self {
+            character_count: input[0x01],
+            volume_label,
+        }
+    }
+
+    /// The UTF-16 code units of the label
+    pub fn label(&self) -> &[u16] {
+        &self.volume_label[..(self.character_count as usize).min(MAX_LABEL_LEN)]
+    }
+}
+
+/// The primary entry of the entry set of a file or directory, which is followed by a Stream
+/// Extension entry and the File Name entries
+#[derive(Debug, Clone, Copy)]
+pub struct FileEntry {
+    /// offset 0x01
+    secondary_count: u8,
+    /// offset 0x02
+    set_checksum: u16,
+    /// offset 0x04
+    file_attributes: u16,
+    /// offset 0x08
+    create_timestamp: u32,
+    /// offset 0x0C
+    last_modified_timestamp: u32,
+    /// offset 0x10
+    last_accessed_timestamp: u32,
+    /// offset 0x14
+    create_10ms_increment: u8,
+    /// offset 0x15
+    last_modified_10ms_increment: u8,
+    /// offset 0x16
+    create_utc_offset: u8,
+    /// offset 0x17
+    last_modified_utc_offset: u8,
+    /// offset 0x18
+    last_accessed_utc_offset: u8,
+}
+
+impl FileEntry {
+    pub fn read(input: &[u8]) -> Self {
+        Self {
+            secondary_count: input[0x01],
+            set_checksum: read_u16_le(input, 0x02),
+            file_attributes: read_u16_le(input, 0x04),
+            create_timestamp: read_u32_le(input, 0x08),
+            last_modified_timestamp: read_u32_le(input, 0x0C),
+            last_accessed_timestamp: read_u32_le(input, 0x10),
+            create_10ms_increment: input[0x14],
+            last_modified_10ms_increment: input[0x15],
+            create_utc_offset: input[0x16],
+            last_modified_utc_offset: input[0x17],
+            last_accessed_utc_offset: input[0x18],
+        }
+    }
+
+    /// How many entries follow this one in the entry set
+    pub fn secondary_count(&self) -> u8 {
+        self.secondary_count
+    }
+
+    pub fn set_checksum(&self) -> u16 {
+        self.set_checksum
+    }
+
+    pub fn attributes(&self) -> u16 {
+        self.file_attributes
+    }
+
+    pub fn has_attribute(&self, attribute: Attributes) -> bool {
+        self.file_attributes & attribute as u16 != 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.has_attribute(Attributes::Directory)
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    pub fn created(&self) -> Option<Timestamp> {
+        Timestamp::from_raw(
+            self.create_timestamp,
+            self.create_10ms_increment,
+            self.create_utc_offset,
+        )
+    }
+
+    pub fn modified(&self) -> Option<Timestamp> {
+        Timestamp::from_raw(
+            self.last_modified_timestamp,
+            self.last_modified_10ms_increment,
+            self.last_modified_utc_offset,
+        )
+    }
+
+    /// Access times only have two second resolution
+    pub fn accessed(&self) -> Option<Timestamp> {
+        Timestamp::from_raw(
+            self.last_accessed_timestamp,
+            0,
+            self.last_accessed_utc_offset,
+        )
+    }
+}
+
+/// Where the data of a file or directory is, and how long it is
+#[derive(Debug, Clone, Copy)]
+pub struct StreamExtensionEntry {
+    /// offset 0x01
+    general_secondary_flags: u8,
+    /// offset 0x03
+    name_length: u8,
+    /// offset 0x04
+    name_hash: u16,
+    /// offset 0x08
+    valid_data_length: u64,
+    /// offset 0x14
+    first_cluster: u32,
+    /// offset 0x18
+    data_length: u64,
+}
+
+impl StreamExtensionEntry {
+    pub fn read(input: &[u8]) -> Self {
+        Self {
+            general_secondary_flags: input[0x01],
+            name_length: input[0x03],
+            name_hash: read_u16_le(input, 0x04),
+            valid_data_length: read_u64_le(input, 0x08),
+            first_cluster: read_u32_le(input, 0x14),
+            data_length: read_u64_le(input, 0x18),
+        }
+    }
+
+    /// Whether any clusters are allocated, empty files have none
+    pub fn is_allocation_possible(&self) -> bool {
+        self.general_secondary_flags & ALLOCATION_POSSIBLE != 0
+    }
+
+    /// Whether the clusters are contiguous, in which case the FAT doesn't describe them
+    pub fn is_no_fat_chain(&self) -> bool {
+        self.general_secondary_flags & NO_FAT_CHAIN != 0
+    }
+
+    /// The length of the name in UTF-16 code units
+    pub fn name_length(&self) -> u8 {
+        self.name_length
+    }
+
+    pub fn name_hash(&self) -> u16 {
+        self.name_hash
+    }
+
+    /// How much of the data has been written, anything after it reads as zeros
+    pub fn valid_data_length(&self) -> u64 {
+        self.valid_data_length
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /// The allocated length of the data
+    pub fn data_length(&self) -> u64 {
+        self.data_length
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileNameEntry {
+    /// offset 0x02
+    file_name: [u16; NAME_CHARS_PER_ENTRY],
+}
+
+impl FileNameEntry {
+    pub fn read(input: &[u8]) -> Self {
+        let mut file_name = [0; NAME_CHARS_PER_ENTRY];
+
+        for (i, c) in file_name.iter_mut().enumerate() {
+            *c = read_u16_le(input, 0x02 + i * 2);
+        }
+
+        Self { file_name }
+    }
+
+    /// This entry's part of the name as UTF-16 code units, the last entry of a name is padded
+    /// with zeros
+    pub fn name(&self) -> &[u16; NAME_CHARS_PER_ENTRY] {
+        &self.file_name
+    }
+}
+
+/// Adds one entry of an entry set to the set checksum, which starts out as 0. The entries have
+/// to be added in order, starting with the primary entry, whose own checksum field is skipped.
+pub fn set_checksum(mut checksum: u16, entry: &[u8], is_primary: bool) -> u16 {
+    for (i, &b) in entry[..DIRECTORY_ENTRY_SIZE].iter().enumerate() {
+        if is_primary && (i == 2 || i == 3) {
+            continue;
+        }
+
+        checksum = checksum.rotate_right(1).wrapping_add(b as u16);
+    }
+
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The algorithm from the specification, over a whole entry set
+    fn reference_checksum(entries: &[[u8; DIRECTORY_ENTRY_SIZE]]) -> u16 {
+        let mut checksum: u16 = 0;
+
+        for (i, &b) in entries.iter().flatten().enumerate() {
+            if i == 2 || i == 3 {
+                continue;
+            }
+
+            checksum = (if checksum & 1 != 0 { 0x8000u16 } else { 0 })
+                .wrapping_add(checksum >> 1)
+                .wrapping_add(b as u16);
+        }
+
+        checksum
+    }
+
+    fn entry_set() -> [[u8; DIRECTORY_ENTRY_SIZE]; 3] {
+        let mut file = [0u8; DIRECTORY_ENTRY_SIZE];
+        file[0] = FILE_ENTRY;
+        file[1] = 2;
+        file[4] = Attributes::Archive as u8;
+
+        let stream: [u8; DIRECTORY_ENTRY_SIZE] = core::array::from_fn(|i| {
+            if i == 0 {
+                STREAM_EXTENSION_ENTRY
+            } else {
+                i as u8 * 7
+            }
+        });
+
+        let mut name = [0u8; DIRECTORY_ENTRY_SIZE];
+        name[0] = FILE_NAME_ENTRY;
+        name[2..6].copy_from_slice(b"a\0b\0");
+
+        [file, stream, name]
+    }
+
+    fn checksum_of(entries: &[[u8; DIRECTORY_ENTRY_SIZE]]) -> u16 {
+        entries.iter().enumerate().fold(0, |checksum, (i, entry)| {
+            set_checksum(checksum, entry, i == 0)
+        })
+    }
+
+    #[test]
+    fn set_checksum_matches_the_specification() {
+        let entries = entry_set();
+
+        assert_eq!(checksum_of(&entries), reference_checksum(&entries));
+    }
+
+    #[test]
+    fn set_checksum_skips_only_the_primary_checksum_field() {
+        let entries = entry_set();
+        let checksum = checksum_of(&entries);
+
+        let mut with_checksum = entries;
+        with_checksum[0][2..4].copy_from_slice(&0xBEEFu16.to_le_bytes());
+        assert_eq!(checksum_of(&with_checksum), checksum);
+
+        let mut changed = entries;
+        changed[1][2] ^= 1;
+        assert_ne!(checksum_of(&changed), checksum);
+    }
+
+    #[test]
+    fn reads_stream_extension() {
+        let mut input = [0u8; DIRECTORY_ENTRY_SIZE];
+        input[0] = STREAM_EXTENSION_ENTRY;
+        input[0x01] = ALLOCATION_POSSIBLE | NO_FAT_CHAIN;
+        input[0x03] = 7;
+        input[0x04..0x06].copy_from_slice(&0x1234u16.to_le_bytes());
+        input[0x08..0x10].copy_from_slice(&1000u64.to_le_bytes());
+        input[0x14..0x18].copy_from_slice(&5u32.to_le_bytes());
+        input[0x18..0x20].copy_from_slice(&4096u64.to_le_bytes());
+
+        let DirectoryEntry::StreamExtension(stream) = DirectoryEntry::read(&input) else {
+            panic!("Not read as a Stream Extension entry");
+        };
+
+        assert!(stream.is_allocation_possible());
+        assert!(stream.is_no_fat_chain());
+        assert_eq!(stream.name_length(), 7);
+        assert_eq!(stream.name_hash(), 0x1234);
+        assert_eq!(stream.valid_data_length(), 1000);
+        assert_eq!(stream.first_cluster(), 5);
+        assert_eq!(stream.data_length(), 4096);
+
+        // Deleted entries of any type are unused
+        input[0] &= !ENTRY_IN_USE;
+        assert!(matches!(
+            DirectoryEntry::read(&input),
+            DirectoryEntry::Unused(0x40)
+        ));
+    }
+}
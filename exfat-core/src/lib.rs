@@ -0,0 +1,41 @@
+#![no_std]
+
+pub mod entry;
+pub mod record;
+pub mod time;
+pub mod upcase;
+
+use core::fmt::Debug;
+use core::fmt::Display;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    BufferSizeTooSmall(u32),
+    /// The compressed up-case table ends in the middle of an identity run, or expands to more
+    /// than 65536 entries
+    InvalidUpcaseTable,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BufferSizeTooSmall(size) => {
+                write!(f, "Buffer size too small, was only {size} bytes.")
+            }
+            Self::InvalidUpcaseTable => {
+                write!(f, "Invalid up-case table.")
+            }
+        }
+    }
+}
+
+/// Bytes that are kept as-is, like reserved areas and boot code, which only show their length
+/// when debug printed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RawBytes<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Debug for RawBytes<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[{N} bytes]")
+    }
+}
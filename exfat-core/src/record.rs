@@ -0,0 +1,316 @@
+use bin_tools::{read_into_array, read_u16_le, read_u32_le, read_u64_le};
+
+use crate::RawBytes;
+
+/// The last two bytes of the boot sector
+pub const BOOT_SIGNATURE: u16 = 0xAA55;
+
+/// The file system name, which tells exFAT apart from the FAT file systems
+pub const FILE_SYSTEM_NAME: [u8; 8] = *b"EXFAT   ";
+
+/// The boot sector, the extended boot sectors, the OEM parameters and the reserved sector, which
+/// the checksum sector that follows them covers
+pub const BOOT_CHECKSUM_SECTORS: u64 = 11;
+
+/// Where the backup of the boot region starts
+pub const BACKUP_BOOT_SECTOR: u64 = 12;
+
+/// The FAT entry of the first cluster of the cluster heap
+pub const FIRST_CLUSTER: u32 = 2;
+
+/// Bytes of the boot sector that change while the volume is in use, so they aren't part of
+/// the boot region checksum
+const VOLUME_FLAGS_OFFSET: usize = 0x6A;
+const PERCENT_IN_USE_OFFSET: usize = 0x70;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u16)]
+pub enum VolumeFlags {
+    /// Which FAT and allocation bitmap are in use, if there are two of each
+    ActiveFat = 0x01,
+    /// The volume might be inconsistent because it wasn't unmounted cleanly
+    VolumeDirty = 0x02,
+    MediaFailure = 0x04,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootRecord {
+    /// offset 0x00
+    jump_boot: [u8; 3],
+    /// offset 0x03
+    file_system_name: [u8; 8],
+    /// offset 0x0B, all 0 so FAT drivers don't mistake the volume for one of theirs
+    must_be_zero: RawBytes<53>,
+    /// offset 0x40, in sectors
+    partition_offset: u64,
+    /// offset 0x48, in sectors
+    volume_length: u64,
+    /// offset 0x50, in sectors
+    fat_offset: u32,
+    /// offset 0x54, in sectors
+    fat_length: u32,
+    /// offset 0x58, in sectors
+    cluster_heap_offset: u32,
+    /// offset 0x5C
+    cluster_count: u32,
+    /// offset 0x60
+    first_cluster_of_root_directory: u32,
+    /// offset 0x64
+    volume_serial_number: u32,
+    /// offset 0x68, major version in the high byte
+    file_system_revision: u16,
+    /// offset 0x6A
+    volume_flags: u16,
+    /// offset 0x6C, log2 of the bytes per sector
+    bytes_per_sector_shift: u8,
+    /// offset 0x6D, log2 of the sectors per cluster
+    sectors_per_cluster_shift: u8,
+    /// offset 0x6E
+    number_of_fats: u8,
+    /// offset 0x6F
+    drive_select: u8,
+    /// offset 0x70, 0xFF if not known
+    percent_in_use: u8,
+    /// offset 0x78
+    boot_code: RawBytes<390>,
+    /// offset 0x1FE
+    boot_signature: u16,
+}
+
+impl BootRecord {
+    pub fn read(buffer: &[u8]) -> Self {
+        Self {
+            jump_boot: read_into_array(buffer, 0x00),
+            file_system_name: read_into_array(buffer, 0x03),
+            must_be_zero: RawBytes(read_into_array(buffer, 0x0B)),
+            partition_offset: read_u64_le(buffer, 0x40),
+            volume_length: read_u64_le(buffer, 0x48),
+            fat_offset: read_u32_le(buffer, 0x50),
+            fat_length: read_u32_le(buffer, 0x54),
+            cluster_heap_offset: read_u32_le(buffer, 0x58),
+            cluster_count: read_u32_le(buffer, 0x5C),
+            first_cluster_of_root_directory: read_u32_le(buffer, 0x60),
+            volume_serial_number: read_u32_le(buffer, 0x64),
+            file_system_revision: read_u16_le(buffer, 0x68),
+            volume_flags: read_u16_le(buffer, VOLUME_FLAGS_OFFSET),
+            bytes_per_sector_shift: buffer[0x6C],
+            sectors_per_cluster_shift: buffer[0x6D],
+            number_of_fats: buffer[0x6E],
+            drive_select: buffer[0x6F],
+            percent_in_use: buffer[PERCENT_IN_USE_OFFSET],
+            boot_code: RawBytes(read_into_array(buffer, 0x78)),
+            boot_signature: read_u16_le(buffer, 0x1FE),
+        }
+    }
+
+    /// Checks the signatures and that the geometry is in the ranges the specification allows
+    pub fn is_valid(&self) -> bool {
+        self.file_system_name == FILE_SYSTEM_NAME
+            && self.boot_signature == BOOT_SIGNATURE
+            && self.must_be_zero.0.iter().all(|&b| b == 0)
+            && (9..=12).contains(&self.bytes_per_sector_shift)
+            // Clusters can be at most 32 MiB
+            && self.sectors_per_cluster_shift <= 25 - self.bytes_per_sector_shift
+            && (1..=2).contains(&self.number_of_fats)
+            && self.fat_offset > 0
+            && self.cluster_heap_offset as u64
+                >= self.fat_offset as u64 + self.fat_length as u64 * self.number_of_fats as u64
+            && (self.cluster_count as u64 + FIRST_CLUSTER as u64) * 4
+                <= self.fat_length as u64 * self.bytes_per_sector()
+            && self.cluster_heap_offset as u64
+                + ((self.cluster_count as u64) << self.sectors_per_cluster_shift)
+                <= self.volume_length
+            && self.is_cluster_valid(self.first_cluster_of_root_directory)
+    }
+
+    /// Whether the cluster is in the cluster heap
+    pub fn is_cluster_valid(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    pub fn jump_boot(&self) -> &[u8; 3] {
+        &self.jump_boot
+    }
+
+    pub fn file_system_name(&self) -> &[u8; 8] {
+        &self.file_system_name
+    }
+
+    pub fn partition_offset(&self) -> u64 {
+        self.partition_offset
+    }
+
+    /// The size of the volume in sectors
+    pub fn volume_length(&self) -> u64 {
+        self.volume_length
+    }
+
+    pub fn fat_offset(&self) -> u64 {
+        self.fat_offset as u64
+    }
+
+    pub fn fat_length(&self) -> u64 {
+        self.fat_length as u64
+    }
+
+    /// The first sector of the FAT that is in use
+    pub fn active_fat_sector(&self) -> u64 {
+        if self.has_volume_flag(VolumeFlags::ActiveFat) && self.number_of_fats == 2 {
+            self.fat_offset() + self.fat_length()
+        } else {
+            self.fat_offset()
+        }
+    }
+
+    pub fn cluster_heap_offset(&self) -> u64 {
+        self.cluster_heap_offset as u64
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn root_directory_cluster(&self) -> u32 {
+        self.first_cluster_of_root_directory
+    }
+
+    pub fn volume_serial_number(&self) -> u32 {
+        self.volume_serial_number
+    }
+
+    /// The major and minor version
+    pub fn file_system_revision(&self) -> (u8, u8) {
+        (
+            (self.file_system_revision >> 8) as u8,
+            self.file_system_revision as u8,
+        )
+    }
+
+    pub fn volume_flags(&self) -> u16 {
+        self.volume_flags
+    }
+
+    pub fn has_volume_flag(&self, flag: VolumeFlags) -> bool {
+        self.volume_flags & flag as u16 != 0
+    }
+
+    pub fn bytes_per_sector(&self) -> u64 {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub fn sectors_per_cluster(&self) -> u64 {
+        1 << self.sectors_per_cluster_shift
+    }
+
+    pub fn bytes_per_cluster(&self) -> u64 {
+        self.bytes_per_sector() << self.sectors_per_cluster_shift
+    }
+
+    pub fn number_of_fats(&self) -> u8 {
+        self.number_of_fats
+    }
+
+    pub fn drive_select(&self) -> u8 {
+        self.drive_select
+    }
+
+    /// None if the percentage isn't known
+    pub fn percent_in_use(&self) -> Option<u8> {
+        if self.percent_in_use <= 100 {
+            Some(self.percent_in_use)
+        } else {
+            None
+        }
+    }
+
+    pub fn boot_code(&self) -> &[u8; 390] {
+        &self.boot_code.0
+    }
+
+    pub fn boot_signature(&self) -> u16 {
+        self.boot_signature
+    }
+
+    /// The first sector of a cluster in the cluster heap
+    pub fn cluster_to_sector(&self, cluster: u32) -> u64 {
+        self.cluster_heap_offset() + ((cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster())
+    }
+}
+
+/// Adds one sector of the boot region to the checksum, which starts out as 0. The sectors have
+/// to be added in order, starting with the boot sector at index 0.
+pub fn boot_checksum(mut checksum: u32, sector: &[u8], sector_index: u64) -> u32 {
+    for (i, &b) in sector.iter().enumerate() {
+        if sector_index == 0
+            && (i == VOLUME_FLAGS_OFFSET
+                || i == VOLUME_FLAGS_OFFSET + 1
+                || i == PERCENT_IN_USE_OFFSET)
+        {
+            continue;
+        }
+
+        checksum = checksum.rotate_right(1).wrapping_add(b as u32);
+    }
+
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The algorithm from the specification, over the whole boot region
+    fn reference_checksum(sectors: &[[u8; 512]]) -> u32 {
+        let mut checksum: u32 = 0;
+
+        for (i, &b) in sectors.iter().flatten().enumerate() {
+            if i == VOLUME_FLAGS_OFFSET
+                || i == VOLUME_FLAGS_OFFSET + 1
+                || i == PERCENT_IN_USE_OFFSET
+            {
+                continue;
+            }
+
+            checksum = (if checksum & 1 != 0 { 0x80000000u32 } else { 0 })
+                .wrapping_add(checksum >> 1)
+                .wrapping_add(b as u32);
+        }
+
+        checksum
+    }
+
+    fn boot_region() -> [[u8; 512]; BOOT_CHECKSUM_SECTORS as usize] {
+        core::array::from_fn(|sector| {
+            core::array::from_fn(|i| (i as u8).wrapping_mul(13) ^ sector as u8)
+        })
+    }
+
+    fn checksum_of(sectors: &[[u8; 512]]) -> u32 {
+        sectors.iter().enumerate().fold(0, |checksum, (i, sector)| {
+            boot_checksum(checksum, sector, i as u64)
+        })
+    }
+
+    #[test]
+    fn boot_checksum_matches_the_specification() {
+        let sectors = boot_region();
+
+        assert_eq!(checksum_of(&sectors), reference_checksum(&sectors));
+    }
+
+    #[test]
+    fn boot_checksum_skips_fields_that_change_in_use() {
+        let sectors = boot_region();
+        let checksum = checksum_of(&sectors);
+
+        let mut in_use = sectors;
+        in_use[0][VOLUME_FLAGS_OFFSET] ^= 0x02;
+        in_use[0][PERCENT_IN_USE_OFFSET] = 42;
+        assert_eq!(checksum_of(&in_use), checksum);
+
+        // Only in the boot sector itself
+        let mut changed = sectors;
+        changed[1][VOLUME_FLAGS_OFFSET] ^= 0x02;
+        assert_ne!(checksum_of(&changed), checksum);
+    }
+}
@@ -0,0 +1,118 @@
+use core::fmt::Display;
+
+/// The year a timestamp with a year field of 0 is in
+pub const EXFAT_EPOCH_YEAR: u16 = 1980;
+
+/// Set in the UTC offset field when the offset is valid
+const UTC_OFFSET_VALID: u8 = 0x80;
+
+/// A timestamp as stored in a File directory entry: bits 31-25 are the years since 1980, 24-21
+/// the month, 20-16 the day, 15-11 the hours, 10-5 the minutes and 4-0 the seconds divided by
+/// two. Some timestamps have an extra byte of 10 ms units, 0 to 199, and all of them have a byte
+/// with the offset from UTC in 15 minute steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    millisecond: u16,
+    /// In 15 minute steps
+    utc_offset: Option<i8>,
+}
+
+impl Timestamp {
+    /// Returns None if any part is out of range, which includes a timestamp of 0, the value of
+    /// timestamps that were never set
+    pub fn from_raw(raw: u32, centiseconds: u8, utc_offset: u8) -> Option<Self> {
+        let year = EXFAT_EPOCH_YEAR + (raw >> 25) as u16;
+        let month = ((raw >> 21) & 0x0F) as u8;
+        let day = ((raw >> 16) & 0x1F) as u8;
+        let hour = ((raw >> 11) & 0x1F) as u8;
+        let minute = ((raw >> 5) & 0x3F) as u8;
+        let second = (raw & 0x1F) as u8 * 2 + centiseconds / 100;
+
+        if !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 59
+            || centiseconds > 199
+        {
+            return None;
+        }
+
+        let utc_offset = if utc_offset & UTC_OFFSET_VALID != 0 {
+            // Sign extend the 7 bit field
+            Some(((utc_offset << 1) as i8) >> 1)
+        } else {
+            None
+        };
+
+        Some(Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            millisecond: (centiseconds % 100) as u16 * 10,
+            utc_offset,
+        })
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// 1 to 12
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// 1 to 31
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    pub fn millisecond(&self) -> u16 {
+        self.millisecond
+    }
+
+    /// The offset from UTC in minutes, if it was recorded
+    pub fn utc_offset_minutes(&self) -> Option<i16> {
+        self.utc_offset.map(|offset| offset as i16 * 15)
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )?;
+
+        if let Some(minutes) = self.utc_offset_minutes() {
+            let sign = if minutes < 0 { '-' } else { '+' };
+            let minutes = minutes.unsigned_abs();
+            write!(f, " {sign}{:02}:{:02}", minutes / 60, minutes % 60)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use bin_tools::read_u16_le;
+
+use crate::Error;
+
+/// An expanded up-case table has an entry for every UTF-16 code unit
+pub const UPCASE_TABLE_LEN: usize = 0x10000;
+
+/// In a compressed up-case table, this is followed by the length of a run of code units that
+/// map to themselves
+const IDENTITY_RUN: u16 = 0xFFFF;
+
+/// Adds bytes of the up-case table, as stored on disk, to its checksum, which starts out as 0
+pub fn table_checksum(mut checksum: u32, bytes: &[u8]) -> u32 {
+    for &b in bytes {
+        checksum = checksum.rotate_right(1).wrapping_add(b as u32);
+    }
+
+    checksum
+}
+
+/// Expands the up-case table as stored on disk, which is either a plain array of code units or
+/// compressed with identity runs, into `table`. Code units after the end of the stored table map
+/// to themselves.
+pub fn expand_table(stored: &[u8], table: &mut [u16]) -> Result<(), Error> {
+    if table.len() < UPCASE_TABLE_LEN {
+        return Err(Error::BufferSizeTooSmall(table.len() as u32));
+    }
+
+    for (i, c) in table.iter_mut().enumerate() {
+        *c = i as u16;
+    }
+
+    let units = stored.len() / 2;
+    let mut next = 0;
+    let mut i = 0;
+
+    while i < units {
+        let unit = read_u16_le(stored, i * 2);
+        i += 1;
+
+        // An uncompressed table ends with the mapping of 0xFFFF, which is a plain entry
+        if unit == IDENTITY_RUN && i < units {
+            next += read_u16_le(stored, i * 2) as usize;
+            i += 1;
+        } else {
+            if next >= UPCASE_TABLE_LEN {
+                return Err(Error::InvalidUpcaseTable);
+            }
+
+            table[next] = unit;
+            next += 1;
+        }
+
+        if next > UPCASE_TABLE_LEN {
+            return Err(Error::InvalidUpcaseTable);
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks a code unit up in an expanded table
+pub fn up_case(table: &[u16], c: u16) -> u16 {
+    table.get(c as usize).copied().unwrap_or(c)
+}
+
+/// The hash stored in the Stream Extension entry, over the up-cased name
+pub fn name_hash(table: &[u16], name: &[u16]) -> u16 {
+    let mut hash: u16 = 0;
+
+    for &c in name {
+        for b in up_case(table, c).to_le_bytes() {
+            hash = hash.rotate_right(1).wrapping_add(b as u16);
+        }
+    }
+
+    hash
+}
+
+/// Compares two names the way exFAT does, by comparing their up-cased code units
+pub fn names_equal(table: &[u16], a: &[u16], b: &[u16]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(&a, &b)| up_case(table, a) == up_case(table, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(units: &[u16]) -> [u8; 256] {
+        let mut bytes = [0u8; 256];
+
+        for (i, unit) in units.iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    fn ascii_upper(c: u16) -> u16 {
+        if (b'a' as u16..=b'z' as u16).contains(&c) {
+            c - 0x20
+        } else {
+            c
+        }
+    }
+
+    #[test]
+    fn expands_uncompressed_table() {
+        let units: [u16; 128] = core::array::from_fn(|c| ascii_upper(c as u16));
+        let bytes = stored(&units);
+        let mut table = [0u16; UPCASE_TABLE_LEN];
+
+        expand_table(&bytes, &mut table).unwrap();
+
+        for c in 0..UPCASE_TABLE_LEN {
+            assert_eq!(table[c], ascii_upper(c as u16), "{c:#x}");
+        }
+    }
+
+    #[test]
+    fn expands_identity_runs() {
+        let mut units = [0u16; 2 + 26 + 2];
+        units[0] = IDENTITY_RUN;
+        units[1] = b'a' as u16;
+        for (i, c) in (b'A'..=b'Z').enumerate() {
+            units[2 + i] = c as u16;
+        }
+        // A run that reaches the end of the table
+        units[28] = IDENTITY_RUN;
+        units[29] = (UPCASE_TABLE_LEN - (b'z' as usize + 1)) as u16;
+
+        let bytes = stored(&units);
+        let mut table = [0u16; UPCASE_TABLE_LEN];
+
+        expand_table(&bytes[..units.len() * 2], &mut table).unwrap();
+
+        for c in 0..UPCASE_TABLE_LEN {
+            assert_eq!(table[c], ascii_upper(c as u16), "{c:#x}");
+        }
+    }
+
+    #[test]
+    fn rejects_tables_that_are_too_long() {
+        let mut table = [0u16; UPCASE_TABLE_LEN];
+
+        // A run past the end of the table
+        let bytes = stored(&[IDENTITY_RUN, 0xFFFF, IDENTITY_RUN, 2]);
+        assert!(matches!(
+            expand_table(&bytes[..8], &mut table),
+            Err(Error::InvalidUpcaseTable)
+        ));
+
+        // A mapping after a run that filled the table
+        let bytes = stored(&[IDENTITY_RUN, 0xFFFF, 0, 0]);
+        assert!(matches!(
+            expand_table(&bytes[..8], &mut table),
+            Err(Error::InvalidUpcaseTable)
+        ));
+
+        assert!(matches!(
+            expand_table(&bytes, &mut table[..100]),
+            Err(Error::BufferSizeTooSmall(100))
+        ));
+    }
+
+    #[test]
+    fn hashes_names_ignoring_case() {
+        let units: [u16; 128] = core::array::from_fn(|c| ascii_upper(c as u16));
+        let mut table = [0u16; UPCASE_TABLE_LEN];
+        expand_table(&stored(&units), &mut table).unwrap();
+
+        let lower: [u16; 7] = core::array::from_fn(|i| b"foo.txt"[i] as u16);
+        let upper: [u16; 7] = core::array::from_fn(|i| b"FOO.TXT"[i] as u16);
+
+        // The algorithm from the specification, over the up-cased name as little-endian bytes
+        let mut expected: u16 = 0;
+        for &c in &upper {
+            for b in c.to_le_bytes() {
+                expected = (if expected & 1 != 0 { 0x8000u16 } else { 0 })
+                    .wrapping_add(expected >> 1)
+                    .wrapping_add(b as u16);
+            }
+        }
+
+        assert_eq!(name_hash(&table, &lower), expected);
+        assert_eq!(name_hash(&table, &upper), expected);
+        assert_ne!(name_hash(&table, &lower[..6]), expected);
+        assert_eq!(name_hash(&table, &[]), 0);
+
+        assert!(names_equal(&table, &lower, &upper));
+        assert!(!names_equal(&table, &lower, &upper[..6]));
+    }
+}
@@ -0,0 +1,1391 @@
+use core::{
+    fmt::{Debug, Display},
+    str,
+};
+
+use block_device::{BlockDevice, OffsetRead, MAX_BLOCK_SIZE};
+use exfat_core::{
+    entry::{
+        set_checksum, Attributes, DirectoryEntry, FileEntry, StreamExtensionEntry,
+        DIRECTORY_ENTRY_SIZE, ENTRY_IN_USE, MAX_NAME_LEN, NAME_CHARS_PER_ENTRY, SECONDARY_ENTRY,
+    },
+    record::{boot_checksum, BootRecord, BOOT_CHECKSUM_SECTORS},
+    time::Timestamp,
+    upcase::{expand_table, name_hash, names_equal, table_checksum, UPCASE_TABLE_LEN},
+};
+
+/// Stored in the FAT for the last cluster of a chain
+const END_OF_CHAIN: u32 = 0xFFFFFFFF;
+
+/// Directories can be at most 256 MiB long, which also bounds the root directory, whose length
+/// is only known from its cluster chain
+const MAX_DIRECTORY_SIZE: u64 = 256 * 1024 * 1024;
+
+/// An up-case table that maps every code unit takes 128 KiB, and compression only makes it
+/// shorter
+const MAX_UPCASE_TABLE_SIZE: u64 = UPCASE_TABLE_LEN as u64 * 2;
+
+/// Each UTF-16 code unit of a name takes at most 3 bytes in UTF-8
+const MAX_NAME_BYTES: usize = MAX_NAME_LEN * 3;
+
+pub type DriverResult<T, E> = Result<T, DriverError<E>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum DriverError<E> {
+    /// The block device returned an error
+    Device(block_device::Error<E>),
+    FileSystemInvalid,
+    PathNotFound,
+    IsADirectory,
+    IsNotADirectory,
+}
+
+impl<E> From<exfat_core::Error> for DriverError<E> {
+    fn from(error: exfat_core::Error) -> Self {
+        match error {
+            exfat_core::Error::BufferSizeTooSmall(_) | exfat_core::Error::InvalidUpcaseTable => {
+                Self::FileSystemInvalid
+            }
+        }
+    }
+}
+
+impl<E: Debug> Display for DriverError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => write!(f, "{e}"),
+            Self::FileSystemInvalid => write!(f, "The file system is invalid."),
+            Self::PathNotFound => write!(f, "No such file or directory."),
+            Self::IsADirectory => write!(f, "Is a directory."),
+            Self::IsNotADirectory => write!(f, "Not a directory."),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for DriverError<E> {}
+
+/// A name as both the UTF-16 code units stored on disk, which lookups compare, and UTF-8
+struct EntryName {
+    units: [u16; MAX_NAME_LEN],
+    units_len: u8,
+    inner: [u8; MAX_NAME_BYTES],
+    len: usize,
+}
+
+impl EntryName {
+    fn new(units: [u16; MAX_NAME_LEN], units_len: u8) -> Self {
+        let mut inner = [0u8; MAX_NAME_BYTES];
+        let mut len = 0;
+
+        // Unpaired surrogates can't be represented in UTF-8
+        for c in char::decode_utf16(units[..units_len as usize].iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            len += c.encode_utf8(&mut inner[len..]).len();
+        }
+
+        Self {
+            units,
+            units_len,
+            inner,
+            len,
+        }
+    }
+
+    fn str(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.inner[0..self.len]) }
+    }
+
+    fn units(&self) -> &[u16] {
+        &self.units[..self.units_len as usize]
+    }
+}
+
+/// Where the data of a file or directory is and how long it is
+#[derive(Debug, Clone, Copy)]
+struct Stream {
+    first_cluster: u32,
+    /// The clusters are contiguous and the FAT doesn't describe them
+    no_fat_chain: bool,
+    data_length: u64,
+    /// Anything after this reads as zeros
+    valid_data_length: u64,
+}
+
+impl Stream {
+    fn new(entry: &StreamExtensionEntry) -> Self {
+        Self {
+            first_cluster: entry.first_cluster(),
+            no_fat_chain: entry.is_no_fat_chain(),
+            data_length: entry.data_length(),
+            valid_data_length: entry.valid_data_length().min(entry.data_length()),
+        }
+    }
+
+    /// A stream that goes on until the end of its cluster chain, which is how the root directory
+    /// and the system files in it are found
+    fn chain(first_cluster: u32, data_length: u64) -> Self {
+        Self {
+            first_cluster,
+            no_fat_chain: false,
+            data_length,
+            valid_data_length: data_length,
+        }
+    }
+}
+
+/// The File, Stream Extension and File Name entries of a file or directory
+struct EntrySet {
+    name: EntryName,
+    file: FileEntry,
+    stream: StreamExtensionEntry,
+}
+
+struct DataBuffer {
+    inner: [u8; MAX_BLOCK_SIZE],
+    location: Option<u64>,
+}
+
+impl DataBuffer {
+    fn empty() -> Self {
+        Self {
+            inner: [0u8; MAX_BLOCK_SIZE],
+            location: None,
+        }
+    }
+}
+
+pub struct ExFatDirectoryEntry {
+    entry_set: EntrySet,
+}
+
+impl ExFatDirectoryEntry {
+    pub fn name(&self) -> &str {
+        self.entry_set.name.str()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.entry_set.file.is_file()
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.entry_set.file.is_dir()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(&self.entry_set)
+    }
+}
+
+/// The timestamps, attributes and allocation of a file or directory
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    attributes: u16,
+    created: Option<Timestamp>,
+    modified: Option<Timestamp>,
+    accessed: Option<Timestamp>,
+    size: u64,
+    valid_size: u64,
+    first_cluster: u32,
+    contiguous: bool,
+}
+
+impl Metadata {
+    fn new(entry_set: &EntrySet) -> Self {
+        Self {
+            attributes: entry_set.file.attributes(),
+            created: entry_set.file.created(),
+            modified: entry_set.file.modified(),
+            accessed: entry_set.file.accessed(),
+            size: entry_set.stream.data_length(),
+            valid_size: entry_set.stream.valid_data_length(),
+            first_cluster: entry_set.stream.first_cluster(),
+            contiguous: entry_set.stream.is_no_fat_chain(),
+        }
+    }
+
+    /// None if the timestamp was never set or is invalid
+    pub fn created(&self) -> Option<Timestamp> {
+        self.created
+    }
+
+    pub fn modified(&self) -> Option<Timestamp> {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> Option<Timestamp> {
+        self.accessed
+    }
+
+    pub fn attributes(&self) -> u16 {
+        self.attributes
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.has_attribute(Attributes::ReadOnly)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attribute(Attributes::Hidden)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.has_attribute(Attributes::System)
+    }
+
+    pub fn is_archive(&self) -> bool {
+        self.has_attribute(Attributes::Archive)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.has_attribute(Attributes::Directory)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// How much of the file has been written, the rest reads as zeros
+    pub fn valid_size(&self) -> u64 {
+        self.valid_size
+    }
+
+    /// 0 for empty files, which have no clusters
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /// Whether the clusters are contiguous and not described by the FAT
+    pub fn is_contiguous(&self) -> bool {
+        self.contiguous
+    }
+
+    fn has_attribute(&self, attribute: Attributes) -> bool {
+        self.attributes & attribute as u16 != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExFatFile {
+    stream: Stream,
+    metadata: Metadata,
+}
+
+impl ExFatFile {
+    pub fn size(&self) -> u64 {
+        self.stream.data_length
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+}
+
+pub struct ExFatDirectory {
+    stream: Stream,
+}
+
+pub struct ExFatDriver<D>
+where
+    D: BlockDevice,
+{
+    block_device: D,
+    boot_record: BootRecord,
+    /// The expanded up-case table, which maps every UTF-16 code unit to its upper case version
+    upcase_table: Vec<u16>,
+    allocation_bitmap: Stream,
+    volume_label: Option<String>,
+    fat_buffer: DataBuffer,
+    data_buffer: DataBuffer,
+}
+
+impl<D: BlockDevice> ExFatDriver<D> {
+    pub fn new(mut block_device: D) -> DriverResult<Self, D::Error> {
+        let mut buffer = [0u8; 512];
+        block_device
+            .read(0, &mut buffer)
+            .map_err(DriverError::Device)?;
+
+        let boot_record = BootRecord::read(&buffer);
+
+        if !boot_record.is_valid() {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let mut driver = Self {
+            block_device,
+            boot_record,
+            upcase_table: Vec::new(),
+            allocation_bitmap: Stream::chain(0, 0),
+            volume_label: None,
+            fat_buffer: DataBuffer::empty(),
+            data_buffer: DataBuffer::empty(),
+        };
+
+        driver.check_boot_checksum()?;
+        driver.read_system_entries()?;
+
+        Ok(driver)
+    }
+
+    pub fn boot_record(&self) -> &BootRecord {
+        &self.boot_record
+    }
+
+    pub fn volume_label(&self) -> Option<&str> {
+        self.volume_label.as_deref()
+    }
+
+    pub fn open_dir(&mut self, path: &str) -> DriverResult<ExFatDirectory, D::Error> {
+        if Self::iter_path_segments(path).next().is_none() {
+            // This is empty of real path segments, but maybe not completely empty
+            if !path.is_empty() {
+                // This is the root directory
+                return Ok(ExFatDirectory {
+                    stream: self.root_directory(),
+                });
+            } else {
+                // This is really empty, which is not a real path
+                return Err(DriverError::PathNotFound);
+            }
+        }
+
+        let found_entry = self.open_entry(path)?;
+
+        if !found_entry.file.is_dir() {
+            return Err(DriverError::IsNotADirectory);
+        }
+
+        Ok(ExFatDirectory {
+            stream: Stream::new(&found_entry.stream),
+        })
+    }
+
+    pub fn read_dir(
+        &mut self,
+        directory: ExFatDirectory,
+    ) -> DriverResult<
+        impl Iterator<Item = DriverResult<ExFatDirectoryEntry, D::Error>> + use<'_, D>,
+        D::Error,
+    > {
+        Ok(EntrySetIterator::new(self, directory.stream)
+            .map(|e| e.map(|e| ExFatDirectoryEntry { entry_set: e })))
+    }
+
+    pub fn open(&mut self, path: &str) -> DriverResult<ExFatFile, D::Error> {
+        let found_entry = self.open_entry(path)?;
+
+        if !found_entry.file.is_file() {
+            return Err(DriverError::IsADirectory);
+        }
+
+        Ok(ExFatFile {
+            stream: Stream::new(&found_entry.stream),
+            metadata: Metadata::new(&found_entry),
+        })
+    }
+
+    /// Reads from `offset` until the buffer is full or the file ends, and returns how many bytes
+    /// were read. The part of the file after its valid data length reads as zeros.
+    pub fn read_file(
+        &mut self,
+        file: &ExFatFile,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> DriverResult<usize, D::Error> {
+        let offset = offset as u64;
+        let end = (offset + buffer.len() as u64).min(file.stream.data_length);
+
+        if offset >= end {
+            return Ok(0);
+        }
+
+        let valid_end = end.min(file.stream.valid_data_length);
+        let len = (end - offset) as usize;
+
+        if offset < valid_end {
+            let valid_len = (valid_end - offset) as usize;
+            self.read_stream(file.stream, offset, &mut buffer[..valid_len])?;
+            buffer[valid_len..len].fill(0);
+        } else {
+            buffer[..len].fill(0);
+        }
+
+        Ok(len)
+    }
+
+    /// Counts the clusters that are free in the allocation bitmap
+    pub fn free_clusters(&mut self) -> DriverResult<u32, D::Error> {
+        let cluster_count = self.boot_record.cluster_count() as u64;
+
+        if self.allocation_bitmap.data_length * 8 < cluster_count {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let mut buffer = [0u8; MAX_BLOCK_SIZE];
+        let mut used = 0;
+        let mut offset = 0;
+
+        // Bit n of the bitmap is cluster n + 2, and any bits after the last cluster don't count
+        while offset * 8 < cluster_count {
+            let len = (cluster_count.div_ceil(8) - offset).min(MAX_BLOCK_SIZE as u64);
+            let chunk = &mut buffer[..len as usize];
+            self.read_stream(self.allocation_bitmap, offset, chunk)?;
+
+            for (i, &b) in chunk.iter().enumerate() {
+                let bits = (cluster_count - (offset + i as u64) * 8).min(8);
+                used += (b & (0xFF >> (8 - bits))).count_ones();
+            }
+
+            offset += len;
+        }
+
+        Ok(self.boot_record.cluster_count() - used)
+    }
+
+    /// Checks the boot region against its checksum sector, which has the checksum repeated over
+    /// the whole sector
+    fn check_boot_checksum(&mut self) -> DriverResult<(), D::Error> {
+        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
+        let mut checksum = 0;
+
+        for sector in 0..BOOT_CHECKSUM_SECTORS {
+            self.read_data_sector(sector)?;
+            checksum = boot_checksum(
+                checksum,
+                &self.data_buffer.inner[..bytes_per_sector],
+                sector,
+            );
+        }
+
+        self.read_data_sector(BOOT_CHECKSUM_SECTORS)?;
+
+        let expected = checksum.to_le_bytes();
+
+        if self.data_buffer.inner[..bytes_per_sector]
+            .chunks_exact(4)
+            .any(|stored| stored != expected)
+        {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        Ok(())
+    }
+
+    /// Finds the allocation bitmap, the up-case table and the volume label in the root directory
+    fn read_system_entries(&mut self) -> DriverResult<(), D::Error> {
+        let root_directory = self.root_directory();
+        let bitmap_index = if self.boot_record.number_of_fats() == 2 {
+            (self.boot_record.active_fat_sector() != self.boot_record.fat_offset()) as u8
+        } else {
+            0
+        };
+
+        let mut allocation_bitmap = None;
+        let mut upcase_table = None;
+        let mut volume_label = None;
+
+        let mut entries = RawEntryIterator::new(self, root_directory);
+
+        while let Some(entry) = entries.next_entry()? {
+            match DirectoryEntry::read(&entry) {
+                DirectoryEntry::EndOfDirectory => break,
+                DirectoryEntry::AllocationBitmap(bitmap)
+                    if bitmap.bitmap_index() == bitmap_index =>
+                {
+                    allocation_bitmap = Some(bitmap);
+                }
+                DirectoryEntry::UpcaseTable(table) => upcase_table = Some(table),
+                DirectoryEntry::VolumeLabel(label) => {
+                    volume_label = Some(String::from_utf16_lossy(label.label()));
+                }
+                _ => {}
+            }
+        }
+
+        let allocation_bitmap = allocation_bitmap.ok_or(DriverError::FileSystemInvalid)?;
+        let upcase_table = upcase_table.ok_or(DriverError::FileSystemInvalid)?;
+
+        if upcase_table.data_length() > MAX_UPCASE_TABLE_SIZE {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let mut stored = vec![0u8; upcase_table.data_length() as usize];
+        self.read_stream(
+            Stream::chain(upcase_table.first_cluster(), upcase_table.data_length()),
+            0,
+            &mut stored,
+        )?;
+
+        if table_checksum(0, &stored) != upcase_table.table_checksum() {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        self.upcase_table = vec![0u16; UPCASE_TABLE_LEN];
+        expand_table(&stored, &mut self.upcase_table)?;
+
+        self.allocation_bitmap = Stream::chain(
+            allocation_bitmap.first_cluster(),
+            allocation_bitmap.data_length(),
+        );
+        self.volume_label = volume_label;
+
+        Ok(())
+    }
+
+    fn root_directory(&self) -> Stream {
+        Stream::chain(
+            self.boot_record.root_directory_cluster(),
+            MAX_DIRECTORY_SIZE,
+        )
+    }
+
+    fn open_entry(&mut self, path: &str) -> DriverResult<EntrySet, D::Error> {
+        let mut segments = Self::iter_path_segments(path).peekable();
+
+        let mut current_dir = self.root_directory();
+
+        while let Some(segment) = segments.next() {
+            let entry_set = self
+                .find_entry_set(segment, current_dir)?
+                .ok_or(DriverError::PathNotFound)?;
+
+            if segments.peek().is_none() {
+                // This is the last segment of the path
+                return Ok(entry_set);
+            } else {
+                // This is a directory segment
+                if !entry_set.file.is_dir() {
+                    return Err(DriverError::PathNotFound);
+                }
+
+                current_dir = Stream::new(&entry_set.stream);
+            }
+        }
+
+        Err(DriverError::PathNotFound)
+    }
+
+    fn iter_path_segments(path: &str) -> impl Iterator<Item = &str> {
+        path.split('/').filter(|s| !s.is_empty())
+    }
+
+    /// Looks a name up the way exFAT compares names, ignoring case as the up-case table defines
+    /// it. The name hash rules out most entry sets without comparing the names.
+    fn find_entry_set(
+        &mut self,
+        name: &str,
+        directory: Stream,
+    ) -> DriverResult<Option<EntrySet>, D::Error> {
+        let mut units = [0u16; MAX_NAME_LEN];
+        let mut len = 0;
+
+        for c in name.encode_utf16() {
+            if len == MAX_NAME_LEN {
+                // No entry can have a name this long
+                return Ok(None);
+            }
+
+            units[len] = c;
+            len += 1;
+        }
+
+        let units = &units[..len];
+        let hash = name_hash(&self.upcase_table, units);
+
+        let mut entry_sets = EntrySetIterator::new(self, directory);
+
+        while let Some(entry_set) = entry_sets.next() {
+            let entry_set = entry_set?;
+
+            if entry_set.stream.name_hash() == hash
+                && names_equal(
+                    &entry_sets.entries.driver.upcase_table,
+                    entry_set.name.units(),
+                    units,
+                )
+            {
+                return Ok(Some(entry_set));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads `buffer.len()` bytes of the stream, starting at `offset`, which all have to be
+    /// inside of it
+    fn read_stream(
+        &mut self,
+        stream: Stream,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> DriverResult<(), D::Error> {
+        let end = offset + buffer.len() as u64;
+
+        if end > stream.data_length {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let bytes_per_cluster = self.boot_record.bytes_per_cluster();
+        let first_index = offset / bytes_per_cluster;
+
+        let mut cluster = if stream.no_fat_chain {
+            if first_index > u32::MAX as u64 {
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            stream.first_cluster.wrapping_add(first_index as u32)
+        } else {
+            let mut cluster = stream.first_cluster;
+
+            for _ in 0..first_index {
+                cluster = self
+                    .next_cluster(stream, cluster)?
+                    .ok_or(DriverError::FileSystemInvalid)?;
+            }
+
+            cluster
+        };
+
+        let mut position = offset;
+
+        while position < end {
+            let offset_in_cluster = position % bytes_per_cluster;
+
+            // Contiguous clusters can be read all at once
+            let len = if stream.no_fat_chain {
+                end - position
+            } else {
+                (bytes_per_cluster - offset_in_cluster).min(end - position)
+            };
+
+            let last_cluster = cluster as u64 + (offset_in_cluster + len - 1) / bytes_per_cluster;
+
+            if !self.boot_record.is_cluster_valid(cluster)
+                || last_cluster > u32::MAX as u64
+                || !self.boot_record.is_cluster_valid(last_cluster as u32)
+            {
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            let disk_offset = self.boot_record.cluster_to_sector(cluster)
+                * self.boot_record.bytes_per_sector()
+                + offset_in_cluster;
+            let start = (position - offset) as usize;
+
+            self.block_device
+                .read(disk_offset, &mut buffer[start..start + len as usize])
+                .map_err(DriverError::Device)?;
+
+            position += len;
+
+            if position < end {
+                cluster = self
+                    .next_cluster(stream, cluster)?
+                    .ok_or(DriverError::FileSystemInvalid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in the stream, or None at the end of a cluster chain.
+    /// Contiguous streams don't end, their length has to be checked instead.
+    fn next_cluster(
+        &mut self,
+        stream: Stream,
+        cluster: u32,
+    ) -> DriverResult<Option<u32>, D::Error> {
+        if stream.no_fat_chain {
+            return Ok(Some(cluster.wrapping_add(1)));
+        }
+
+        let next = self.read_fat_entry(cluster)?;
+
+        if next == END_OF_CHAIN {
+            Ok(None)
+        } else if self.boot_record.is_cluster_valid(next) {
+            Ok(Some(next))
+        } else {
+            // Free or bad clusters, or anything else that isn't in the cluster heap
+            Err(DriverError::FileSystemInvalid)
+        }
+    }
+
+    fn read_fat_entry(&mut self, cluster: u32) -> DriverResult<u32, D::Error> {
+        if !self.boot_record.is_cluster_valid(cluster) {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let bytes_per_sector = self.boot_record.bytes_per_sector();
+        let fat_offset = cluster as u64 * 4;
+        let sector = self.boot_record.active_fat_sector() + fat_offset / bytes_per_sector;
+        let offset_in_sector = (fat_offset % bytes_per_sector) as usize;
+
+        if self.fat_buffer.location != Some(sector) {
+            self.block_device
+                .read(
+                    sector * bytes_per_sector,
+                    &mut self.fat_buffer.inner[..bytes_per_sector as usize],
+                )
+                .map_err(DriverError::Device)?;
+            self.fat_buffer.location = Some(sector);
+        }
+
+        Ok(u32::from_le_bytes(
+            self.fat_buffer.inner[offset_in_sector..offset_in_sector + 4]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+
+    fn read_data_sector(&mut self, sector: u64) -> DriverResult<(), D::Error> {
+        if self.data_buffer.location == Some(sector) {
+            return Ok(());
+        }
+
+        let bytes_per_sector = self.boot_record.bytes_per_sector();
+
+        self.block_device
+            .read(
+                sector * bytes_per_sector,
+                &mut self.data_buffer.inner[..bytes_per_sector as usize],
+            )
+            .map_err(DriverError::Device)?;
+        self.data_buffer.location = Some(sector);
+
+        Ok(())
+    }
+}
+
+/// Goes through the entries of a directory one at a time, keeping track of the cluster it is in
+/// so the cluster chain is only followed once
+struct RawEntryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    driver: &'a mut ExFatDriver<D>,
+    stream: Stream,
+    /// The cluster the next entry is in, or None after the end of the cluster chain
+    cluster: Option<u32>,
+    /// Which cluster of the stream `cluster` is
+    cluster_index: u64,
+    next_index: u64,
+}
+
+impl<'a, D> RawEntryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    fn new(driver: &'a mut ExFatDriver<D>, stream: Stream) -> Self {
+        Self {
+            driver,
+            stream,
+            cluster: Some(stream.first_cluster),
+            cluster_index: 0,
+            next_index: 0,
+        }
+    }
+
+    /// The next entry, or None after the end of the directory's data
+    fn next_entry(&mut self) -> DriverResult<Option<[u8; DIRECTORY_ENTRY_SIZE]>, D::Error> {
+        let position = self.next_index * DIRECTORY_ENTRY_SIZE as u64;
+
+        if position >= self.stream.data_length.min(MAX_DIRECTORY_SIZE) {
+            return Ok(None);
+        }
+
+        let bytes_per_cluster = self.driver.boot_record.bytes_per_cluster();
+
+        while self.cluster_index < position / bytes_per_cluster {
+            if let Some(cluster) = self.cluster {
+                self.cluster = self.driver.next_cluster(self.stream, cluster)?;
+            }
+
+            self.cluster_index += 1;
+        }
+
+        let cluster = match self.cluster {
+            Some(cluster) if self.driver.boot_record.is_cluster_valid(cluster) => cluster,
+            Some(_) => return Err(DriverError::FileSystemInvalid),
+            None => return Ok(None),
+        };
+
+        let bytes_per_sector = self.driver.boot_record.bytes_per_sector();
+        let offset_in_cluster = position % bytes_per_cluster;
+        let sector = self.driver.boot_record.cluster_to_sector(cluster)
+            + offset_in_cluster / bytes_per_sector;
+        let offset_in_sector = (offset_in_cluster % bytes_per_sector) as usize;
+
+        self.driver.read_data_sector(sector)?;
+        self.next_index += 1;
+
+        let mut entry = [0u8; DIRECTORY_ENTRY_SIZE];
+        entry.copy_from_slice(
+            &self.driver.data_buffer.inner
+                [offset_in_sector..offset_in_sector + DIRECTORY_ENTRY_SIZE],
+        );
+
+        Ok(Some(entry))
+    }
+}
+
+/// Goes through the entry sets of the files and directories in a directory, skipping the other
+/// primary entries and deleted entries
+struct EntrySetIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    entries: RawEntryIterator<'a, D>,
+    done: bool,
+}
+
+impl<'a, D> EntrySetIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    fn new(driver: &'a mut ExFatDriver<D>, stream: Stream) -> Self {
+        Self {
+            entries: RawEntryIterator::new(driver, stream),
+            done: false,
+        }
+    }
+
+    /// Reads the secondary entries that follow a File entry and checks them against the set
+    /// checksum
+    fn read_entry_set(
+        &mut self,
+        primary: &[u8; DIRECTORY_ENTRY_SIZE],
+        file: FileEntry,
+    ) -> DriverResult<EntrySet, D::Error> {
+        let mut checksum = set_checksum(0, primary, true);
+        let mut stream = None;
+        let mut units = [0u16; MAX_NAME_LEN];
+        let mut units_len = 0;
+
+        for i in 0..file.secondary_count() {
+            let entry = self
+                .entries
+                .next_entry()?
+                .ok_or(DriverError::FileSystemInvalid)?;
+
+            if entry[0] & (ENTRY_IN_USE | SECONDARY_ENTRY) != ENTRY_IN_USE | SECONDARY_ENTRY {
+                // The set is shorter than its secondary count
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            checksum = set_checksum(checksum, &entry, false);
+
+            match DirectoryEntry::read(&entry) {
+                DirectoryEntry::StreamExtension(entry) if i == 0 => stream = Some(entry),
+                DirectoryEntry::FileName(entry) if stream.is_some() => {
+                    let name_length = stream.map_or(0, |s| s.name_length() as usize);
+                    let len = name_length
+                        .saturating_sub(units_len)
+                        .min(NAME_CHARS_PER_ENTRY);
+
+                    units[units_len..units_len + len].copy_from_slice(&entry.name()[..len]);
+                    units_len += len;
+                }
+                // Vendor extensions and the like, which don't change how the file is read
+                _ => {}
+            }
+        }
+
+        let stream = stream.ok_or(DriverError::FileSystemInvalid)?;
+
+        if checksum != file.set_checksum()
+            || units_len == 0
+            || units_len != stream.name_length() as usize
+        {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        Ok(EntrySet {
+            name: EntryName::new(units, units_len as u8),
+            file,
+            stream,
+        })
+    }
+}
+
+impl<'a, D> Iterator for EntrySetIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    type Item = DriverResult<EntrySet, D::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let entry = match self.entries.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+            match DirectoryEntry::read(&entry) {
+                DirectoryEntry::EndOfDirectory => break,
+                DirectoryEntry::File(file) => return Some(self.read_entry_set(&entry, file)),
+                _ => {}
+            }
+        }
+
+        self.done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_device::memory::MemoryBlockDevice;
+    use exfat_core::{
+        entry::{
+            ALLOCATION_BITMAP_ENTRY, FILE_ENTRY, FILE_NAME_ENTRY, STREAM_EXTENSION_ENTRY,
+            UPCASE_TABLE_ENTRY, VOLUME_LABEL_ENTRY,
+        },
+        record::{BOOT_SIGNATURE, FIRST_CLUSTER},
+    };
+
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+    /// One sector per cluster
+    const CLUSTER_SIZE: usize = SECTOR_SIZE;
+    const FAT_OFFSET: usize = 24;
+    const CLUSTER_HEAP_OFFSET: usize = 32;
+    const CLUSTER_COUNT: usize = 64;
+
+    const BITMAP_CLUSTER: u32 = 2;
+    const UPCASE_CLUSTER: u32 = 3;
+    const ROOT_CLUSTER: u32 = 4;
+    const DIRECTORY_CLUSTER: u32 = 5;
+    /// Clusters 6 to 8, which are not in the FAT
+    const CONTIGUOUS_CLUSTER: u32 = 6;
+    /// Clusters 10, 12 and 11, in that order
+    const FRAGMENTED_CLUSTERS: [u32; 3] = [10, 12, 11];
+    /// Clusters 13 and 14
+    const PARTLY_VALID_CLUSTERS: [u32; 2] = [13, 14];
+
+    type Driver = ExFatDriver<MemoryBlockDevice<Vec<u8>>>;
+
+    /// An up-case table that only maps the ASCII letters, compressed with identity runs
+    fn stored_upcase_table() -> Vec<u8> {
+        let mut units = vec![0xFFFF, b'a' as u16];
+        units.extend((b'A'..=b'Z').map(|c| c as u16));
+        units.extend([0xFFFF, (UPCASE_TABLE_LEN - (b'z' as usize + 1)) as u16]);
+
+        units.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+    }
+
+    fn contents(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    struct Image {
+        bytes: Vec<u8>,
+        upcase_table: Vec<u16>,
+    }
+
+    impl Image {
+        fn new() -> Self {
+            let mut upcase_table = vec![0u16; UPCASE_TABLE_LEN];
+            expand_table(&stored_upcase_table(), &mut upcase_table).unwrap();
+
+            let mut image = Self {
+                bytes: vec![0u8; (CLUSTER_HEAP_OFFSET + CLUSTER_COUNT) * SECTOR_SIZE],
+                upcase_table,
+            };
+
+            image.write_boot_region();
+
+            image.set_fat_entry(0, 0xFFFFFFF8);
+            image.set_fat_entry(1, END_OF_CHAIN);
+            for cluster in [
+                BITMAP_CLUSTER,
+                UPCASE_CLUSTER,
+                ROOT_CLUSTER,
+                DIRECTORY_CLUSTER,
+            ] {
+                image.set_fat_entry(cluster, END_OF_CHAIN);
+            }
+
+            let upcase_table = stored_upcase_table();
+            image.cluster_mut(UPCASE_CLUSTER)[..upcase_table.len()].copy_from_slice(&upcase_table);
+
+            // Clusters 2 to 14 are in use
+            image.cluster_mut(BITMAP_CLUSTER)[..2].copy_from_slice(&[0xFF, 0x1F]);
+
+            let mut bitmap = [0u8; 32];
+            bitmap[0] = ALLOCATION_BITMAP_ENTRY;
+            bitmap[0x14..0x18].copy_from_slice(&BITMAP_CLUSTER.to_le_bytes());
+            bitmap[0x18..0x20].copy_from_slice(&(CLUSTER_COUNT as u64 / 8).to_le_bytes());
+
+            let mut upcase = [0u8; 32];
+            upcase[0] = UPCASE_TABLE_ENTRY;
+            upcase[0x04..0x08].copy_from_slice(&table_checksum(0, &upcase_table).to_le_bytes());
+            upcase[0x14..0x18].copy_from_slice(&UPCASE_CLUSTER.to_le_bytes());
+            upcase[0x18..0x20].copy_from_slice(&(upcase_table.len() as u64).to_le_bytes());
+
+            let mut label = [0u8; 32];
+            label[0] = VOLUME_LABEL_ENTRY;
+            label[1] = 4;
+            for (i, c) in "Test".encode_utf16().enumerate() {
+                label[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+
+            // The contiguous file ends part of the way into its third cluster
+            let contiguous_len = CLUSTER_SIZE * 2 + 100;
+            image.write_file_data(
+                &[
+                    CONTIGUOUS_CLUSTER,
+                    CONTIGUOUS_CLUSTER + 1,
+                    CONTIGUOUS_CLUSTER + 2,
+                ],
+                &contents(1, contiguous_len),
+            );
+
+            let fragmented_len = CLUSTER_SIZE * 3;
+            image.write_file_data(&FRAGMENTED_CLUSTERS, &contents(2, fragmented_len));
+            image.chain(&FRAGMENTED_CLUSTERS);
+
+            // Only the first 700 bytes were written, the rest of the clusters has old data
+            image.write_file_data(&PARTLY_VALID_CLUSTERS, &contents(3, CLUSTER_SIZE * 2));
+            image.chain(&PARTLY_VALID_CLUSTERS);
+
+            let mut root = vec![bitmap, upcase, label];
+            root.extend(image.entry_set(
+                "Directory",
+                Attributes::Directory as u16,
+                DIRECTORY_CLUSTER,
+                false,
+                CLUSTER_SIZE as u64,
+                CLUSTER_SIZE as u64,
+            ));
+            root.extend(image.entry_set(
+                "contiguous.bin",
+                Attributes::Archive as u16,
+                CONTIGUOUS_CLUSTER,
+                true,
+                contiguous_len as u64,
+                contiguous_len as u64,
+            ));
+            root.extend(image.entry_set("empty", Attributes::Archive as u16, 0, false, 0, 0));
+            image.write_directory(ROOT_CLUSTER, &root);
+
+            let mut directory = image.entry_set(
+                "Fragmented.bin",
+                Attributes::Archive as u16,
+                FRAGMENTED_CLUSTERS[0],
+                false,
+                fragmented_len as u64,
+                fragmented_len as u64,
+            );
+            directory.extend(image.entry_set(
+                "partly valid.bin",
+                Attributes::Archive as u16,
+                PARTLY_VALID_CLUSTERS[0],
+                false,
+                700,
+                1000,
+            ));
+            image.write_directory(DIRECTORY_CLUSTER, &directory);
+
+            image
+        }
+
+        fn write_boot_region(&mut self) {
+            let boot = &mut self.bytes[..SECTOR_SIZE];
+            boot[0x00..0x03].copy_from_slice(&[0xEB, 0x76, 0x90]);
+            boot[0x03..0x0B].copy_from_slice(b"EXFAT   ");
+            let volume_length = (CLUSTER_HEAP_OFFSET + CLUSTER_COUNT) as u64;
+            boot[0x48..0x50].copy_from_slice(&volume_length.to_le_bytes());
+            boot[0x50..0x54].copy_from_slice(&(FAT_OFFSET as u32).to_le_bytes());
+            boot[0x54..0x58].copy_from_slice(&1u32.to_le_bytes());
+            boot[0x58..0x5C].copy_from_slice(&(CLUSTER_HEAP_OFFSET as u32).to_le_bytes());
+            boot[0x5C..0x60].copy_from_slice(&(CLUSTER_COUNT as u32).to_le_bytes());
+            boot[0x60..0x64].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+            boot[0x64..0x68].copy_from_slice(&0x1234ABCDu32.to_le_bytes());
+            boot[0x68..0x6A].copy_from_slice(&0x0100u16.to_le_bytes());
+            boot[0x6C] = 9;
+            boot[0x6D] = 0;
+            boot[0x6E] = 1;
+            boot[0x70] = 0xFF;
+            boot[0x1FE..0x200].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+
+            self.write_boot_checksum();
+        }
+
+        fn write_boot_checksum(&mut self) {
+            let mut checksum = 0;
+
+            for sector in 0..BOOT_CHECKSUM_SECTORS {
+                let start = sector as usize * SECTOR_SIZE;
+                checksum = boot_checksum(checksum, &self.bytes[start..start + SECTOR_SIZE], sector);
+            }
+
+            let start = BOOT_CHECKSUM_SECTORS as usize * SECTOR_SIZE;
+            for stored in self.bytes[start..start + SECTOR_SIZE].chunks_exact_mut(4) {
+                stored.copy_from_slice(&checksum.to_le_bytes());
+            }
+        }
+
+        fn set_fat_entry(&mut self, cluster: u32, value: u32) {
+            let offset = FAT_OFFSET * SECTOR_SIZE + cluster as usize * 4;
+            self.bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn chain(&mut self, clusters: &[u32]) {
+            for pair in clusters.windows(2) {
+                self.set_fat_entry(pair[0], pair[1]);
+            }
+
+            self.set_fat_entry(*clusters.last().unwrap(), END_OF_CHAIN);
+        }
+
+        fn cluster_mut(&mut self, cluster: u32) -> &mut [u8] {
+            let start = (CLUSTER_HEAP_OFFSET + (cluster - FIRST_CLUSTER) as usize) * SECTOR_SIZE;
+            &mut self.bytes[start..start + CLUSTER_SIZE]
+        }
+
+        fn write_file_data(&mut self, clusters: &[u32], data: &[u8]) {
+            for (&cluster, chunk) in clusters.iter().zip(data.chunks(CLUSTER_SIZE)) {
+                self.cluster_mut(cluster)[..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        fn write_directory(&mut self, cluster: u32, entries: &[[u8; 32]]) {
+            for (i, entry) in entries.iter().enumerate() {
+                self.cluster_mut(cluster)[i * 32..i * 32 + 32].copy_from_slice(entry);
+            }
+        }
+
+        fn entry_set(
+            &self,
+            name: &str,
+            attributes: u16,
+            first_cluster: u32,
+            no_fat_chain: bool,
+            valid_data_length: u64,
+            data_length: u64,
+        ) -> Vec<[u8; 32]> {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let name_entries = units.len().div_ceil(NAME_CHARS_PER_ENTRY);
+
+            let mut file = [0u8; 32];
+            file[0] = FILE_ENTRY;
+            file[1] = 1 + name_entries as u8;
+            file[0x04..0x06].copy_from_slice(&attributes.to_le_bytes());
+
+            let mut stream = [0u8; 32];
+            stream[0] = STREAM_EXTENSION_ENTRY;
+            stream[1] =
+                if first_cluster != 0 { 0x01 } else { 0 } | if no_fat_chain { 0x02 } else { 0 };
+            stream[0x03] = units.len() as u8;
+            stream[0x04..0x06]
+                .copy_from_slice(&name_hash(&self.upcase_table, &units).to_le_bytes());
+            stream[0x08..0x10].copy_from_slice(&valid_data_length.to_le_bytes());
+            stream[0x14..0x18].copy_from_slice(&first_cluster.to_le_bytes());
+            stream[0x18..0x20].copy_from_slice(&data_length.to_le_bytes());
+
+            let mut entries = vec![file, stream];
+
+            for part in units.chunks(NAME_CHARS_PER_ENTRY) {
+                let mut name = [0u8; 32];
+                name[0] = FILE_NAME_ENTRY;
+                for (i, c) in part.iter().enumerate() {
+                    name[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+                }
+                entries.push(name);
+            }
+
+            let checksum = entries.iter().enumerate().fold(0, |checksum, (i, entry)| {
+                set_checksum(checksum, entry, i == 0)
+            });
+            entries[0][0x02..0x04].copy_from_slice(&checksum.to_le_bytes());
+
+            entries
+        }
+
+        fn mount(self) -> Driver {
+            let device = MemoryBlockDevice::new(self.bytes, SECTOR_SIZE as u64).unwrap();
+            ExFatDriver::new(device).unwrap()
+        }
+    }
+
+    fn read_all(driver: &mut Driver, path: &str) -> Vec<u8> {
+        let file = driver.open(path).unwrap();
+        let mut buffer = vec![0xEEu8; file.size() as usize];
+
+        assert_eq!(
+            driver.read_file(&file, 0, &mut buffer).unwrap(),
+            buffer.len()
+        );
+
+        buffer
+    }
+
+    fn names(driver: &mut Driver, path: &str) -> Vec<(String, bool)> {
+        let directory = driver.open_dir(path).unwrap();
+
+        driver
+            .read_dir(directory)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.name().to_string(), entry.is_directory())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_directories() {
+        let mut driver = Image::new().mount();
+
+        assert_eq!(driver.volume_label(), Some("Test"));
+        assert_eq!(
+            names(&mut driver, "/"),
+            [
+                ("Directory".to_string(), true),
+                ("contiguous.bin".to_string(), false),
+                ("empty".to_string(), false),
+            ]
+        );
+        assert_eq!(
+            names(&mut driver, "/Directory"),
+            [
+                ("Fragmented.bin".to_string(), false),
+                ("partly valid.bin".to_string(), false),
+            ]
+        );
+
+        // 13 clusters are in use
+        assert_eq!(driver.free_clusters().unwrap(), CLUSTER_COUNT as u32 - 13);
+    }
+
+    #[test]
+    fn reads_files() {
+        let mut driver = Image::new().mount();
+
+        let contiguous = read_all(&mut driver, "/contiguous.bin");
+        assert_eq!(contiguous, contents(1, CLUSTER_SIZE * 2 + 100));
+        assert!(driver
+            .open("/contiguous.bin")
+            .unwrap()
+            .metadata()
+            .is_contiguous());
+
+        let fragmented = read_all(&mut driver, "/Directory/Fragmented.bin");
+        assert_eq!(fragmented, contents(2, CLUSTER_SIZE * 3));
+
+        assert_eq!(read_all(&mut driver, "/empty"), []);
+
+        // Reads that start in the middle, and cross into the next cluster
+        let file = driver.open("/Directory/Fragmented.bin").unwrap();
+        let mut buffer = [0u8; 300];
+        driver
+            .read_file(&file, CLUSTER_SIZE + 400, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, fragmented[CLUSTER_SIZE + 400..CLUSTER_SIZE + 700]);
+
+        // Reads past the end stop there
+        assert_eq!(
+            driver
+                .read_file(&file, CLUSTER_SIZE * 3 - 10, &mut buffer)
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            driver
+                .read_file(&file, CLUSTER_SIZE * 3, &mut buffer)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn reads_zeros_after_valid_data_length() {
+        let mut driver = Image::new().mount();
+
+        let file = driver.open("/Directory/partly valid.bin").unwrap();
+        assert_eq!(file.size(), 1000);
+        assert_eq!(file.metadata().valid_size(), 700);
+
+        let mut expected = contents(3, 700);
+        expected.resize(1000, 0);
+        assert_eq!(
+            read_all(&mut driver, "/Directory/partly valid.bin"),
+            expected
+        );
+
+        // Entirely past the valid data
+        let mut buffer = [0xEEu8; 100];
+        assert_eq!(driver.read_file(&file, 800, &mut buffer).unwrap(), 100);
+        assert_eq!(buffer, [0u8; 100]);
+    }
+
+    #[test]
+    fn looks_names_up_ignoring_case() {
+        let mut driver = Image::new().mount();
+
+        assert!(driver.open("/CONTIGUOUS.BIN").is_ok());
+        assert!(driver.open("/directory/fragmented.BIN").is_ok());
+        assert!(driver.open_dir("/DIRECTORY").is_ok());
+        assert!(matches!(
+            driver.open("/Directory/missing"),
+            Err(DriverError::PathNotFound)
+        ));
+    }
+
+    #[test]
+    fn reports_path_errors() {
+        let mut driver = Image::new().mount();
+
+        assert!(matches!(
+            driver.open("/Directory"),
+            Err(DriverError::IsADirectory)
+        ));
+        assert!(matches!(
+            driver.open_dir("/empty"),
+            Err(DriverError::IsNotADirectory)
+        ));
+        assert!(matches!(
+            driver.open("/empty/file"),
+            Err(DriverError::PathNotFound)
+        ));
+    }
+
+    #[test]
+    fn rejects_damaged_volumes() {
+        let mut image = Image::new();
+        image.bytes[SECTOR_SIZE * 3] ^= 1;
+        let device = MemoryBlockDevice::new(image.bytes, SECTOR_SIZE as u64).unwrap();
+        assert!(matches!(
+            ExFatDriver::new(device),
+            Err(DriverError::FileSystemInvalid)
+        ));
+
+        // The volume flags aren't covered by the checksum
+        let mut image = Image::new();
+        image.bytes[0x6A] = 0x02;
+        image.mount();
+
+        // A set checksum that doesn't match
+        let mut image = Image::new();
+        let root = (CLUSTER_HEAP_OFFSET + (ROOT_CLUSTER - FIRST_CLUSTER) as usize) * SECTOR_SIZE;
+        // The Stream Extension entry of the first entry set
+        image.bytes[root + 4 * 32 + 0x10] ^= 1;
+        let mut driver = image.mount();
+        let directory = driver.open_dir("/").unwrap();
+        let entries: Vec<_> = driver.read_dir(directory).unwrap().collect();
+        assert!(matches!(entries[0], Err(DriverError::FileSystemInvalid)));
+        // The entry sets after it are still read
+        assert_eq!(entries.len(), 3);
+        assert!(entries[1..].iter().all(Result::is_ok));
+    }
+}
@@ -0,0 +1,88 @@
+use bin_tools::{read_u16_le, read_u32_le};
+
+/// The inode number, record length, name length and file type before the name
+pub const DIRECTORY_ENTRY_HEADER_SIZE: usize = 8;
+
+/// Names are at most this many bytes long, they aren't null terminated
+pub const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Unknown,
+    RegularFile,
+    Directory,
+    CharacterDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    SymbolicLink
+}
+
+impl From<u8> for FileType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::RegularFile,
+            2 => Self::Directory,
+            3 => Self::CharacterDevice,
+            4 => Self::BlockDevice,
+            5 => Self::Fifo,
+            6 => Self::Socket,
+            7 => Self::SymbolicLink,
+            _ => Self::Unknown
+        }
+    }
+}
+
+/// The fixed part of a linear directory entry, which the name follows. The record length covers
+/// the name and any unused space after it, up to the next entry.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectoryEntry {
+    /// offset 0x0, 0 for unused entries
+    inode: u32,
+    /// offset 0x4
+    record_length: u16,
+    /// offset 0x6
+    name_length: u8,
+    /// offset 0x7, only with the filetype feature, before that this was the high byte of the
+    /// name length, which is always 0
+    file_type: u8
+}
+
+impl DirectoryEntry {
+    pub fn read(buffer: &[u8]) -> Self {
+        Self {
+            inode: read_u32_le(buffer, 0x0),
+            record_length: read_u16_le(buffer, 0x4),
+            name_length: buffer[0x6],
+            file_type: buffer[0x7],
+        }
+    }
+
+    /// 0 for entries that are unused, like deleted entries and the checksum at the end of a
+    /// directory block
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    pub fn record_length(&self) -> usize {
+        self.record_length as usize
+    }
+
+    pub fn name_length(&self) -> usize {
+        self.name_length as usize
+    }
+
+    /// Only meaningful with the filetype feature
+    pub fn file_type(&self) -> FileType {
+        FileType::from(self.file_type)
+    }
+
+    /// Whether the entry holds its name and fits into the `space` bytes left in its block
+    pub fn is_valid(&self, space: usize) -> bool {
+        let record_length = self.record_length();
+
+        record_length >= DIRECTORY_ENTRY_HEADER_SIZE + self.name_length()
+            && record_length.is_multiple_of(4)
+            && record_length <= space
+    }
+}
@@ -0,0 +1,415 @@
+use bin_tools::{read_u16_le, read_u32_le};
+
+use crate::Error;
+
+/// Every node of an extent tree starts with this
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+
+/// The size of the header and of each entry after it
+pub const EXTENT_ENTRY_SIZE: usize = 12;
+
+/// Trees can be at most this many levels deep below the root in the inode
+pub const MAX_EXTENT_DEPTH: u16 = 5;
+
+/// Lengths over this mark uninitialized extents, whose length is the part over it
+const MAX_INITIALIZED_LENGTH: u16 = 32768;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentHeader {
+    /// offset 0x0
+    magic: u16,
+    /// offset 0x2
+    entries: u16,
+    /// offset 0x4
+    max_entries: u16,
+    /// offset 0x6, 0 for leaves, whose entries are extents
+    depth: u16,
+    /// offset 0x8
+    generation: u32
+}
+
+impl ExtentHeader {
+    pub fn read(buffer: &[u8]) -> Self {
+        Self {
+            magic: read_u16_le(buffer, 0x0),
+            entries: read_u16_le(buffer, 0x2),
+            max_entries: read_u16_le(buffer, 0x4),
+            depth: read_u16_le(buffer, 0x6),
+            generation: read_u32_le(buffer, 0x8),
+        }
+    }
+
+    pub fn magic(&self) -> u16 {
+        self.magic
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries as usize
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries as usize
+    }
+
+    pub fn depth(&self) -> u16 {
+        self.depth
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// An entry of an index node, which points at the node below it that covers the blocks from
+/// its first logical block up to the next entry's
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentIndex {
+    /// offset 0x0
+    first_block: u32,
+    // offset 0x4 for lo bytes
+    // offset 0x8 for hi bytes
+    leaf: u64,
+    // offset 0xa is unused
+}
+
+impl ExtentIndex {
+    pub fn read(buffer: &[u8]) -> Self {
+        Self {
+            first_block: read_u32_le(buffer, 0x0),
+            leaf: read_u32_le(buffer, 0x4) as u64 // lo bytes
+                | ((read_u16_le(buffer, 0x8) as u64) << 32), // hi bytes
+        }
+    }
+
+    /// The first logical block of the file that the node below covers
+    pub fn first_block(&self) -> u32 {
+        self.first_block
+    }
+
+    /// The block on the volume that holds the node below
+    pub fn leaf_block(&self) -> u64 {
+        self.leaf
+    }
+}
+
+/// An entry of a leaf node, a run of logical blocks of the file that are contiguous on the
+/// volume
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    /// offset 0x0
+    first_block: u32,
+    /// offset 0x4
+    length: u16,
+    // offset 0x8 for lo bytes
+    // offset 0x6 for hi bytes
+    start: u64
+}
+
+impl Extent {
+    pub fn read(buffer: &[u8]) -> Self {
+        Self {
+            first_block: read_u32_le(buffer, 0x0),
+            length: read_u16_le(buffer, 0x4),
+            start: read_u32_le(buffer, 0x8) as u64 // lo bytes
+                 | ((read_u16_le(buffer, 0x6) as u64) << 32), // hi bytes
+        }
+    }
+
+    /// The first logical block of the file in the extent
+    pub fn first_block(&self) -> u32 {
+        self.first_block
+    }
+
+    /// The number of blocks, whether the extent is initialized or not
+    pub fn length(&self) -> u32 {
+        if self.is_uninitialized() {
+            (self.length - MAX_INITIALIZED_LENGTH) as u32
+        } else {
+            self.length as u32
+        }
+    }
+
+    /// The blocks are allocated but were never written, so they read as zeros
+    pub fn is_uninitialized(&self) -> bool {
+        self.length > MAX_INITIALIZED_LENGTH
+    }
+
+    /// The block on the volume that holds the first block of the extent
+    pub fn start_block(&self) -> u64 {
+        self.start
+    }
+
+    pub fn contains(&self, logical_block: u32) -> bool {
+        logical_block >= self.first_block
+            && ((logical_block - self.first_block) as u64) < self.length() as u64
+    }
+}
+
+/// What a leaf node has for a logical block
+#[derive(Debug, Clone, Copy)]
+pub enum ExtentLookup {
+    Extent(Extent),
+    /// No extent has the block, this is the first logical block of the next one in the node
+    Hole { next: Option<u32> }
+}
+
+/// A node of an extent tree, either the root in an inode's block array or a whole block below
+/// it. The entries are sorted by their first logical block.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentNode<'a> {
+    header: ExtentHeader,
+    buffer: &'a [u8]
+}
+
+impl<'a> ExtentNode<'a> {
+    /// Checks that the buffer starts with a header and has room for all its entries
+    pub fn read(buffer: &'a [u8]) -> Result<Self, Error> {
+        if buffer.len() < EXTENT_ENTRY_SIZE {
+            return Err(Error::BufferSizeTooSmall(buffer.len() as u32));
+        }
+
+        let header = ExtentHeader::read(buffer);
+
+        if header.magic() != EXTENT_MAGIC
+            || header.entries() > header.max_entries()
+            || (header.max_entries() + 1) * EXTENT_ENTRY_SIZE > buffer.len()
+            || header.depth() > MAX_EXTENT_DEPTH
+        {
+            return Err(Error::InvalidExtentNode);
+        }
+
+        Ok(Self { header, buffer })
+    }
+
+    pub fn header(&self) -> &ExtentHeader {
+        &self.header
+    }
+
+    pub fn depth(&self) -> u16 {
+        self.header.depth()
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.header.depth() == 0
+    }
+
+    pub fn entries(&self) -> usize {
+        self.header.entries()
+    }
+
+    /// Only meaningful for index nodes
+    pub fn index(&self, i: usize) -> ExtentIndex {
+        ExtentIndex::read(self.entry(i))
+    }
+
+    /// Only meaningful for leaves
+    pub fn extent(&self, i: usize) -> Extent {
+        Extent::read(self.entry(i))
+    }
+
+    /// The entry whose node covers the logical block, which is the last one that starts at or
+    /// before it, and the first logical block of the entry after it. None if the first entry
+    /// starts after the block.
+    pub fn find_index(&self, logical_block: u32) -> Option<(ExtentIndex, Option<u32>)> {
+        let count = self.entries();
+        let i = self.partition_point(logical_block);
+
+        if i == 0 {
+            return None;
+        }
+
+        let next = (i < count).then(|| self.index(i).first_block());
+
+        Some((self.index(i - 1), next))
+    }
+
+    pub fn find_extent(&self, logical_block: u32) -> ExtentLookup {
+        let count = self.entries();
+        let i = self.partition_point(logical_block);
+
+        if i > 0 {
+            let extent = self.extent(i - 1);
+
+            if extent.contains(logical_block) {
+                return ExtentLookup::Extent(extent);
+            }
+        }
+
+        ExtentLookup::Hole {
+            next: (i < count).then(|| self.extent(i).first_block()),
+        }
+    }
+
+    /// The number of entries that start at or before the logical block
+    fn partition_point(&self, logical_block: u32) -> usize {
+        let mut low = 0;
+        let mut high = self.entries();
+
+        while low < high {
+            let middle = (low + high) / 2;
+
+            // Both kinds of entries start with their first logical block
+            if read_u32_le(self.entry(middle), 0x0) <= logical_block {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        low
+    }
+
+    fn entry(&self, i: usize) -> &'a [u8] {
+        let offset = (i + 1) * EXTENT_ENTRY_SIZE;
+        &self.buffer[offset..offset + EXTENT_ENTRY_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node with room for four entries, like the root in an inode
+    fn node(depth: u16, entries: &[[u8; EXTENT_ENTRY_SIZE]]) -> [u8; 60] {
+        let mut buffer = [0u8; 60];
+
+        buffer[0x0..0x2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        buffer[0x2..0x4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        buffer[0x4..0x6].copy_from_slice(&4u16.to_le_bytes());
+        buffer[0x6..0x8].copy_from_slice(&depth.to_le_bytes());
+
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = (i + 1) * EXTENT_ENTRY_SIZE;
+            buffer[offset..offset + EXTENT_ENTRY_SIZE].copy_from_slice(entry);
+        }
+
+        buffer
+    }
+
+    fn extent(first_block: u32, length: u16, start: u64) -> [u8; EXTENT_ENTRY_SIZE] {
+        let mut entry = [0u8; EXTENT_ENTRY_SIZE];
+
+        entry[0x0..0x4].copy_from_slice(&first_block.to_le_bytes());
+        entry[0x4..0x6].copy_from_slice(&length.to_le_bytes());
+        entry[0x6..0x8].copy_from_slice(&((start >> 32) as u16).to_le_bytes());
+        entry[0x8..0xC].copy_from_slice(&(start as u32).to_le_bytes());
+
+        entry
+    }
+
+    fn index(first_block: u32, leaf: u64) -> [u8; EXTENT_ENTRY_SIZE] {
+        let mut entry = [0u8; EXTENT_ENTRY_SIZE];
+
+        entry[0x0..0x4].copy_from_slice(&first_block.to_le_bytes());
+        entry[0x4..0x8].copy_from_slice(&(leaf as u32).to_le_bytes());
+        entry[0x8..0xA].copy_from_slice(&((leaf >> 32) as u16).to_le_bytes());
+
+        entry
+    }
+
+    fn found(lookup: ExtentLookup) -> (u32, u32, u64) {
+        match lookup {
+            ExtentLookup::Extent(extent) => (extent.first_block(), extent.length(), extent.start_block()),
+            ExtentLookup::Hole { .. } => panic!("Found a hole instead of an extent")
+        }
+    }
+
+    fn hole(lookup: ExtentLookup) -> Option<u32> {
+        match lookup {
+            ExtentLookup::Hole { next } => next,
+            ExtentLookup::Extent(extent) => panic!("Found {extent:?} instead of a hole")
+        }
+    }
+
+    #[test]
+    fn finds_extents_in_leaves() {
+        let buffer = node(0, &[
+            extent(0, 4, 1000),
+            extent(4, 2, 0x1_0000_0010),
+            extent(10, 5, 2000),
+        ]);
+        let node = ExtentNode::read(&buffer).unwrap();
+
+        assert!(node.is_leaf());
+        assert_eq!(node.entries(), 3);
+
+        assert_eq!(found(node.find_extent(0)), (0, 4, 1000));
+        assert_eq!(found(node.find_extent(3)), (0, 4, 1000));
+        // The high bits of the start block come from their own field
+        assert_eq!(found(node.find_extent(5)), (4, 2, 0x1_0000_0010));
+        assert_eq!(found(node.find_extent(14)), (10, 5, 2000));
+    }
+
+    #[test]
+    fn finds_holes_between_extents() {
+        let buffer = node(0, &[extent(2, 4, 1000), extent(10, 5, 2000)]);
+        let node = ExtentNode::read(&buffer).unwrap();
+
+        // Before the first extent, between the two and after the last
+        assert_eq!(hole(node.find_extent(0)), Some(2));
+        assert_eq!(hole(node.find_extent(6)), Some(10));
+        assert_eq!(hole(node.find_extent(9)), Some(10));
+        assert_eq!(hole(node.find_extent(15)), None);
+        assert_eq!(hole(node.find_extent(u32::MAX)), None);
+    }
+
+    #[test]
+    fn lengths_over_32768_are_uninitialized() {
+        let initialized = Extent::read(&extent(0, 32768, 1000));
+        assert!(!initialized.is_uninitialized());
+        assert_eq!(initialized.length(), 32768);
+        assert!(initialized.contains(32767));
+        assert!(!initialized.contains(32768));
+
+        let uninitialized = Extent::read(&extent(100, 32769, 1000));
+        assert!(uninitialized.is_uninitialized());
+        assert_eq!(uninitialized.length(), 1);
+        assert!(uninitialized.contains(100));
+        assert!(!uninitialized.contains(101));
+
+        let longest = Extent::read(&extent(0, u16::MAX, 1000));
+        assert!(longest.is_uninitialized());
+        assert_eq!(longest.length(), 32767);
+    }
+
+    #[test]
+    fn finds_the_index_that_covers_a_block() {
+        let buffer = node(1, &[index(0, 500), index(100, 0x2_0000_0600), index(200, 700)]);
+        let indexes = ExtentNode::read(&buffer).unwrap();
+
+        assert!(!indexes.is_leaf());
+
+        let (entry, next) = indexes.find_index(150).unwrap();
+        assert_eq!(entry.first_block(), 100);
+        assert_eq!(entry.leaf_block(), 0x2_0000_0600);
+        assert_eq!(next, Some(200));
+
+        let (entry, next) = indexes.find_index(99).unwrap();
+        assert_eq!(entry.leaf_block(), 500);
+        assert_eq!(next, Some(100));
+
+        let (entry, next) = indexes.find_index(1_000_000).unwrap();
+        assert_eq!(entry.leaf_block(), 700);
+        assert_eq!(next, None);
+
+        // Nothing covers blocks before the first entry
+        let buffer = node(1, &[index(10, 500)]);
+        assert!(ExtentNode::read(&buffer).unwrap().find_index(5).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_nodes() {
+        let mut buffer = node(0, &[extent(0, 1, 1000)]);
+        buffer[0] = 0;
+        assert!(matches!(ExtentNode::read(&buffer), Err(Error::InvalidExtentNode)));
+
+        // More entries than the node has room for
+        let buffer = node(0, &[extent(0, 1, 1000); 4]);
+        assert!(matches!(ExtentNode::read(&buffer[..48]), Err(Error::InvalidExtentNode)));
+
+        let buffer = node(MAX_EXTENT_DEPTH + 1, &[]);
+        assert!(matches!(ExtentNode::read(&buffer), Err(Error::InvalidExtentNode)));
+
+        assert!(matches!(ExtentNode::read(&buffer[..8]), Err(Error::BufferSizeTooSmall(8))));
+    }
+}
@@ -1,46 +1,78 @@
-use bin_tools::read_u32_le;
+use bin_tools::{read_u16_le, read_u32_le};
 
-use crate::inode::BlockNumber;
+/// The size of group descriptors on filesystems without the 64bit feature
+pub const GROUP_DESCRIPTOR_SIZE: usize = 32;
 
+/// The smallest size of group descriptors on filesystems with the 64bit feature, which have the
+/// upper halves of the fields after the first 32 bytes
+pub const GROUP_DESCRIPTOR_SIZE_64BIT: usize = 64;
 
 #[derive(Debug, Clone, Copy)]
 pub struct GroupDescriptor {
-    /// offset 0x00
-    block_bitmap: BlockNumber,
-    /// offset 0x04
-    inode_bitmap: BlockNumber,
-    /// offset 0x08
-    inode_table: BlockNumber,
-    /// offset 0x0c
+    // offset 0x00 for lo bytes
+    // offset 0x20 for hi bytes
+    block_bitmap: u64,
+    // offset 0x04 for lo bytes
+    // offset 0x24 for hi bytes
+    inode_bitmap: u64,
+    // offset 0x08 for lo bytes
+    // offset 0x28 for hi bytes
+    inode_table: u64,
+    // offset 0x0c for lo bytes
+    // offset 0x2c for hi bytes
     free_blocks_count: u32,
-    /// offset 0x10
+    // offset 0x0e for lo bytes
+    // offset 0x2e for hi bytes
     free_inodes_count: u32,
-    /// offset 0x14
+    // offset 0x10 for lo bytes
+    // offset 0x30 for hi bytes
     used_dirs_count: u32,
-    // padding and reserved bytes
+    // offset 0x12
+    flags: u16,
+    // checksums, reserved bytes and padding
 }
 
 impl GroupDescriptor {
+    /// The group's inode table hasn't been written yet, so every inode in it is unused
+    pub const INODES_UNINITIALIZED: u16 = 0x0001;
+    /// The group's block bitmap hasn't been written yet
+    pub const BLOCK_BITMAP_UNINITIALIZED: u16 = 0x0002;
+    /// The group's inode table has been zeroed
+    pub const INODE_TABLE_ZEROED: u16 = 0x0004;
+
+    /// Reads the upper halves of the fields too if the buffer is long enough to have them, so
+    /// the buffer should be exactly one descriptor
     pub fn read(buffer: &[u8]) -> Self {
+        let is_64bit = buffer.len() >= GROUP_DESCRIPTOR_SIZE_64BIT;
+        let hi_u32 = |offset| if is_64bit { read_u32_le(buffer, offset) } else { 0 };
+        let hi_u16 = |offset| if is_64bit { read_u16_le(buffer, offset) } else { 0 };
+
         Self {
-            block_bitmap: BlockNumber::from(read_u32_le(buffer, 0x00)),
-            inode_bitmap: BlockNumber::from(read_u32_le(buffer, 0x04)),
-            inode_table: BlockNumber::from(read_u32_le(buffer, 0x08)),
-            free_blocks_count: read_u32_le(buffer, 0x0c),
-            free_inodes_count: read_u32_le(buffer, 0x10),
-            used_dirs_count: read_u32_le(buffer, 0x14),
+            block_bitmap: read_u32_le(buffer, 0x00) as u64 // lo bytes
+                        | ((hi_u32(0x20) as u64) << 32), // hi bytes
+            inode_bitmap: read_u32_le(buffer, 0x04) as u64 // lo bytes
+                        | ((hi_u32(0x24) as u64) << 32), // hi bytes
+            inode_table: read_u32_le(buffer, 0x08) as u64 // lo bytes
+                       | ((hi_u32(0x28) as u64) << 32), // hi bytes
+            free_blocks_count: read_u16_le(buffer, 0x0c) as u32 // lo bytes
+                             | ((hi_u16(0x2c) as u32) << 16), // hi bytes
+            free_inodes_count: read_u16_le(buffer, 0x0e) as u32 // lo bytes
+                             | ((hi_u16(0x2e) as u32) << 16), // hi bytes
+            used_dirs_count: read_u16_le(buffer, 0x10) as u32 // lo bytes
+                           | ((hi_u16(0x30) as u32) << 16), // hi bytes
+            flags: read_u16_le(buffer, 0x12),
         }
     }
 
-    pub fn block_bitmap_block(&self) -> BlockNumber {
+    pub fn block_bitmap_block(&self) -> u64 {
         self.block_bitmap
     }
 
-    pub fn inode_bitmap_block(&self) -> BlockNumber {
+    pub fn inode_bitmap_block(&self) -> u64 {
         self.inode_bitmap
     }
 
-    pub fn inode_table_block(&self) -> BlockNumber {
+    pub fn inode_table_block(&self) -> u64 {
         self.inode_table
     }
 
@@ -55,4 +87,12 @@ impl GroupDescriptor {
     pub fn used_dirs(&self) -> u32 {
         self.used_dirs_count
     }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    pub fn inodes_uninitialized(&self) -> bool {
+        (self.flags & Self::INODES_UNINITIALIZED) != 0
+    }
 }
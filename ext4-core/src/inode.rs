@@ -2,6 +2,14 @@ use core::fmt::Debug;
 
 use bin_tools::{read_i32_le, read_u16_le, read_u32_le};
 
+use crate::directory::FileType;
+
+/// The inode of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+/// The part of an inode that every inode size has, larger inodes have extra fields after it
+pub const INODE_SIZE: usize = 128;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Inode {
     /// offset 0x00
@@ -71,6 +79,57 @@ impl Inode {
             osd_2_3: read_u32_le(buffer, 0x7c),
         }
     }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Includes the high 16 bits from the OS dependent fields
+    pub fn uid(&self) -> u32 {
+        self.uid as u32 | ((self.osd_2_2 & 0xFFFF) << 16)
+    }
+
+    /// Includes the high 16 bits from the OS dependent fields
+    pub fn gid(&self) -> u32 {
+        self.gid as u32 | (self.osd_2_2 & 0xFFFF0000)
+    }
+
+    /// The size in bytes, whose high 32 bits are in what used to be the directory ACL field
+    pub fn size(&self) -> u64 {
+        self.size as u32 as u64 | ((self.dir_acl as u64) << 32)
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn access_time(&self) -> u32 {
+        self.atime
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn change_time(&self) -> u32 {
+        self.ctime
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn modification_time(&self) -> u32 {
+        self.mtime
+    }
+
+    /// Seconds since the Unix epoch, 0 if the inode is in use
+    pub fn deletion_time(&self) -> u32 {
+        self.dtime
+    }
+
+    pub fn links_count(&self) -> u16 {
+        self.links_count
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn blocks(&self) -> &Blocks {
+        &self.blocks
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -110,32 +169,53 @@ impl Debug for Mode {
 }
 
 impl Mode {
+    /// The top four bits hold the file type as a number, not as separate flags
+    const FILE_TYPE_MASK: u16 = 0xF000;
+
+    pub fn file_type(&self) -> FileType {
+        match self.0 & Self::FILE_TYPE_MASK {
+            0x1000 => FileType::Fifo,
+            0x2000 => FileType::CharacterDevice,
+            0x4000 => FileType::Directory,
+            0x6000 => FileType::BlockDevice,
+            0x8000 => FileType::RegularFile,
+            0xA000 => FileType::SymbolicLink,
+            0xC000 => FileType::Socket,
+            _ => FileType::Unknown
+        }
+    }
+
+    /// The permission bits, including the set UID, set GID and sticky bits
+    pub fn permissions(&self) -> u16 {
+        self.0 & !Self::FILE_TYPE_MASK
+    }
+
     pub fn is_socket(&self) -> bool {
-        (0xC000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0xC000
     }
 
     pub fn is_symbolic_link(&self) -> bool {
-        (0xA000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0xA000
     }
 
     pub fn is_regular_file(&self) -> bool {
-        (0x8000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0x8000
     }
 
     pub fn is_block_device(&self) -> bool {
-        (0x6000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0x6000
     }
 
     pub fn is_directory(&self) -> bool {
-        (0x4000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0x4000
     }
 
     pub fn is_character_device(&self) -> bool {
-        (0x2000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0x2000
     }
 
     pub fn is_fifo(&self) -> bool {
-        (0x1000 & self.0) != 0
+        (self.0 & Self::FILE_TYPE_MASK) == 0x1000
     }
 
     pub fn is_set_uid(&self) -> bool {
@@ -289,6 +369,16 @@ impl Flags {
         (0x00008000 & self.0) != 0
     }
 
+    /// The blocks are described by an extent tree instead of a block map
+    pub fn extents(&self) -> bool {
+        (0x00080000 & self.0) != 0
+    }
+
+    /// The data is stored in the inode itself
+    pub fn inline_data(&self) -> bool {
+        (0x10000000 & self.0) != 0
+    }
+
     pub fn reserved(&self) -> bool {
         (0x80000000 & self.0) != 0
     }
@@ -336,6 +426,17 @@ impl Blocks {
         }
     }
 
+    /// The array as it is stored, which is how extent trees use it
+    pub fn to_bytes(&self) -> [u8; 60] {
+        let mut bytes = [0u8; 60];
+
+        for (chunk, block) in bytes.chunks_exact_mut(4).zip(self.0) {
+            chunk.copy_from_slice(&block.to_le_bytes());
+        }
+
+        bytes
+    }
+
     pub fn indirect_block(&self) -> BlockNumber {
         BlockNumber::from(self.0[12])
     }
@@ -6,12 +6,16 @@ use core::fmt::Display;
 pub mod superblock;
 pub mod inode;
 pub mod groups;
+pub mod directory;
+pub mod extent;
 
 pub const EXT4_MAGIC: u16 = 0xEF53;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Error {
-    BufferSizeTooSmall(u32)
+    BufferSizeTooSmall(u32),
+    /// An extent tree node has the wrong magic number or more entries than fit
+    InvalidExtentNode
 }
 
 impl Display for Error {
@@ -20,6 +24,11 @@ impl Display for Error {
             Self::BufferSizeTooSmall(size) => {
                 write!(f, "Buffer size too small, was only {size} bytes.")
             }
+            Self::InvalidExtentNode => {
+                write!(f, "Invalid extent tree node.")
+            }
         }
     }
 }
+
+impl core::error::Error for Error {}
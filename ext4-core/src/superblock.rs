@@ -38,6 +38,56 @@ impl From<u16> for FileSystemState {
     }
 }
 
+/// Features that change the on-disk format so much that a driver which doesn't know them can't
+/// read the filesystem at all
+#[derive(Debug, Copy, Clone)]
+pub struct IncompatibleFeatures(u32);
+
+impl IncompatibleFeatures {
+    pub const COMPRESSION: u32 = 0x00001;
+    /// Directory entries store the file type
+    pub const FILE_TYPE: u32 = 0x00002;
+    /// The journal has to be replayed
+    pub const NEEDS_RECOVERY: u32 = 0x00004;
+    /// This is the external journal of another filesystem
+    pub const JOURNAL_DEVICE: u32 = 0x00008;
+    /// The group descriptors are spread over the meta block groups
+    pub const META_BLOCK_GROUPS: u32 = 0x00010;
+    /// Files can use extent trees instead of block maps
+    pub const EXTENTS: u32 = 0x00040;
+    /// Block numbers are 64 bits, and group descriptors are larger
+    pub const IS_64BIT: u32 = 0x00080;
+    pub const MULTIPLE_MOUNT_PROTECTION: u32 = 0x00100;
+    /// The bitmaps and inode tables of several groups are together
+    pub const FLEXIBLE_BLOCK_GROUPS: u32 = 0x00200;
+    /// Large extended attributes are stored in inodes
+    pub const EXTENDED_ATTRIBUTE_INODES: u32 = 0x00400;
+    pub const DIRECTORY_DATA: u32 = 0x01000;
+    /// The checksum seed is stored in the superblock
+    pub const CHECKSUM_SEED: u32 = 0x02000;
+    /// Directories can be larger than 2 GiB and have three level hash trees
+    pub const LARGE_DIRECTORIES: u32 = 0x04000;
+    /// Small files and directories are stored in their inode
+    pub const INLINE_DATA: u32 = 0x08000;
+    pub const ENCRYPTION: u32 = 0x10000;
+    /// Directories can look names up without regard to case
+    pub const CASE_FOLDING: u32 = 0x20000;
+
+    pub fn contains(&self, features: u32) -> bool {
+        (self.0 & features) == features
+    }
+
+    pub fn raw_value(&self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for IncompatibleFeatures {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum SuperBlockErrorPolicy {
     Continue,
@@ -342,7 +392,7 @@ pub struct SuperBlock {
     // offset 0x5c
     compatible_features: u32,
     // offset 0x60
-    incompatible_features: u32,
+    incompatible_features: IncompatibleFeatures,
     // offset 0x64
     read_only_compatible_features: u32,
     // offset 0x68
@@ -510,7 +560,7 @@ impl SuperBlock {
             inode_size: read_u16_le(buffer, 0x58),
             block_group_number: read_u16_le(buffer, 0x5a),
             compatible_features: read_u32_le(buffer, 0x5c),
-            incompatible_features: read_u32_le(buffer, 0x60),
+            incompatible_features: IncompatibleFeatures::from(read_u32_le(buffer, 0x60)),
             read_only_compatible_features: read_u32_le(buffer, 0x64),
             uuid: read_uuid(buffer, 0x68),
             volume_label: read_label(buffer, 0x78),
@@ -580,6 +630,42 @@ impl SuperBlock {
         self.magic
     }
 
+    pub fn inodes_count(&self) -> u32 {
+        self.inodes_count
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        self.blocks_count
+    }
+
+    /// The block the first group starts at, which holds the superblock on filesystems with 1 KiB
+    /// blocks and is 0 otherwise
+    pub fn first_data_block(&self) -> u32 {
+        self.first_data_block
+    }
+
+    /// 1024 bytes shifted left by the stored logarithm, or None if that is over the 64 KiB that
+    /// ext4 allows
+    pub fn block_size(&self) -> Option<u64> {
+        if self.log_block_size > 6 {
+            return None;
+        }
+
+        Some(1024 << self.log_block_size)
+    }
+
+    pub fn blocks_per_group(&self) -> u32 {
+        self.blocks_per_group
+    }
+
+    pub fn inodes_per_group(&self) -> u32 {
+        self.inodes_per_group
+    }
+
+    pub fn incompatible_features(&self) -> IncompatibleFeatures {
+        self.incompatible_features
+    }
+
     pub fn volume_label(&self) -> &str {
         self.volume_label.as_str()
     }
@@ -608,8 +694,12 @@ impl SuperBlock {
         self.group_descriptor_size
     }
 
+    /// The original revision has no inode size field and always uses 128 byte inodes
     pub fn inode_size(&self) -> u16 {
-        self.inode_size
+        match self.revision_level {
+            Revision::Original => 128,
+            _ => self.inode_size
+        }
     }
 }
 
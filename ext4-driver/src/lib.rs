@@ -0,0 +1,1075 @@
+use core::fmt::{Debug, Display};
+
+use block_device::{BlockDevice, OffsetRead};
+use ext4_core::{
+    directory::{DirectoryEntry, FileType, DIRECTORY_ENTRY_HEADER_SIZE},
+    extent::{ExtentLookup, ExtentNode},
+    groups::{GroupDescriptor, GROUP_DESCRIPTOR_SIZE, GROUP_DESCRIPTOR_SIZE_64BIT},
+    inode::{Inode, Mode, INODE_SIZE, ROOT_INODE},
+    superblock::{IncompatibleFeatures, SuperBlock},
+    EXT4_MAGIC,
+};
+
+/// The superblock is 1024 bytes into the volume whatever the block size is
+const SUPERBLOCK_OFFSET: u64 = 1024;
+
+const SUPERBLOCK_SIZE: usize = 1024;
+
+/// Block maps have 12 direct blocks before the indirect, doubly and triply indirect ones
+const DIRECT_BLOCKS: u64 = 12;
+
+/// Extent trees cover logical blocks below this
+const EXTENT_BLOCK_LIMIT: u64 = 1 << 32;
+
+/// The incompatible features this driver can read volumes with. Features that only matter when
+/// writing, or to the journal, don't change how the volume is read.
+const SUPPORTED_FEATURES: u32 = IncompatibleFeatures::FILE_TYPE
+    | IncompatibleFeatures::NEEDS_RECOVERY
+    | IncompatibleFeatures::EXTENTS
+    | IncompatibleFeatures::IS_64BIT
+    | IncompatibleFeatures::MULTIPLE_MOUNT_PROTECTION
+    | IncompatibleFeatures::FLEXIBLE_BLOCK_GROUPS
+    | IncompatibleFeatures::EXTENDED_ATTRIBUTE_INODES
+    | IncompatibleFeatures::CHECKSUM_SEED
+    | IncompatibleFeatures::LARGE_DIRECTORIES;
+
+pub type DriverResult<T, E> = Result<T, DriverError<E>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum DriverError<E> {
+    /// The block device returned an error
+    Device(block_device::Error<E>),
+    /// The superblock doesn't have the ext2, ext3 and ext4 magic number
+    BadMagic,
+    /// The volume or a file uses these incompatible features, which the driver can't read
+    UnsupportedFeatures(u32),
+    /// A directory entry or path points at an inode that isn't on the volume
+    InodeOutOfRange(u32),
+    /// A block map or group descriptor points at a block that isn't on the volume
+    BlockOutOfRange(u64),
+    /// An error from decoding the on-disk structures
+    Format(ext4_core::Error),
+    /// The volume is inconsistent in some other way
+    FileSystemInvalid,
+    PathNotFound,
+    IsADirectory,
+    IsNotADirectory,
+    /// Only regular files can be read, not devices, pipes, sockets or symbolic links
+    IsNotARegularFile,
+}
+
+impl<E> From<ext4_core::Error> for DriverError<E> {
+    fn from(error: ext4_core::Error) -> Self {
+        Self::Format(error)
+    }
+}
+
+impl<E: Debug> Display for DriverError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => write!(f, "{e}"),
+            Self::BadMagic => write!(f, "The superblock's magic number is wrong."),
+            Self::UnsupportedFeatures(features) => {
+                write!(f, "Unsupported incompatible features 0x{features:05X}.")
+            }
+            Self::InodeOutOfRange(inode) => write!(f, "Inode {inode} is outside of the volume."),
+            Self::BlockOutOfRange(block) => write!(f, "Block {block} is outside of the volume."),
+            Self::Format(e) => write!(f, "{e}"),
+            Self::FileSystemInvalid => write!(f, "The file system is invalid."),
+            Self::PathNotFound => write!(f, "No such file or directory."),
+            Self::IsADirectory => write!(f, "Is a directory."),
+            Self::IsNotADirectory => write!(f, "Not a directory."),
+            Self::IsNotARegularFile => write!(f, "Not a regular file."),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for DriverError<E> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where a run of a file's blocks is, starting at the block that was looked up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockMapping {
+    /// The blocks are on the volume from `start` on
+    Mapped { start: u64, length: u64 },
+    /// The blocks are allocated from `start` on, but were never written so they read as zeros
+    Uninitialized { start: u64, length: u64 },
+    /// The file has a hole there, which reads as zeros
+    Hole { length: u64 },
+}
+
+impl BlockMapping {
+    /// The number of blocks in the run
+    pub fn length(&self) -> u64 {
+        match *self {
+            Self::Mapped { length, .. } => length,
+            Self::Uninitialized { length, .. } => length,
+            Self::Hole { length } => length,
+        }
+    }
+
+    /// Puts the run after this one onto its end if it carries on the same way
+    fn join(self, next: Self) -> Option<Self> {
+        match (self, next) {
+            (
+                Self::Mapped { start, length },
+                Self::Mapped {
+                    start: next_start,
+                    length: next_length,
+                },
+            ) if start + length == next_start => Some(Self::Mapped {
+                start,
+                length: length + next_length,
+            }),
+            (
+                Self::Uninitialized { start, length },
+                Self::Uninitialized {
+                    start: next_start,
+                    length: next_length,
+                },
+            ) if start + length == next_start => Some(Self::Uninitialized {
+                start,
+                length: length + next_length,
+            }),
+            (
+                Self::Hole { length },
+                Self::Hole {
+                    length: next_length,
+                },
+            ) => Some(Self::Hole {
+                length: length + next_length,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Where looking a block up in one node of an extent tree leads
+enum ExtentStep {
+    Found(BlockMapping),
+    /// The node below that covers the block, which covers the blocks before `end`
+    Child {
+        block: u64,
+        depth: u16,
+        end: u64,
+    },
+}
+
+/// One block of the volume and which block it is
+struct BlockBuffer {
+    inner: Vec<u8>,
+    location: Option<u64>,
+}
+
+impl BlockBuffer {
+    fn new(block_size: u64) -> Self {
+        Self {
+            inner: vec![0u8; block_size as usize],
+            location: None,
+        }
+    }
+}
+
+pub struct Ext4DirectoryEntry {
+    name: String,
+    inode_number: u32,
+    file_type: FileType,
+}
+
+impl Ext4DirectoryEntry {
+    /// Names are bytes without an encoding, anything that isn't UTF-8 is replaced
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::RegularFile
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+/// The type, permissions, owner, size and timestamps of a file or directory
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    inode_number: u32,
+    mode: Mode,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    links_count: u16,
+    accessed: u32,
+    changed: u32,
+    modified: u32,
+}
+
+impl Metadata {
+    fn new(inode_number: u32, inode: &Inode) -> Self {
+        Self {
+            inode_number,
+            mode: inode.mode(),
+            uid: inode.uid(),
+            gid: inode.gid(),
+            size: inode.size(),
+            links_count: inode.links_count(),
+            accessed: inode.access_time(),
+            changed: inode.change_time(),
+            modified: inode.modification_time(),
+        }
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.mode.file_type()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode.is_regular_file()
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.mode.is_directory()
+    }
+
+    pub fn is_symbolic_link(&self) -> bool {
+        self.mode.is_symbolic_link()
+    }
+
+    /// The permission bits, including the set UID, set GID and sticky bits
+    pub fn permissions(&self) -> u16 {
+        self.mode.permissions()
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn links_count(&self) -> u16 {
+        self.links_count
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn accessed(&self) -> u32 {
+        self.accessed
+    }
+
+    /// When the inode last changed, in seconds since the Unix epoch
+    pub fn changed(&self) -> u32 {
+        self.changed
+    }
+
+    /// When the contents last changed, in seconds since the Unix epoch
+    pub fn modified(&self) -> u32 {
+        self.modified
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ext4File {
+    inode_number: u32,
+    inode: Inode,
+}
+
+impl Ext4File {
+    pub fn size(&self) -> u64 {
+        self.inode.size()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(self.inode_number, &self.inode)
+    }
+}
+
+pub struct Ext4Directory {
+    inode: Inode,
+}
+
+pub struct Ext4Driver<D>
+where
+    D: BlockDevice,
+{
+    block_device: D,
+    superblock: SuperBlock,
+    block_size: u64,
+    group_descriptors: Vec<GroupDescriptor>,
+    /// The last indirect block or extent tree node a lookup went through
+    map_buffer: BlockBuffer,
+    /// The last directory block
+    data_buffer: BlockBuffer,
+}
+
+impl<D: BlockDevice> Ext4Driver<D> {
+    pub fn new(mut block_device: D) -> DriverResult<Self, D::Error> {
+        let mut buffer = [0u8; SUPERBLOCK_SIZE];
+        block_device
+            .read(SUPERBLOCK_OFFSET, &mut buffer)
+            .map_err(DriverError::Device)?;
+
+        let superblock = SuperBlock::read(&buffer)?;
+
+        if superblock.magic() != EXT4_MAGIC {
+            return Err(DriverError::BadMagic);
+        }
+
+        let features = superblock.incompatible_features().raw_value();
+
+        if features & !SUPPORTED_FEATURES != 0 {
+            return Err(DriverError::UnsupportedFeatures(
+                features & !SUPPORTED_FEATURES,
+            ));
+        }
+
+        let block_size = superblock
+            .block_size()
+            .ok_or(DriverError::FileSystemInvalid)?;
+        let inode_size = superblock.inode_size() as u64;
+
+        // The volume has to fit on the device, which also bounds how many groups there can be
+        let device_size = block_device.num_blocks() * block_device.block_size();
+
+        if superblock.blocks_per_group() == 0
+            || superblock.inodes_per_group() == 0
+            || superblock.blocks_count() <= superblock.first_data_block() as u64
+            || superblock.blocks_count() > device_size / block_size
+            || inode_size < INODE_SIZE as u64
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+        {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let mut driver = Self {
+            block_device,
+            superblock,
+            block_size,
+            group_descriptors: Vec::new(),
+            map_buffer: BlockBuffer::new(block_size),
+            data_buffer: BlockBuffer::new(block_size),
+        };
+
+        driver.read_group_descriptors()?;
+
+        Ok(driver)
+    }
+
+    pub fn superblock(&self) -> &SuperBlock {
+        &self.superblock
+    }
+
+    pub fn group_descriptors(&self) -> &[GroupDescriptor] {
+        &self.group_descriptors
+    }
+
+    pub fn open_dir(&mut self, path: &str) -> DriverResult<Ext4Directory, D::Error> {
+        if path.is_empty() {
+            return Err(DriverError::PathNotFound);
+        }
+
+        let (_, inode) = self.lookup(path)?;
+
+        if !inode.mode().is_directory() {
+            return Err(DriverError::IsNotADirectory);
+        }
+
+        Ok(Ext4Directory { inode })
+    }
+
+    /// Goes through the entries of a directory, including `.` and `..`
+    pub fn read_dir(
+        &mut self,
+        directory: Ext4Directory,
+    ) -> DriverResult<
+        impl Iterator<Item = DriverResult<Ext4DirectoryEntry, D::Error>> + use<'_, D>,
+        D::Error,
+    > {
+        Ok(DirectoryIterator::new(self, directory.inode))
+    }
+
+    pub fn open(&mut self, path: &str) -> DriverResult<Ext4File, D::Error> {
+        let (inode_number, inode) = self.lookup(path)?;
+
+        if inode.mode().is_directory() {
+            return Err(DriverError::IsADirectory);
+        }
+
+        if !inode.mode().is_regular_file() {
+            return Err(DriverError::IsNotARegularFile);
+        }
+
+        Ok(Ext4File {
+            inode_number,
+            inode,
+        })
+    }
+
+    /// The metadata of any kind of file, including directories
+    pub fn metadata(&mut self, path: &str) -> DriverResult<Metadata, D::Error> {
+        if path.is_empty() {
+            return Err(DriverError::PathNotFound);
+        }
+
+        let (inode_number, inode) = self.lookup(path)?;
+
+        Ok(Metadata::new(inode_number, &inode))
+    }
+
+    /// Reads from `offset` until the buffer is full or the file ends, and returns how many bytes
+    /// were read. Holes in sparse files and uninitialized extents read as zeros.
+    pub fn read_file(
+        &mut self,
+        file: &Ext4File,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> DriverResult<usize, D::Error> {
+        let offset = offset as u64;
+        let size = file.inode.size();
+
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = (buffer.len() as u64).min(size - offset) as usize;
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            let logical_block = position / self.block_size;
+            let offset_in_block = position % self.block_size;
+
+            let mut mapping = self.map_inode_block(&file.inode, logical_block)?;
+
+            // Runs that carry on from each other, like blocks that follow each other on the disk
+            // too, can be read all at once
+            while mapping.length() * self.block_size - offset_in_block < (len - done) as u64 {
+                let next = self.map_inode_block(&file.inode, logical_block + mapping.length())?;
+
+                match mapping.join(next) {
+                    Some(joined) => mapping = joined,
+                    None => break,
+                }
+            }
+
+            let run = mapping.length() * self.block_size - offset_in_block;
+            let end = done + run.min((len - done) as u64) as usize;
+            let chunk = &mut buffer[done..end];
+
+            match mapping {
+                BlockMapping::Mapped { start, .. } => {
+                    let last = start + (offset_in_block + chunk.len() as u64 - 1) / self.block_size;
+                    self.check_block(last)?;
+                    self.block_device
+                        .read(start * self.block_size + offset_in_block, chunk)
+                        .map_err(DriverError::Device)?;
+                }
+                BlockMapping::Uninitialized { .. } | BlockMapping::Hole { .. } => chunk.fill(0),
+            }
+
+            done += chunk.len();
+        }
+
+        Ok(len)
+    }
+
+    /// Finds where a block of a file is on the volume, along with the blocks after it that
+    /// carry on the same way
+    pub fn map_block(
+        &mut self,
+        file: &Ext4File,
+        logical_block: u64,
+    ) -> DriverResult<BlockMapping, D::Error> {
+        self.map_inode_block(&file.inode, logical_block)
+    }
+
+    /// Reads an inode from its group's inode table
+    pub fn read_inode(&mut self, inode_number: u32) -> DriverResult<Inode, D::Error> {
+        if inode_number == 0 || inode_number > self.superblock.inodes_count() {
+            return Err(DriverError::InodeOutOfRange(inode_number));
+        }
+
+        let inodes_per_group = self.superblock.inodes_per_group();
+        let group = ((inode_number - 1) / inodes_per_group) as usize;
+        let index = ((inode_number - 1) % inodes_per_group) as u64;
+
+        let descriptor = self
+            .group_descriptors
+            .get(group)
+            .ok_or(DriverError::InodeOutOfRange(inode_number))?;
+
+        if descriptor.inodes_uninitialized() {
+            // Nothing has been written to the inode table, so the inode can't be in use
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let offset = descriptor.inode_table_block() * self.block_size
+            + index * self.superblock.inode_size() as u64;
+
+        self.check_block(offset / self.block_size)?;
+
+        let mut buffer = [0u8; INODE_SIZE];
+        self.block_device
+            .read(offset, &mut buffer)
+            .map_err(DriverError::Device)?;
+
+        Ok(Inode::read(&buffer))
+    }
+
+    /// The group descriptor table is in the block after the superblock
+    fn read_group_descriptors(&mut self) -> DriverResult<(), D::Error> {
+        let blocks_per_group = self.superblock.blocks_per_group() as u64;
+        let data_blocks =
+            self.superblock.blocks_count() - self.superblock.first_data_block() as u64;
+        let group_count = data_blocks.div_ceil(blocks_per_group);
+
+        let inodes_per_group = self.superblock.inodes_per_group() as u64;
+
+        if group_count * inodes_per_group < self.superblock.inodes_count() as u64 {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        let descriptor_size = if self
+            .superblock
+            .incompatible_features()
+            .contains(IncompatibleFeatures::IS_64BIT)
+        {
+            let size = self.superblock.group_descriptor_size() as usize;
+
+            if size < GROUP_DESCRIPTOR_SIZE_64BIT || !size.is_power_of_two() {
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            size
+        } else {
+            GROUP_DESCRIPTOR_SIZE
+        };
+
+        let table_block = self.superblock.first_data_block() as u64 + 1;
+        let table_size = group_count * descriptor_size as u64;
+
+        self.check_block(table_block + (table_size - 1) / self.block_size)?;
+
+        let mut table = vec![0u8; table_size as usize];
+        self.block_device
+            .read(table_block * self.block_size, &mut table)
+            .map_err(DriverError::Device)?;
+
+        self.group_descriptors = table
+            .chunks_exact(descriptor_size)
+            .map(GroupDescriptor::read)
+            .collect();
+
+        Ok(())
+    }
+
+    /// Follows a path from the root directory and returns the inode at its end
+    fn lookup(&mut self, path: &str) -> DriverResult<(u32, Inode), D::Error> {
+        let mut inode_number = ROOT_INODE;
+        let mut inode = self.read_inode(inode_number)?;
+
+        // `.` and `..` are real entries in every directory, so they don't need special handling
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            if !inode.mode().is_directory() {
+                return Err(DriverError::PathNotFound);
+            }
+
+            inode_number = self
+                .find_entry(inode, segment.as_bytes())?
+                .ok_or(DriverError::PathNotFound)?;
+            inode = self.read_inode(inode_number)?;
+        }
+
+        Ok((inode_number, inode))
+    }
+
+    /// Looks a name up in a directory, names are compared byte for byte
+    fn find_entry(&mut self, directory: Inode, name: &[u8]) -> DriverResult<Option<u32>, D::Error> {
+        let mut entries = RawEntryIterator::new(directory);
+
+        while let Some((entry, offset)) = entries.next_entry(self)? {
+            let name_start = offset + DIRECTORY_ENTRY_HEADER_SIZE;
+            let entry_name = &self.data_buffer.inner[name_start..name_start + entry.name_length()];
+
+            if entry_name == name {
+                return Ok(Some(entry.inode()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn map_inode_block(
+        &mut self,
+        inode: &Inode,
+        logical_block: u64,
+    ) -> DriverResult<BlockMapping, D::Error> {
+        if inode.flags().extents() {
+            return self.map_extent_block(inode, logical_block);
+        }
+
+        // Block maps describe each block on its own
+        Ok(match self.map_block_map_block(inode, logical_block)? {
+            Some(start) => BlockMapping::Mapped { start, length: 1 },
+            None => BlockMapping::Hole { length: 1 },
+        })
+    }
+
+    /// Walks the extent tree from its root in the inode down to the leaf that covers the block
+    fn map_extent_block(
+        &mut self,
+        inode: &Inode,
+        logical_block: u64,
+    ) -> DriverResult<BlockMapping, D::Error> {
+        if logical_block >= EXTENT_BLOCK_LIMIT {
+            return Ok(BlockMapping::Hole { length: 1 });
+        }
+
+        let logical_block = logical_block as u32;
+        let root = inode.blocks().to_bytes();
+        let mut step = Self::search_extent_node(&root, logical_block, None, EXTENT_BLOCK_LIMIT)?;
+
+        // Every node is one level less deep than the one above it, so this ends at a leaf
+        loop {
+            match step {
+                ExtentStep::Found(mapping) => return Ok(mapping),
+                ExtentStep::Child { block, depth, end } => {
+                    self.load_map_block(block)?;
+                    step = Self::search_extent_node(
+                        &self.map_buffer.inner,
+                        logical_block,
+                        Some(depth),
+                        end,
+                    )?;
+                }
+            }
+        }
+    }
+
+    /// Looks a block up in one node of an extent tree, which covers the blocks before `end`
+    fn search_extent_node(
+        buffer: &[u8],
+        logical_block: u32,
+        depth: Option<u16>,
+        end: u64,
+    ) -> DriverResult<ExtentStep, D::Error> {
+        let node = ExtentNode::read(buffer)?;
+
+        if depth.is_some_and(|depth| depth != node.depth()) {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        // A hole lasts until the next entry, or the end of what the node covers
+        let hole = |next: Option<u32>| {
+            let hole_end = next.map_or(end, |next| end.min(next as u64));
+
+            BlockMapping::Hole {
+                length: hole_end.saturating_sub(logical_block as u64).max(1),
+            }
+        };
+
+        if node.is_leaf() {
+            let mapping = match node.find_extent(logical_block) {
+                ExtentLookup::Extent(extent) => {
+                    let skipped = (logical_block - extent.first_block()) as u64;
+                    let start = extent.start_block() + skipped;
+                    let length = extent.length() as u64 - skipped;
+
+                    if extent.is_uninitialized() {
+                        BlockMapping::Uninitialized { start, length }
+                    } else {
+                        BlockMapping::Mapped { start, length }
+                    }
+                }
+                ExtentLookup::Hole { next } => hole(next),
+            };
+
+            return Ok(ExtentStep::Found(mapping));
+        }
+
+        Ok(match node.find_index(logical_block) {
+            Some((index, next)) => ExtentStep::Child {
+                block: index.leaf_block(),
+                depth: node.depth() - 1,
+                end: next.map_or(end, |next| end.min(next as u64)),
+            },
+            None => ExtentStep::Found(hole(
+                (node.entries() > 0).then(|| node.index(0).first_block()),
+            )),
+        })
+    }
+
+    /// The block on the volume that holds a block of a file that uses a block map, or None if
+    /// the file has a hole there
+    fn map_block_map_block(
+        &mut self,
+        inode: &Inode,
+        logical_block: u64,
+    ) -> DriverResult<Option<u64>, D::Error> {
+        let blocks = inode.blocks();
+
+        if logical_block < DIRECT_BLOCKS {
+            let block = u32::from(blocks.blocks()[logical_block as usize]);
+
+            return Ok((block != 0).then_some(block as u64));
+        }
+
+        // Each indirect block holds this many block numbers
+        let per_block = self.block_size / 4;
+        let mut index = logical_block - DIRECT_BLOCKS;
+
+        let (mut block, levels) = if index < per_block {
+            (blocks.indirect_block(), 1)
+        } else if index - per_block < per_block * per_block {
+            index -= per_block;
+            (blocks.doubly_indirect_block(), 2)
+        } else if index - per_block - per_block * per_block < per_block * per_block * per_block {
+            index -= per_block + per_block * per_block;
+            (blocks.triply_indirect_block(), 3)
+        } else {
+            // The size says the file is longer than a block map can describe
+            return Err(DriverError::FileSystemInvalid);
+        };
+
+        for level in (0..levels).rev() {
+            let indirect = u32::from(block) as u64;
+
+            if indirect == 0 {
+                return Ok(None);
+            }
+
+            let slot = (index / per_block.pow(level)) % per_block;
+            block = self.read_block_number(indirect, slot)?.into();
+        }
+
+        let block = u32::from(block);
+
+        Ok((block != 0).then_some(block as u64))
+    }
+
+    /// Reads one of the block numbers in an indirect block
+    fn read_block_number(&mut self, indirect: u64, slot: u64) -> DriverResult<u32, D::Error> {
+        self.load_map_block(indirect)?;
+
+        let offset = slot as usize * 4;
+
+        Ok(u32::from_le_bytes(
+            self.map_buffer.inner[offset..offset + 4]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+
+    /// Loads an indirect block or extent tree node into the map buffer
+    fn load_map_block(&mut self, block: u64) -> DriverResult<(), D::Error> {
+        if self.map_buffer.location != Some(block) {
+            self.check_block(block)?;
+            self.block_device
+                .read(block * self.block_size, &mut self.map_buffer.inner)
+                .map_err(DriverError::Device)?;
+            self.map_buffer.location = Some(block);
+        }
+
+        Ok(())
+    }
+
+    /// Loads a block of a file into the data buffer
+    fn read_file_block(&mut self, inode: &Inode, logical_block: u64) -> DriverResult<(), D::Error> {
+        // Directories don't have holes or uninitialized blocks
+        let BlockMapping::Mapped { start: block, .. } =
+            self.map_inode_block(inode, logical_block)?
+        else {
+            return Err(DriverError::FileSystemInvalid);
+        };
+
+        if self.data_buffer.location != Some(block) {
+            self.check_block(block)?;
+            self.block_device
+                .read(block * self.block_size, &mut self.data_buffer.inner)
+                .map_err(DriverError::Device)?;
+            self.data_buffer.location = Some(block);
+        }
+
+        Ok(())
+    }
+
+    fn check_block(&self, block: u64) -> DriverResult<(), D::Error> {
+        if block < self.superblock.blocks_count() {
+            Ok(())
+        } else {
+            Err(DriverError::BlockOutOfRange(block))
+        }
+    }
+}
+
+/// Goes through the entries of a linear directory, or the leaves of a hash tree directory,
+/// which look the same. Unused entries are skipped.
+struct RawEntryIterator {
+    directory: Inode,
+    /// Where the next entry starts in the directory
+    position: u64,
+}
+
+impl RawEntryIterator {
+    fn new(directory: Inode) -> Self {
+        Self {
+            directory,
+            position: 0,
+        }
+    }
+
+    /// The next entry that is in use and where it is in the driver's data buffer, which holds
+    /// its block afterwards
+    fn next_entry<D: BlockDevice>(
+        &mut self,
+        driver: &mut Ext4Driver<D>,
+    ) -> DriverResult<Option<(DirectoryEntry, usize)>, D::Error> {
+        let block_size = driver.block_size;
+
+        while self.position < self.directory.size() {
+            let offset = (self.position % block_size) as usize;
+            let space = block_size as usize - offset;
+
+            if space < DIRECTORY_ENTRY_HEADER_SIZE {
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            driver.read_file_block(&self.directory, self.position / block_size)?;
+
+            let entry = DirectoryEntry::read(&driver.data_buffer.inner[offset..]);
+
+            // Entries can't cross into the next block
+            if !entry.is_valid(space) {
+                return Err(DriverError::FileSystemInvalid);
+            }
+
+            self.position += entry.record_length() as u64;
+
+            if entry.inode() != 0 {
+                return Ok(Some((entry, offset)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+struct DirectoryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    driver: &'a mut Ext4Driver<D>,
+    entries: RawEntryIterator,
+    done: bool,
+}
+
+impl<'a, D> DirectoryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    fn new(driver: &'a mut Ext4Driver<D>, directory: Inode) -> Self {
+        Self {
+            driver,
+            entries: RawEntryIterator::new(directory),
+            done: false,
+        }
+    }
+
+    fn next_entry(&mut self) -> DriverResult<Option<Ext4DirectoryEntry>, D::Error> {
+        let Some((entry, offset)) = self.entries.next_entry(self.driver)? else {
+            return Ok(None);
+        };
+
+        let name_start = offset + DIRECTORY_ENTRY_HEADER_SIZE;
+        let name = String::from_utf8_lossy(
+            &self.driver.data_buffer.inner[name_start..name_start + entry.name_length()],
+        )
+        .into_owned();
+
+        // Without the filetype feature the type is only in the inode
+        let file_type = if self
+            .driver
+            .superblock
+            .incompatible_features()
+            .contains(IncompatibleFeatures::FILE_TYPE)
+        {
+            entry.file_type()
+        } else {
+            self.driver.read_inode(entry.inode())?.mode().file_type()
+        };
+
+        Ok(Some(Ext4DirectoryEntry {
+            name,
+            inode_number: entry.inode(),
+            file_type,
+        }))
+    }
+}
+
+impl<'a, D> Iterator for DirectoryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    type Item = DriverResult<Ext4DirectoryEntry, D::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_device::memory::MemoryBlockDevice;
+    use ext4_core::extent::{EXTENT_ENTRY_SIZE, EXTENT_MAGIC};
+
+    use super::*;
+
+    type Driver = Ext4Driver<MemoryBlockDevice<Vec<u8>>>;
+
+    /// A node with room for four entries, like the root in an inode, whose entries are
+    /// (first logical block, length or 0 for indexes, block on the volume)
+    fn node(depth: u16, entries: &[(u32, u16, u32)]) -> [u8; 60] {
+        let mut buffer = [0u8; 60];
+
+        buffer[0x0..0x2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        buffer[0x2..0x4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        buffer[0x4..0x6].copy_from_slice(&4u16.to_le_bytes());
+        buffer[0x6..0x8].copy_from_slice(&depth.to_le_bytes());
+
+        for (i, &(first_block, length, block)) in entries.iter().enumerate() {
+            let entry = &mut buffer[(i + 1) * EXTENT_ENTRY_SIZE..(i + 2) * EXTENT_ENTRY_SIZE];
+
+            entry[0x0..0x4].copy_from_slice(&first_block.to_le_bytes());
+
+            if depth == 0 {
+                entry[0x4..0x6].copy_from_slice(&length.to_le_bytes());
+                entry[0x8..0xC].copy_from_slice(&block.to_le_bytes());
+            } else {
+                entry[0x4..0x8].copy_from_slice(&block.to_le_bytes());
+            }
+        }
+
+        buffer
+    }
+
+    fn search(
+        buffer: &[u8],
+        logical_block: u32,
+        depth: Option<u16>,
+        end: u64,
+    ) -> DriverResult<ExtentStep, block_device::Error<core::convert::Infallible>> {
+        Driver::search_extent_node(buffer, logical_block, depth, end)
+    }
+
+    fn mapping(
+        step: DriverResult<ExtentStep, block_device::Error<core::convert::Infallible>>,
+    ) -> BlockMapping {
+        match step {
+            Ok(ExtentStep::Found(mapping)) => mapping,
+            Ok(ExtentStep::Child { block, .. }) => panic!("Went down to block {block}"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn maps_blocks_in_leaves() {
+        let leaf = node(0, &[(0, 4, 1000), (10, 32768 + 6, 2000)]);
+
+        assert_eq!(
+            mapping(search(&leaf, 1, None, EXTENT_BLOCK_LIMIT)),
+            BlockMapping::Mapped {
+                start: 1001,
+                length: 3
+            }
+        );
+        assert_eq!(
+            mapping(search(&leaf, 12, None, EXTENT_BLOCK_LIMIT)),
+            BlockMapping::Uninitialized {
+                start: 2002,
+                length: 4
+            }
+        );
+    }
+
+    #[test]
+    fn maps_holes_up_to_the_next_extent() {
+        let leaf = node(0, &[(0, 4, 1000), (10, 2, 2000)]);
+
+        assert_eq!(
+            mapping(search(&leaf, 4, None, EXTENT_BLOCK_LIMIT)),
+            BlockMapping::Hole { length: 6 }
+        );
+        // A leaf only covers the blocks before the next index entry above it
+        assert_eq!(
+            mapping(search(&leaf, 12, Some(0), 20)),
+            BlockMapping::Hole { length: 8 }
+        );
+    }
+
+    #[test]
+    fn goes_down_to_the_node_that_covers_a_block() {
+        let root = node(1, &[(0, 0, 500), (100, 0, 600)]);
+
+        match search(&root, 50, None, EXTENT_BLOCK_LIMIT) {
+            Ok(ExtentStep::Child { block, depth, end }) => {
+                assert_eq!((block, depth, end), (500, 0, 100));
+            }
+            _ => panic!("Didn't go down to the first leaf"),
+        }
+
+        // Blocks before the first index entry are a hole
+        let root = node(1, &[(10, 0, 500)]);
+        assert_eq!(
+            mapping(search(&root, 5, None, EXTENT_BLOCK_LIMIT)),
+            BlockMapping::Hole { length: 5 }
+        );
+    }
+
+    #[test]
+    fn rejects_nodes_at_the_wrong_depth() {
+        let leaf = node(0, &[(0, 4, 1000)]);
+
+        assert!(matches!(
+            search(&leaf, 0, Some(1), EXTENT_BLOCK_LIMIT),
+            Err(DriverError::FileSystemInvalid)
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+//! Reads volumes that `mke2fs` fills from a directory, so e2fsprogs has to be installed
+
+use std::{
+    fs,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use block_device::memory::MemoryBlockDevice;
+use ext4_core::directory::FileType;
+use ext4_driver::{DriverError, Ext4Driver};
+
+type Driver = Ext4Driver<MemoryBlockDevice<Vec<u8>>>;
+
+/// Enough entries that the directory takes several blocks, which `e2fsck -D` then indexes
+const MANY_FILES: usize = 300;
+
+fn contents(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+/// A directory in the temporary directory, which is removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        // Tests run at the same time, and each makes its own volumes
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+
+        let path =
+            std::env::temp_dir().join(format!("ext4-driver-{}-{count}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn populate(root: &Path) {
+    fs::write(root.join("hello.txt"), b"Hello, world!\n").unwrap();
+    // Past the direct blocks and the first indirect block of a block map with 1 KiB blocks
+    fs::write(root.join("big.bin"), contents(1, 300 * 1024 + 123)).unwrap();
+
+    fs::create_dir_all(root.join("dir/nested")).unwrap();
+    fs::write(root.join("dir/nested/file.txt"), b"nested").unwrap();
+    fs::set_permissions(
+        root.join("dir/nested/file.txt"),
+        fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+
+    fs::create_dir(root.join("many")).unwrap();
+    for i in 0..MANY_FILES {
+        fs::write(
+            root.join(format!("many/file-with-a-long-name-{i:04}")),
+            [i as u8],
+        )
+        .unwrap();
+    }
+
+    symlink("hello.txt", root.join("link")).unwrap();
+}
+
+/// Makes a volume with `mke2fs` and the given options, with the files from `populate`
+fn volume(name: &str, options: &[&str]) -> Driver {
+    let temp = TempDir::new(name);
+    let root = temp.0.join("root");
+    let image = temp.0.join("image");
+
+    fs::create_dir(&root).unwrap();
+    populate(&root);
+
+    let output = Command::new("mke2fs")
+        .args(["-q", "-F", "-d"])
+        .arg(&root)
+        .args(options)
+        .arg(&image)
+        .arg("4M")
+        .output()
+        .expect("mke2fs is not installed");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Index the directories that are long enough for it
+    let status = Command::new("e2fsck")
+        .args(["-f", "-y", "-D"])
+        .arg(&image)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("e2fsck is not installed");
+    // 1 means that the file system was changed, which optimising the directories does
+    assert!(status.code().is_some_and(|code| code <= 1));
+
+    let device = MemoryBlockDevice::new(fs::read(&image).unwrap(), 512).unwrap();
+
+    Ext4Driver::new(device).unwrap()
+}
+
+fn volumes() -> Vec<(&'static str, Driver)> {
+    vec![
+        ("ext4-1k", volume("ext4-1k", &["-t", "ext4", "-b", "1024"])),
+        ("ext4-4k", volume("ext4-4k", &["-t", "ext4", "-b", "4096"])),
+        ("ext2-1k", volume("ext2-1k", &["-t", "ext2", "-b", "1024"])),
+    ]
+}
+
+fn read_all(driver: &mut Driver, path: &str) -> Vec<u8> {
+    let file = driver.open(path).unwrap();
+    let mut buffer = vec![0u8; file.size() as usize];
+
+    assert_eq!(
+        driver.read_file(&file, 0, &mut buffer).unwrap(),
+        buffer.len()
+    );
+
+    buffer
+}
+
+fn names(driver: &mut Driver, path: &str) -> Vec<String> {
+    let directory = driver.open_dir(path).unwrap();
+    let mut names: Vec<String> = driver
+        .read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().name().to_string())
+        .collect();
+
+    names.sort();
+    names
+}
+
+#[test]
+fn reads_directories() {
+    for (name, mut driver) in volumes() {
+        let root = names(&mut driver, "/");
+        for expected in [".", "..", "big.bin", "dir", "hello.txt", "link", "many"] {
+            assert!(
+                root.iter().any(|n| n == expected),
+                "{name}: {expected} in {root:?}"
+            );
+        }
+
+        assert_eq!(names(&mut driver, "/dir"), [".", "..", "nested"], "{name}");
+
+        let many = names(&mut driver, "/many");
+        assert_eq!(many.len(), MANY_FILES + 2, "{name}");
+
+        let directory = driver.open_dir("/dir").unwrap();
+        let nested = driver
+            .read_dir(directory)
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.name() == "nested")
+            .unwrap();
+        assert!(nested.is_directory(), "{name}");
+        assert_eq!(nested.file_type(), FileType::Directory, "{name}");
+    }
+}
+
+#[test]
+fn reads_files() {
+    for (name, mut driver) in volumes() {
+        assert_eq!(
+            read_all(&mut driver, "/hello.txt"),
+            b"Hello, world!\n",
+            "{name}"
+        );
+        assert_eq!(
+            read_all(&mut driver, "/dir/nested/file.txt"),
+            b"nested",
+            "{name}"
+        );
+
+        let big = contents(1, 300 * 1024 + 123);
+        assert!(read_all(&mut driver, "/big.bin") == big, "{name}");
+
+        // A read that starts in the middle of a block and crosses several
+        let file = driver.open("/big.bin").unwrap();
+        let mut buffer = vec![0u8; 10000];
+        driver
+            .read_file(&file, 200 * 1024 + 5, &mut buffer)
+            .unwrap();
+        assert!(
+            buffer[..] == big[200 * 1024 + 5..200 * 1024 + 10005],
+            "{name}"
+        );
+
+        // Reads stop at the end of the file
+        assert_eq!(
+            driver.read_file(&file, big.len() - 3, &mut buffer).unwrap(),
+            3
+        );
+        assert_eq!(driver.read_file(&file, big.len(), &mut buffer).unwrap(), 0);
+
+        // Lookups in an indexed directory
+        for i in [0, MANY_FILES / 2, MANY_FILES - 1] {
+            let path = format!("/many/file-with-a-long-name-{i:04}");
+            assert_eq!(read_all(&mut driver, &path), [i as u8], "{name}: {path}");
+        }
+    }
+}
+
+#[test]
+fn reads_metadata() {
+    for (name, mut driver) in volumes() {
+        let file = driver.metadata("/dir/nested/file.txt").unwrap();
+        assert!(file.is_file(), "{name}");
+        assert_eq!(file.size(), 6, "{name}");
+        assert_eq!(file.permissions() & 0o777, 0o640, "{name}");
+        assert_eq!(file.links_count(), 1, "{name}");
+
+        let directory = driver.metadata("/dir").unwrap();
+        assert!(directory.is_directory(), "{name}");
+        // ., the entry in / and the .. of nested
+        assert_eq!(directory.links_count(), 3, "{name}");
+
+        let link = driver.metadata("/link").unwrap();
+        assert!(link.is_symbolic_link(), "{name}");
+
+        assert_eq!(
+            driver.open("/hello.txt").unwrap().metadata().inode_number(),
+            driver.metadata("/hello.txt").unwrap().inode_number(),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn reports_path_errors() {
+    for (name, mut driver) in volumes() {
+        assert!(
+            matches!(driver.open("/dir"), Err(DriverError::IsADirectory)),
+            "{name}"
+        );
+        assert!(
+            matches!(driver.open("/link"), Err(DriverError::IsNotARegularFile)),
+            "{name}"
+        );
+        assert!(
+            matches!(
+                driver.open_dir("/hello.txt"),
+                Err(DriverError::IsNotADirectory)
+            ),
+            "{name}"
+        );
+        assert!(
+            matches!(driver.open("/missing"), Err(DriverError::PathNotFound)),
+            "{name}"
+        );
+        assert!(
+            matches!(
+                driver.open("/hello.txt/file"),
+                Err(DriverError::PathNotFound)
+            ),
+            "{name}"
+        );
+        assert!(
+            matches!(driver.metadata(""), Err(DriverError::PathNotFound)),
+            "{name}"
+        );
+    }
+}
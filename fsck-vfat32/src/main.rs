@@ -0,0 +1,82 @@
+use block_device::impls::FileBlockDevice;
+use clap::Parser;
+use std::{fs::OpenOptions, path::PathBuf, process::ExitCode};
+
+use mock_vfat32_driver::VFAT32Driver;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The exit codes other fsck programs use
+const ERRORS_CORRECTED: u8 = 1;
+const ERRORS_UNCORRECTED: u8 = 4;
+const OPERATIONAL_ERROR: u8 = 8;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The image to check
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// Repair the problems that can be repaired automatically
+    #[arg(short = 'a', long)]
+    repair: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    println!("fsck-vfat32 {VERSION}");
+
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(args.repair)
+        .open(&args.file)
+    {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Error opening file: {e}");
+            return ExitCode::from(OPERATIONAL_ERROR);
+        }
+    };
+
+    let mut driver = match VFAT32Driver::new(FileBlockDevice::new(file)) {
+        Ok(driver) => driver,
+        Err(e) => {
+            eprintln!("Error opening {}: {e}", args.file.display());
+            return ExitCode::from(OPERATIONAL_ERROR);
+        }
+    };
+
+    let report = match driver.fsck(args.repair) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error checking {}: {e}", args.file.display());
+            return ExitCode::from(OPERATIONAL_ERROR);
+        }
+    };
+
+    for finding in &report.findings {
+        if finding.repaired {
+            println!("{} (repaired)", finding.problem);
+        } else {
+            println!("{}", finding.problem);
+        }
+    }
+
+    println!(
+        "{}: {} files, {} directories, {}/{} clusters free",
+        args.file.display(),
+        report.files,
+        report.directories,
+        report.free_clusters,
+        report.total_clusters
+    );
+
+    if report.is_clean() {
+        ExitCode::SUCCESS
+    } else if report.all_repaired() {
+        ExitCode::from(ERRORS_CORRECTED)
+    } else {
+        ExitCode::from(ERRORS_UNCORRECTED)
+    }
+}
@@ -1,4 +1,5 @@
 use bin_tools::{read_into_array, read_u16_le, read_u64_le};
+use block_device::{partition::PartitionBlockDevice, BlockDevice};
 
 use super::guids::GUID;
 
@@ -54,6 +55,14 @@ impl PartitionEntry {
         self.attribute_flags
     }
 
+    /// Opens this partition on the disk it was read from, so it can be used as its own device
+    pub fn block_device<D: BlockDevice>(
+        &self,
+        disk: D,
+    ) -> Result<PartitionBlockDevice<D>, block_device::Error<D::Error>> {
+        PartitionBlockDevice::new(disk, self.first_lba, self.last_lba)
+    }
+
     pub fn name_str(&self) -> String {
         let mut name_end = 0;
 
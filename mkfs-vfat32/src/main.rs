@@ -0,0 +1,108 @@
+use block_device::impls::FileBlockDevice;
+use clap::Parser;
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    process::ExitCode,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use vfat32_core::format::{format, volume_label, FormatOptions};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The image to format, which must already have the size the filesystem should have
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+    /// Volume label, at most 11 characters
+    #[arg(short = 'n', long, value_name = "LABEL")]
+    volume_name: Option<String>,
+    /// Sectors per cluster, picked from the size of the image if not given
+    #[arg(short, long, value_name = "N")]
+    sectors_per_cluster: Option<u8>,
+    /// Number of reserved sectors at the start of the volume
+    #[arg(short = 'R', long, value_name = "N", default_value_t = 32)]
+    reserved_sectors: u16,
+    /// Number of copies of the FAT
+    #[arg(short = 'f', long, value_name = "N", default_value_t = 2)]
+    fats: u8,
+    /// Volume serial number in hexadecimal, based on the current time if not given
+    #[arg(short = 'i', long, value_name = "ID", value_parser = parse_hex)]
+    volume_id: Option<u32>,
+    /// Logical sector size in bytes
+    #[arg(short = 'S', long, value_name = "BYTES", default_value_t = 512)]
+    sector_size: u64,
+}
+
+fn parse_hex(value: &str) -> Result<u32, String> {
+    let digits = value.trim_start_matches("0x");
+
+    u32::from_str_radix(digits, 16).map_err(|e| format!("invalid volume ID {value}: {e}"))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    println!("mkfs-vfat32 {VERSION}");
+
+    let volume_label = match args.volume_name.as_deref().map(volume_label) {
+        Some(Some(label)) => label,
+        Some(None) => {
+            eprintln!("Invalid volume label, it can have at most 11 characters, which must be valid in short file names");
+            return ExitCode::FAILURE;
+        }
+        None => FormatOptions::default().volume_label,
+    };
+
+    let volume_serial_number = args.volume_id.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        (now.as_secs() as u32) ^ now.subsec_nanos()
+    });
+
+    let options = FormatOptions {
+        volume_label,
+        sectors_per_cluster: args.sectors_per_cluster.unwrap_or(0),
+        reserved_sectors: args.reserved_sectors,
+        num_file_allocation_tables: args.fats,
+        volume_serial_number,
+    };
+
+    let file = match OpenOptions::new().read(true).write(true).open(&args.file) {
+        Ok(opened) => opened,
+        Err(e) => {
+            eprintln!("Error opening file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut device = match FileBlockDevice::with_block_size(file, args.sector_size) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Error opening file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match format(&mut device, &options) {
+        Ok(boot_record) => {
+            println!(
+                "Created FAT32 filesystem with {} clusters of {} bytes, volume ID {:08X}",
+                boot_record.cluster_count(),
+                boot_record.sectors_per_cluster() * boot_record.bytes_per_sector() as u64,
+                boot_record.volume_serial_number()
+            );
+
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error formatting {}: {e}", args.file.display());
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+    process::Command,
+};
+
+const MKFS: &str = env!("CARGO_BIN_EXE_mkfs-vfat32");
+
+/// A file of `len` bytes in the temporary directory, which is removed when dropped
+struct Image(PathBuf);
+
+impl Image {
+    fn new(name: &str, len: u64) -> Self {
+        let path = std::env::temp_dir().join(format!("mkfs-vfat32-{}-{name}", std::process::id()));
+        File::create(&path).unwrap().set_len(len).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn formats_an_image() {
+    let image = Image::new("ok", 64 * 1024 * 1024);
+
+    let status = Command::new(MKFS).arg(&image.0).status().unwrap();
+
+    assert!(status.success());
+}
+
+#[test]
+fn fails_when_formatting_fails() {
+    let too_small = Image::new("small", 8 * 1024);
+    let status = Command::new(MKFS).arg(&too_small.0).status().unwrap();
+    assert!(!status.success());
+
+    let missing = std::env::temp_dir().join("mkfs-vfat32-does-not-exist");
+    let status = Command::new(MKFS).arg(&missing).status().unwrap();
+    assert!(!status.success());
+
+    let image = Image::new("label", 64 * 1024 * 1024);
+    let status = Command::new(MKFS)
+        .args(["-n", "A LABEL THAT IS TOO LONG"])
+        .arg(&image.0)
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+#[ignore = "needs fsck.fat from dosfstools"]
+fn output_passes_fsck_fat() {
+    for (size, extra_args) in [
+        (64 * 1024 * 1024, &[][..]),
+        (300 * 1024 * 1024, &["-s", "8", "-n", "DATA"][..]),
+        (64 * 1024 * 1024, &["-f", "1", "-S", "4096"][..]),
+    ] {
+        let image = Image::new("fsck", size);
+
+        let status = Command::new(MKFS)
+            .args(extra_args)
+            .arg(&image.0)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = Command::new("fsck.fat")
+            .args(["-n", "-v"])
+            .arg(&image.0)
+            .output()
+            .expect("fsck.fat is not installed");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+}
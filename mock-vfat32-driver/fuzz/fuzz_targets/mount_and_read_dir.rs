@@ -0,0 +1,62 @@
+#![no_main]
+
+//! Mounts arbitrary bytes as a volume and reads everything in it. Reading a damaged volume may
+//! fail, but it has to fail with an error and not a panic or a hang.
+
+use block_device::memory::MemoryBlockDevice;
+use libfuzzer_sys::fuzz_target;
+use mock_vfat32_driver::VFAT32Driver;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// How deep into the directory tree to go, directories can contain themselves on a damaged volume
+const MAX_DEPTH: usize = 8;
+
+/// How much of each file to read
+const MAX_READ: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut image = data.to_vec();
+    image.resize(image.len().next_multiple_of(BLOCK_SIZE as usize), 0);
+
+    let Ok(block_device) = MemoryBlockDevice::new(image, BLOCK_SIZE) else {
+        return;
+    };
+
+    let Ok(mut driver) = VFAT32Driver::new(block_device) else {
+        return;
+    };
+
+    read_tree(&mut driver, String::from("/"), 0);
+});
+
+fn read_tree(driver: &mut VFAT32Driver<MemoryBlockDevice<Vec<u8>>>, path: String, depth: usize) {
+    let Ok(directory) = driver.open_dir(&path) else {
+        return;
+    };
+
+    let Ok(entries) = driver.read_dir(directory) else {
+        return;
+    };
+
+    let entries: Vec<_> = entries.filter_map(Result::ok).collect();
+
+    for entry in entries {
+        let name = entry.name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let entry_path = format!("{path}{name}");
+
+        if entry.is_directory() {
+            if depth < MAX_DEPTH {
+                read_tree(driver, entry_path + "/", depth + 1);
+            }
+        } else if let Ok(file) = driver.open(&entry_path) {
+            let mut buffer = vec![0; file.size().min(MAX_READ)];
+            let _ = driver.read_file(&file, 0, &mut buffer);
+        }
+    }
+}
@@ -0,0 +1,713 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use block_device::BlockDevice;
+use vfat32_core::{
+    entry::{
+        DirectoryEntry, LongFileNameEntry, RealEntry, DELETED_ENTRY_MARKER, DIRECTORY_ENTRY_SIZE,
+    },
+    fs_info::FS_INFO_UNKNOWN,
+    name::{short_name_chars, short_name_checksum},
+};
+
+use crate::{
+    DriverError, DriverResult, VFAT32Driver, BAD_CLUSTER, END_OF_CHAIN, MAX_DIRECTORY_ENTRIES,
+};
+
+/// An inconsistency that a check of the filesystem found
+#[derive(Debug, Clone)]
+pub enum Problem {
+    /// A copy of the FAT has sectors that differ from the first FAT
+    FatCopyDiffers { fat: u8, sectors: u32 },
+    /// The chain reaches a cluster outside the data area, or one that is marked free or bad
+    InvalidCluster { path: String, cluster: u32 },
+    /// The chain reaches a cluster that already belongs to another chain, or to itself
+    CrossLinked { path: String, cluster: u32 },
+    /// The file's chain has a different number of clusters than its size needs
+    WrongChainLength {
+        path: String,
+        size: u32,
+        clusters: u32,
+    },
+    /// The long file name entries don't belong to the short entry after them
+    BadLongNameChecksum { path: String },
+    /// Allocated clusters that no file or directory refers to
+    LostChain { start: u32, clusters: u32 },
+    /// The FS info sector's free cluster count doesn't match the FAT
+    WrongFreeCount { recorded: u32, actual: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FatCopyDiffers { fat, sectors } => {
+                write!(f, "FAT {fat} differs from FAT 0 in {sectors} sector(s)")
+            }
+            Self::InvalidCluster { path, cluster } => {
+                write!(f, "{path}: chain contains invalid cluster {cluster}")
+            }
+            Self::CrossLinked { path, cluster } => {
+                write!(f, "{path}: cluster {cluster} is cross-linked")
+            }
+            Self::WrongChainLength {
+                path,
+                size,
+                clusters,
+            } => {
+                write!(
+                    f,
+                    "{path}: {size} bytes, but the chain has {clusters} cluster(s)"
+                )
+            }
+            Self::BadLongNameChecksum { path } => {
+                write!(f, "{path}: long file name has the wrong checksum")
+            }
+            Self::LostChain { start, clusters } => {
+                write!(f, "Lost chain of {clusters} cluster(s) starting at {start}")
+            }
+            Self::WrongFreeCount { recorded, actual } => {
+                write!(
+                    f,
+                    "Free cluster count is {recorded}, but should be {actual}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub problem: Problem,
+    /// Whether the problem has been repaired
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub findings: Vec<Finding>,
+    pub files: u32,
+    pub directories: u32,
+    pub free_clusters: u32,
+    pub total_clusters: u32,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Whether every problem that was found has been repaired
+    pub fn all_repaired(&self) -> bool {
+        self.findings.iter().all(|f| f.repaired)
+    }
+}
+
+/// A real directory entry and where it is on disk
+struct FoundEntry {
+    entry: RealEntry,
+    name: String,
+    path: String,
+    sector: u64,
+    offset: usize,
+}
+
+/// The state of one run over the filesystem
+struct Checker {
+    repair: bool,
+    report: FsckReport,
+    /// Which clusters belong to a file or directory that has been walked
+    used: Vec<bool>,
+}
+
+impl Checker {
+    fn found(&mut self, problem: Problem, repaired: bool) {
+        self.report.findings.push(Finding { problem, repaired });
+    }
+}
+
+impl<D: BlockDevice> VFAT32Driver<D> {
+    /// Checks the filesystem for inconsistencies: FAT copies that differ, cluster chains that
+    /// are invalid, cross-linked or don't match their file's size, long file names with the
+    /// wrong checksum, lost clusters and a wrong free cluster count.
+    ///
+    /// With `repair`, fixes what it can. FATs are copied from the first FAT, bad chains are cut
+    /// short, sizes and chains are trimmed to match each other, orphaned long file name entries
+    /// are deleted, lost clusters are freed and the free cluster count is corrected.
+    pub fn fsck(&mut self, repair: bool) -> DriverResult<FsckReport, D::Error> {
+        let end = self.cluster_end();
+
+        let mut checker = Checker {
+            repair,
+            report: FsckReport {
+                total_clusters: end.saturating_sub(2),
+                ..FsckReport::default()
+            },
+            used: vec![false; end as usize],
+        };
+
+        self.check_fat_copies(&mut checker)?;
+
+        let root = self.boot_record.root_directory_cluster();
+
+        let root_sectors = if root == 0 {
+            // The fixed root directory of FAT12 and FAT16 has no chain
+            let first_sector = self.boot_record.root_directory_sector();
+
+            (first_sector..first_sector + self.boot_record.root_directory_sectors()).collect()
+        } else {
+            match self.check_chain(&mut checker, root, String::from("/"), false)? {
+                Some(clusters) => self.cluster_sectors(&clusters),
+                None => return Err(DriverError::FileSystemInvalid),
+            }
+        };
+
+        checker.report.directories += 1;
+        self.check_directory(&mut checker, root_sectors, "")?;
+
+        self.check_lost_clusters(&mut checker)?;
+        self.check_free_count(&mut checker)?;
+
+        Ok(checker.report)
+    }
+
+    fn check_fat_copies(&mut self, checker: &mut Checker) -> DriverResult<(), D::Error> {
+        let first_fat = self.boot_record.first_fat_sector();
+        let sectors_per_fat = self.boot_record.sectors_per_fat() as u64;
+
+        let mut first = [0u8; 512];
+        let mut copy = [0u8; 512];
+
+        for fat in 1..self.boot_record.num_file_allocation_tables() {
+            let copy_start = first_fat + fat as u64 * sectors_per_fat;
+            let mut sectors = 0;
+
+            for sector in 0..sectors_per_fat {
+                self.block_device
+                    .read_block(first_fat + sector, &mut first)
+                    .map_err(DriverError::Device)?;
+                self.block_device
+                    .read_block(copy_start + sector, &mut copy)
+                    .map_err(DriverError::Device)?;
+
+                if first != copy {
+                    sectors += 1;
+
+                    if checker.repair {
+                        self.block_device
+                            .write_block(copy_start + sector, &first)
+                            .map_err(DriverError::Device)?;
+                    }
+                }
+            }
+
+            if sectors > 0 {
+                checker.found(Problem::FatCopyDiffers { fat, sectors }, checker.repair);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Follows a chain, claiming its clusters. Cuts it off before the first invalid or
+    /// cross-linked cluster when repairing. Returns the clusters, or None if the first cluster
+    /// is already unusable, which only the caller can repair.
+    fn check_chain(
+        &mut self,
+        checker: &mut Checker,
+        start_cluster: u32,
+        path: String,
+        repairable_start: bool,
+    ) -> DriverResult<Option<Vec<u32>>, D::Error> {
+        let end = self.cluster_end();
+        let mut clusters: Vec<u32> = Vec::new();
+        let mut cluster = start_cluster;
+
+        loop {
+            let next = if (2..end).contains(&cluster) {
+                self.read_fat_entry(cluster)?
+            } else {
+                0
+            };
+
+            let problem = if next == 0 || next == BAD_CLUSTER {
+                Some(Problem::InvalidCluster {
+                    path: path.clone(),
+                    cluster,
+                })
+            } else if checker.used[cluster as usize] {
+                Some(Problem::CrossLinked {
+                    path: path.clone(),
+                    cluster,
+                })
+            } else {
+                None
+            };
+
+            if let Some(problem) = problem {
+                let Some(&last) = clusters.last() else {
+                    checker.found(problem, checker.repair && repairable_start);
+                    return Ok(None);
+                };
+
+                if checker.repair {
+                    self.write_fat_entry(last, END_OF_CHAIN)?;
+                }
+
+                checker.found(problem, checker.repair);
+
+                return Ok(Some(clusters));
+            }
+
+            checker.used[cluster as usize] = true;
+            clusters.push(cluster);
+
+            if Self::is_end(next) {
+                return Ok(Some(clusters));
+            }
+
+            cluster = next;
+        }
+    }
+
+    fn check_file(
+        &mut self,
+        checker: &mut Checker,
+        found: FoundEntry,
+    ) -> DriverResult<(), D::Error> {
+        checker.report.files += 1;
+
+        let size = found.entry.file_size();
+        let bytes_per_cluster = self.bytes_per_cluster() as u64;
+        let needed = (size as u64).div_ceil(bytes_per_cluster) as usize;
+
+        let start_cluster = found.entry.start_cluster();
+
+        let clusters = if start_cluster == 0 {
+            Vec::new()
+        } else {
+            match self.check_chain(checker, start_cluster, found.path.clone(), true)? {
+                Some(clusters) => clusters,
+                None => {
+                    // The whole chain is unusable, so the file becomes empty
+                    if checker.repair {
+                        self.update_entry(&found, 0, 0)?;
+                    }
+
+                    return Ok(());
+                }
+            }
+        };
+
+        if clusters.len() == needed {
+            return Ok(());
+        }
+
+        checker.found(
+            Problem::WrongChainLength {
+                path: found.path.clone(),
+                size,
+                clusters: clusters.len() as u32,
+            },
+            checker.repair,
+        );
+
+        if !checker.repair {
+            return Ok(());
+        }
+
+        if clusters.len() > needed {
+            // Free the clusters past the end of the file
+            for &cluster in &clusters[needed..] {
+                self.write_fat_entry(cluster, 0)?;
+                checker.used[cluster as usize] = false;
+            }
+
+            if needed == 0 {
+                self.update_entry(&found, 0, size)?;
+            } else {
+                self.write_fat_entry(clusters[needed - 1], END_OF_CHAIN)?;
+            }
+        } else {
+            // The data past the end of the chain is gone, so the file ends with the chain
+            let size = (clusters.len() as u64 * bytes_per_cluster).min(size as u64) as u32;
+            let start_cluster = if clusters.is_empty() {
+                0
+            } else {
+                start_cluster
+            };
+
+            self.update_entry(&found, start_cluster, size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the entries of a directory whose clusters have been claimed, then its files and
+    /// subdirectories
+    fn check_directory(
+        &mut self,
+        checker: &mut Checker,
+        sectors: Vec<u64>,
+        path: &str,
+    ) -> DriverResult<(), D::Error> {
+        let mut subdirectories = Vec::new();
+
+        for found in self.read_directory(checker, &sectors, path)? {
+            if found.name == "." || found.name == ".." {
+                continue;
+            }
+
+            if found.entry.is_file() {
+                self.check_file(checker, found)?;
+            } else if found.entry.is_dir() {
+                checker.report.directories += 1;
+
+                let start_cluster = found.entry.start_cluster();
+
+                // A directory without its first cluster can't simply be repaired
+                if let Some(clusters) =
+                    self.check_chain(checker, start_cluster, found.path.clone(), false)?
+                {
+                    subdirectories.push((self.cluster_sectors(&clusters), found.path));
+                }
+            }
+        }
+
+        for (sectors, path) in subdirectories {
+            self.check_directory(checker, sectors, &path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the real entries of a directory, with their full paths. Long file names with the
+    /// wrong checksum are reported, and deleted when repairing.
+    fn read_directory(
+        &mut self,
+        checker: &mut Checker,
+        sectors: &[u64],
+        path: &str,
+    ) -> DriverResult<Vec<FoundEntry>, D::Error> {
+        let entries_per_sector = self.sector_size as usize / DIRECTORY_ENTRY_SIZE;
+
+        let mut found = Vec::new();
+        let mut long_entries: Vec<(u64, usize, LongFileNameEntry)> = Vec::new();
+        let mut count = 0;
+
+        for &sector in sectors {
+            for index in 0..entries_per_sector {
+                if count == MAX_DIRECTORY_ENTRIES {
+                    return Ok(found);
+                }
+
+                count += 1;
+
+                self.read_data_sector(sector)?;
+
+                let Some(entry) = self.read_dir_entry_from_buffer(index) else {
+                    return Ok(found);
+                };
+
+                let offset = index * DIRECTORY_ENTRY_SIZE;
+
+                match entry {
+                    DirectoryEntry::LFN(lfn) if lfn.is_deleted() => long_entries.clear(),
+                    DirectoryEntry::LFN(lfn) => long_entries.push((sector, offset, lfn)),
+                    DirectoryEntry::Real(real) if real.is_deleted() => long_entries.clear(),
+                    DirectoryEntry::Real(real) => {
+                        let short_name: String =
+                            short_name_chars(real.name_bytes(), real.entry_case()).collect();
+                        let checksum = short_name_checksum(real.name_bytes());
+
+                        let name = if long_entries.is_empty() {
+                            short_name
+                        } else if long_entries
+                            .iter()
+                            .all(|(_, _, lfn)| lfn.short_name_checksum() == checksum)
+                        {
+                            Self::long_name_string(&long_entries)
+                        } else {
+                            checker.found(
+                                Problem::BadLongNameChecksum {
+                                    path: format!("{path}/{short_name}"),
+                                },
+                                checker.repair,
+                            );
+
+                            if checker.repair {
+                                for &(lfn_sector, lfn_offset, _) in &long_entries {
+                                    self.read_data_sector(lfn_sector)?;
+                                    self.data_buffer.as_slice_mut()[lfn_offset] =
+                                        DELETED_ENTRY_MARKER;
+                                    self.write_data_sector(lfn_sector)?;
+                                }
+                            }
+
+                            short_name
+                        };
+
+                        long_entries.clear();
+
+                        found.push(FoundEntry {
+                            entry: real,
+                            path: format!("{path}/{name}"),
+                            name,
+                            sector,
+                            offset,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    fn check_lost_clusters(&mut self, checker: &mut Checker) -> DriverResult<(), D::Error> {
+        let end = self.cluster_end();
+
+        // Every lost cluster and the entry it has in the FAT
+        let mut lost = HashMap::new();
+
+        for cluster in 2..end {
+            let next = self.read_fat_entry(cluster)?;
+
+            if next != 0 && next != BAD_CLUSTER && !checker.used[cluster as usize] {
+                lost.insert(cluster, next);
+            }
+        }
+
+        if lost.is_empty() {
+            return Ok(());
+        }
+
+        // Chains start at the lost clusters that no other lost cluster points to. Whatever is
+        // left after following those is made of loops, which start anywhere.
+        let pointed_to: HashSet<u32> = lost.values().copied().collect();
+        let mut starts: Vec<u32> = lost
+            .keys()
+            .copied()
+            .filter(|c| !pointed_to.contains(c))
+            .collect();
+        starts.sort_unstable();
+
+        let mut remaining: Vec<u32> = lost.keys().copied().collect();
+        remaining.sort_unstable();
+
+        let mut visited = vec![false; end as usize];
+
+        for start in starts.into_iter().chain(remaining) {
+            if visited[start as usize] {
+                continue;
+            }
+
+            let mut cluster = start;
+            let mut length = 0;
+
+            while let Some(&next) = lost.get(&cluster) {
+                if visited[cluster as usize] {
+                    break;
+                }
+
+                visited[cluster as usize] = true;
+                length += 1;
+
+                if checker.repair {
+                    self.write_fat_entry(cluster, 0)?;
+                }
+
+                cluster = next;
+            }
+
+            checker.found(
+                Problem::LostChain {
+                    start,
+                    clusters: length,
+                },
+                checker.repair,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn check_free_count(&mut self, checker: &mut Checker) -> DriverResult<(), D::Error> {
+        let mut free = 0;
+
+        for cluster in 2..self.cluster_end() {
+            if self.read_fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+
+        checker.report.free_clusters = free;
+
+        // Only FAT32 keeps a count
+        let Some(recorded) = self.fs_info.as_ref().map(|f| f.free_cluster_count()) else {
+            return Ok(());
+        };
+
+        if recorded != free {
+            if recorded != FS_INFO_UNKNOWN {
+                checker.found(
+                    Problem::WrongFreeCount {
+                        recorded,
+                        actual: free,
+                    },
+                    checker.repair,
+                );
+            }
+
+            if checker.repair {
+                if let Some(fs_info) = &mut self.fs_info {
+                    fs_info.set_free_cluster_count(free);
+                }
+                self.fs_info_dirty = true;
+                self.write_fs_info()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cluster_sectors(&self, clusters: &[u32]) -> Vec<u64> {
+        let sectors_per_cluster = self.boot_record.sectors_per_cluster();
+
+        clusters
+            .iter()
+            .flat_map(|&cluster| {
+                let first_sector = self.data_sector_from_cluster(cluster);
+
+                first_sector..first_sector + sectors_per_cluster
+            })
+            .collect()
+    }
+
+    fn update_entry(
+        &mut self,
+        found: &FoundEntry,
+        start_cluster: u32,
+        size: u32,
+    ) -> DriverResult<(), D::Error> {
+        self.read_data_sector(found.sector)?;
+
+        let slice =
+            &mut self.data_buffer.as_slice_mut()[found.offset..found.offset + DIRECTORY_ENTRY_SIZE];
+        let mut entry = RealEntry::read(slice);
+        entry.set_start_cluster(start_cluster);
+        entry.set_file_size(size);
+        entry.write(slice);
+
+        self.write_data_sector(found.sector)
+    }
+
+    /// Puts a long file name back together from its entries, which are stored last part first
+    fn long_name_string(long_entries: &[(u64, usize, LongFileNameEntry)]) -> String {
+        let units = long_entries
+            .iter()
+            .rev()
+            .flat_map(|(_, _, lfn)| lfn.name_units().iter().copied())
+            .take_while(|&unit| unit != 0);
+
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_device::memory::MemoryBlockDevice;
+    use vfat32_core::format::{format, FormatOptions};
+
+    use super::Problem;
+    use crate::VFAT32Driver;
+
+    const SECTOR_SIZE: u64 = 512;
+
+    fn formatted(
+        num_sectors: u64,
+        options: &FormatOptions,
+    ) -> VFAT32Driver<MemoryBlockDevice<Vec<u8>>> {
+        let mut device = MemoryBlockDevice::zeroed(num_sectors, SECTOR_SIZE).unwrap();
+        format(&mut device, options).unwrap();
+
+        VFAT32Driver::new(device).unwrap()
+    }
+
+    #[test]
+    fn formatted_volume_is_clean() {
+        let one_fat = FormatOptions {
+            num_file_allocation_tables: 1,
+            ..FormatOptions::default()
+        };
+        let large_clusters = FormatOptions {
+            sectors_per_cluster: 8,
+            ..FormatOptions::default()
+        };
+
+        for (num_sectors, options) in [
+            (20480, FormatOptions::default()),
+            (81920, FormatOptions::default()),
+            (20480, one_fat),
+            (81920, large_clusters),
+        ] {
+            let mut driver = formatted(num_sectors, &options);
+            let report = driver.fsck(false).unwrap();
+
+            assert!(report.is_clean(), "{:?}", report.findings);
+            assert_eq!(report.files, 0);
+            assert_eq!(report.directories, 1);
+            // Only the root directory's cluster is in use
+            assert_eq!(report.free_clusters, report.total_clusters - 1);
+        }
+    }
+
+    #[test]
+    fn formatted_volume_stays_clean_after_writes() {
+        let mut driver = formatted(20480, &FormatOptions::default());
+
+        driver.mkdir("/Some Directory").unwrap();
+        let mut file = driver.create("/Some Directory/file.bin").unwrap();
+        driver.write_file(&mut file, 0, &[0xA5; 5000]).unwrap();
+
+        let report = driver.fsck(false).unwrap();
+
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.files, 1);
+        assert_eq!(report.directories, 2);
+    }
+
+    #[test]
+    fn reports_entries_with_bad_long_names_by_short_name() {
+        let mut driver = formatted(20480, &FormatOptions::default());
+        driver.create("/Long File Name.txt").unwrap();
+
+        // Break the checksum in the long name entry, which comes before the short one
+        let root_cluster = driver.boot_record.root_directory_cluster();
+        let sector = driver.data_sector_from_cluster(root_cluster);
+        driver.read_data_sector(sector).unwrap();
+        let offset = (0..SECTOR_SIZE as usize)
+            .step_by(32)
+            .find(|&offset| driver.data_buffer.as_slice()[offset + 11] == 0x0F)
+            .unwrap();
+        driver.data_buffer.as_slice_mut()[offset + 13] ^= 0xFF;
+        driver.write_data_sector(sector).unwrap();
+
+        let report = driver.fsck(false).unwrap();
+
+        assert!(
+            matches!(
+                &report.findings[..],
+                [finding] if matches!(
+                    &finding.problem,
+                    Problem::BadLongNameChecksum { path } if path == "/LONGFI~1.TXT"
+                )
+            ),
+            "{:?}",
+            report.findings
+        );
+    }
+}
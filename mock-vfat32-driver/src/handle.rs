@@ -0,0 +1,115 @@
+use block_device::BlockDevice;
+
+use crate::{ClusterCursor, DriverError, DriverResult, VFAT32Driver, VFATFile};
+
+/// Where to seek to, relative to the start or end of the file or the current position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An open file with a position that reads continue from. It keeps the cluster the last read
+/// ended in, so reading a file from start to end follows its cluster chain only once.
+pub struct VFATFileHandle<'a, D>
+where
+    D: BlockDevice,
+{
+    driver: &'a mut VFAT32Driver<D>,
+    file: VFATFile,
+    position: u64,
+    cursor: ClusterCursor,
+}
+
+impl<'a, D: BlockDevice> VFATFileHandle<'a, D> {
+    /// Reads from the current position and moves it past the bytes that were read. Returns 0 at
+    /// the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> DriverResult<usize, D::Error> {
+        if self.position >= self.file.size() as u64 {
+            return Ok(0);
+        }
+
+        let read = self.driver.read_file_at(
+            &self.file,
+            self.position as usize,
+            buffer,
+            &mut self.cursor,
+        )?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+
+    /// Moves the position and returns it. Seeking past the end of the file is allowed, reads
+    /// from there return 0 bytes.
+    pub fn seek(&mut self, position: SeekFrom) -> DriverResult<u64, D::Error> {
+        let (base, offset) = match position {
+            SeekFrom::Start(position) => (position, 0),
+            SeekFrom::End(offset) => (self.file.size() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or(DriverError::InvalidSeek)?;
+
+        Ok(self.position)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn file(&self) -> &VFATFile {
+        &self.file
+    }
+
+    pub fn into_file(self) -> VFATFile {
+        self.file
+    }
+}
+
+impl<D: BlockDevice> VFAT32Driver<D> {
+    /// Opens a file for reading through a handle, starting at position 0
+    pub fn open_handle(&mut self, path: &str) -> DriverResult<VFATFileHandle<'_, D>, D::Error> {
+        let file = self.open(path)?;
+        Ok(self.handle(file))
+    }
+
+    /// Makes a handle for a file that is already open, starting at position 0
+    pub fn handle(&mut self, file: VFATFile) -> VFATFileHandle<'_, D> {
+        VFATFileHandle {
+            driver: self,
+            cursor: ClusterCursor::new(&file),
+            file,
+            position: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> std::io::Read for VFATFileHandle<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        VFATFileHandle::read(self, buf).map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: BlockDevice> std::io::Seek for VFATFileHandle<'_, D> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            std::io::SeekFrom::Start(position) => SeekFrom::Start(position),
+            std::io::SeekFrom::End(offset) => SeekFrom::End(offset),
+            std::io::SeekFrom::Current(offset) => SeekFrom::Current(offset),
+        };
+
+        VFATFileHandle::seek(self, position).map_err(|e| match e {
+            DriverError::InvalidSeek => std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ),
+            e => std::io::Error::other(e.to_string()),
+        })
+    }
+}
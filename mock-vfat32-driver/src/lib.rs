@@ -1,65 +1,234 @@
+pub mod fsck;
+pub mod handle;
+
+use core::fmt::{Debug, Display};
 use core::str;
 
-use bin_tools::read_u32_le;
 use block_device::BlockDevice;
 use vfat32_core::{
-    entry::{DirectoryEntry, RealEntry, DIRECTORY_ENTRY_SIZE},
-    fs_info::FSInfo,
-    record::BootRecord,
+    entry::{
+        Attributes, DirectoryEntry, RealEntry, DELETED_ENTRY_MARKER, DIRECTORY_ENTRY_SIZE,
+        LAST_LONG_ENTRY, LONG_NAME_CHARS_PER_ENTRY,
+    },
+    fs_info::{FSInfo, FS_INFO_UNKNOWN},
+    name::{
+        encode_name, long_names_equal, short_name_chars, short_name_checksum, EncodedName,
+    },
+    record::{BootRecord, FatType, BOOT_SIGNATURE},
+    time::{DosDate, DosDateTime},
 };
 
-pub type DriverResult<T> = Result<T, DriverError>;
+/// Written into the FAT to mark the last cluster of a chain
+const END_OF_CHAIN: u32 = 0x0FFFFFFF;
+
+/// The FAT entry of a cluster that can't be used because the disk is damaged there
+const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+
+/// A directory can't have more entries than this
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+
+/// Following `..` entries further than this means the directory tree has a loop
+const MAX_DIRECTORY_DEPTH: usize = 4096;
+
+pub type DriverResult<T, E> = Result<T, DriverError<E>>;
 
 #[derive(Debug, Clone, Copy)]
-pub enum DriverError {
-    DiskError,
+pub enum DriverError<E> {
+    /// The block device returned an error
+    Device(E),
+    /// The boot sector doesn't end with 0xAA55
+    BadBootSignature,
+    /// The FS info sector's signatures are wrong
+    BadFSInfo,
+    /// A cluster chain or directory entry points at a cluster that isn't on the volume
+    ClusterOutOfRange(u32),
+    /// A cluster chain goes back to a cluster it already went through
+    ClusterChainLoop(u32),
+    /// A cluster chain goes through a cluster that is marked as free
+    FreeClusterInChain(u32),
+    /// A cluster chain goes through a cluster that is marked as bad
+    BadClusterInChain(u32),
+    /// An error from encoding or decoding the on-disk structures
+    Format(vfat32_core::Error),
+    /// The volume is inconsistent in some other way
     FileSystemInvalid,
     PathNotFound,
     IsADirectory,
     IsNotADirectory,
+    AlreadyExists,
+    /// The name can't be stored in a directory entry
+    InvalidName,
+    /// There are no free clusters left
+    NoSpace,
+    /// The directory already has the maximum number of entries
+    DirectoryFull,
+    /// Files can be at most 4 GiB - 1 bytes long
+    FileTooLarge,
+    DirectoryNotEmpty,
+    /// A directory can't be moved into itself or one of its subdirectories
+    InvalidRename,
+    /// Seeking to a position before the start of the file
+    InvalidSeek,
+}
+
+impl<E> From<vfat32_core::Error> for DriverError<E> {
+    fn from(error: vfat32_core::Error) -> Self {
+        match error {
+            vfat32_core::Error::InvalidName => Self::InvalidName,
+            error => Self::Format(error),
+        }
+    }
+}
+
+impl<E: Debug> Display for DriverError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => write!(f, "Block device error: {e:?}"),
+            Self::BadBootSignature => write!(f, "The boot sector signature is wrong."),
+            Self::BadFSInfo => write!(f, "The FS info sector is invalid."),
+            Self::ClusterOutOfRange(cluster) => {
+                write!(f, "Cluster {cluster} is outside of the volume.")
+            }
+            Self::ClusterChainLoop(cluster) => {
+                write!(f, "The cluster chain loops back to cluster {cluster}.")
+            }
+            Self::FreeClusterInChain(cluster) => {
+                write!(
+                    f,
+                    "Cluster {cluster} is in a cluster chain but marked as free."
+                )
+            }
+            Self::BadClusterInChain(cluster) => {
+                write!(
+                    f,
+                    "Cluster {cluster} is in a cluster chain but marked as bad."
+                )
+            }
+            Self::Format(e) => write!(f, "{e}"),
+            Self::FileSystemInvalid => write!(f, "The file system is invalid."),
+            Self::PathNotFound => write!(f, "No such file or directory."),
+            Self::IsADirectory => write!(f, "Is a directory."),
+            Self::IsNotADirectory => write!(f, "Not a directory."),
+            Self::AlreadyExists => write!(f, "The file already exists."),
+            Self::InvalidName => write!(f, "Invalid file name."),
+            Self::NoSpace => write!(f, "No space left on the volume."),
+            Self::DirectoryFull => write!(f, "The directory is full."),
+            Self::FileTooLarge => write!(f, "The file would be larger than 4 GiB."),
+            Self::DirectoryNotEmpty => write!(f, "The directory is not empty."),
+            Self::InvalidRename => {
+                write!(f, "A directory can't be moved into itself.")
+            }
+            Self::InvalidSeek => write!(f, "Can't seek before the start of the file."),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for DriverError<E> {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::Format(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
+/// Long names are at most 20 entries of 13 UTF-16 code units
+const MAX_LONG_NAME_UNITS: usize = 20 * LONG_NAME_CHARS_PER_ENTRY;
+
+/// Each UTF-16 code unit takes at most 3 bytes in UTF-8
+const MAX_NAME_BYTES: usize = MAX_LONG_NAME_UNITS * 3;
+
 struct EntryName {
-    inner: [u8; 256],
-    len: u8,
+    inner: [u8; MAX_NAME_BYTES],
+    len: usize,
 }
 
 impl EntryName {
-    fn new(inner: [u8; 256]) -> Self {
+    fn new(chars: impl Iterator<Item = char>) -> Self {
+        let mut inner = [0u8; MAX_NAME_BYTES];
         let mut len = 0;
 
-        for b in inner {
-            if b != 0 {
-                len += 1;
-            } else {
+        for c in chars {
+            if len + c.len_utf8() > MAX_NAME_BYTES {
                 break;
             }
+
+            len += c.encode_utf8(&mut inner[len..]).len();
         }
 
         Self { inner, len }
     }
 
+    /// Decodes a long name, replacing unpaired surrogates, which can't be represented in UTF-8
+    fn from_long_name(units: &[u16]) -> Self {
+        Self::new(
+            char::decode_utf16(units.iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+        )
+    }
+
+    fn from_short_name(entry: &RealEntry) -> Self {
+        Self::new(short_name_chars(entry.name_bytes(), entry.entry_case()))
+    }
+
     fn str(&self) -> &str {
-        unsafe { str::from_utf8_unchecked(&self.inner[0..(self.len as usize)]) }
+        unsafe { str::from_utf8_unchecked(&self.inner[0..self.len]) }
     }
 }
 
 struct NamedEntry {
     name: EntryName,
     entry: RealEntry,
+    /// The first cluster of the directory containing the entry
+    dir_cluster: u32,
+    /// The index of the real entry in its directory
+    index: usize,
+    /// The index of the first long file name entry, or the real entry if there are none
+    first_index: usize,
 }
 
 impl NamedEntry {
-    fn new(name: [u8; 256], entry: RealEntry) -> Self {
+    fn new(
+        name: EntryName,
+        entry: RealEntry,
+        dir_cluster: u32,
+        index: usize,
+        first_index: usize,
+    ) -> Self {
         Self {
-            name: EntryName::new(name),
+            name,
             entry,
+            dir_cluster,
+            index,
+            first_index,
         }
     }
 
     fn name(&self) -> &str {
         self.name.str()
     }
+
+    fn is_same_entry(&self, other: &NamedEntry) -> bool {
+        self.dir_cluster == other.dir_cluster && self.index == other.index
+    }
+
+    fn has_long_name(&self) -> bool {
+        self.first_index != self.index
+    }
+
+    /// Whether `name` refers to this entry. Long names ignore case the way Windows does, and
+    /// short names, which an entry with a long name can also be opened by, only ignore the case
+    /// of ASCII letters.
+    fn matches(&self, name: &str) -> bool {
+        if self.has_long_name() {
+            long_names_equal(self.name(), name)
+                || EntryName::from_short_name(&self.entry)
+                    .str()
+                    .eq_ignore_ascii_case(name)
+        } else {
+            self.name().eq_ignore_ascii_case(name)
+        }
+    }
 }
 
 struct DataBuffer {
@@ -100,23 +269,178 @@ impl VFATDirectoryEntry {
     pub fn is_directory(&self) -> bool {
         self.named_entry.entry.is_dir()
     }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(&self.named_entry.entry)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    attributes: u8,
+    created: Option<DosDateTime>,
+    modified: Option<DosDateTime>,
+    accessed: Option<DosDate>,
+    size: u32,
+    first_cluster: u32,
+}
+
+impl Metadata {
+    fn new(entry: &RealEntry) -> Self {
+        Self {
+            attributes: entry.attributes(),
+            created: entry.created(),
+            modified: entry.modified(),
+            accessed: entry.accessed(),
+            size: entry.file_size(),
+            first_cluster: entry.start_cluster(),
+        }
+    }
+
+    /// None if the entry has no creation time, which writers are allowed to leave out
+    pub fn created(&self) -> Option<DosDateTime> {
+        self.created
+    }
+
+    pub fn modified(&self) -> Option<DosDateTime> {
+        self.modified
+    }
+
+    /// Only the date of the last access is stored
+    pub fn accessed(&self) -> Option<DosDate> {
+        self.accessed
+    }
+
+    /// The raw attributes byte, see `Attributes`
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.has_attribute(Attributes::ReadOnly)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attribute(Attributes::Hidden)
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.has_attribute(Attributes::System)
+    }
+
+    /// Set when the file has changed since it was last backed up
+    pub fn is_archive(&self) -> bool {
+        self.has_attribute(Attributes::Archive)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.has_attribute(Attributes::Directory)
+    }
+
+    /// Always 0 for directories
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// 0 for empty files
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    fn has_attribute(&self, attribute: Attributes) -> bool {
+        self.attributes & attribute as u8 != 0
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct VFATFile {
     start_cluster: u32,
     size_bytes: u32,
+    /// The metadata from when the file was opened, except for the size and first cluster
+    metadata: Metadata,
+    /// Where the file's directory entry is, so it can be updated after writes
+    dir_cluster: u32,
+    entry_index: usize,
 }
 
 impl VFATFile {
     pub fn size(&self) -> usize {
         self.size_bytes as usize
     }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            size: self.size_bytes,
+            first_cluster: self.start_cluster,
+            ..self.metadata
+        }
+    }
 }
 
 pub struct VFATDirectory {
     start_cluster: u32,
 }
 
+/// Follows a cluster chain and notices when it goes around in a loop, with Brent's algorithm:
+/// it remembers one cluster, and moves that up to the current one after twice as many steps each
+/// time. A loop is found within the length of the chain up to the loop plus twice the loop's
+/// length.
+#[derive(Debug, Clone, Copy)]
+struct ChainWalker {
+    cluster: u32,
+    saved: u32,
+    steps: u32,
+    limit: u32,
+}
+
+impl ChainWalker {
+    fn new(start_cluster: u32) -> Self {
+        Self {
+            cluster: start_cluster,
+            saved: start_cluster,
+            steps: 0,
+            limit: 1,
+        }
+    }
+
+    /// Moves on to `next`, the cluster after the current one. Returns false if the chain has
+    /// been there before.
+    fn step(&mut self, next: u32) -> bool {
+        if next == self.saved {
+            return false;
+        }
+
+        self.steps += 1;
+
+        if self.steps == self.limit {
+            self.saved = next;
+            self.steps = 0;
+            self.limit = self.limit.saturating_mul(2);
+        }
+
+        self.cluster = next;
+
+        true
+    }
+}
+
+/// A cluster of a file and its index in the file's cluster chain, which reads can continue from
+/// without following the chain from the start
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClusterCursor {
+    index: usize,
+    walker: ChainWalker,
+}
+
+impl ClusterCursor {
+    pub(crate) fn new(file: &VFATFile) -> Self {
+        Self {
+            index: 0,
+            walker: ChainWalker::new(file.start_cluster),
+        }
+    }
+}
+
 pub struct VFAT32Driver<D>
 where
     D: BlockDevice,
@@ -124,42 +448,81 @@ where
     block_device: D,
     sector_size: u32,
     boot_record: BootRecord,
-    fs_info: FSInfo,
+    fat_type: FatType,
+    /// Only FAT32 has an FS info sector
+    fs_info: Option<FSInfo>,
+    /// Whether fs_info has changed since it was last written to disk
+    fs_info_dirty: bool,
+    /// Where to start looking for a free cluster. Starts out as the hint in the FS info sector,
+    /// which FAT12 and FAT16 don't have.
+    next_free_cluster: u32,
     fat_buffer: DataBuffer,
     data_buffer: DataBuffer,
 }
 
 impl<D: BlockDevice> VFAT32Driver<D> {
-    pub fn new(mut block_device: D) -> Result<Self, DriverError> {
+    pub fn new(mut block_device: D) -> Result<Self, DriverError<D::Error>> {
         let mut buffer = [0u8; 512];
         block_device
             .read_block(0, &mut buffer)
-            .map_err(|_| DriverError::DiskError)?;
+            .map_err(DriverError::Device)?;
 
         let boot_record = BootRecord::read(&buffer);
 
-        let fs_info_sector = boot_record.fs_info_sector() as u64;
-        block_device
-            .read_block(fs_info_sector, &mut buffer)
-            .map_err(|_| DriverError::DiskError)?;
-
-        let fs_info = FSInfo::read(&buffer);
+        if boot_record.boot_signature() != BOOT_SIGNATURE {
+            return Err(DriverError::BadBootSignature);
+        }
 
-        if !fs_info.is_valid() {
+        // The sector buffers are one 512 byte sector, and the layout needs clusters and a FAT
+        if boot_record.bytes_per_sector() as usize != buffer.len()
+            || boot_record.sectors_per_cluster() == 0
+            || boot_record.num_file_allocation_tables() == 0
+            || boot_record.sectors_per_fat() == 0
+        {
             return Err(DriverError::FileSystemInvalid);
         }
 
+        let fat_type = boot_record.fat_type();
+
+        let fs_info = match fat_type {
+            FatType::Fat32 => {
+                // Too many clusters for FAT16, but without the FAT32 fields
+                if !boot_record.is_fat32_layout() {
+                    return Err(DriverError::FileSystemInvalid);
+                }
+
+                let fs_info_sector = boot_record.fs_info_sector();
+                block_device
+                    .read_block(fs_info_sector, &mut buffer)
+                    .map_err(DriverError::Device)?;
+
+                let fs_info = FSInfo::read(&buffer);
+
+                if !fs_info.is_valid() {
+                    return Err(DriverError::BadFSInfo);
+                }
+
+                Some(fs_info)
+            }
+            FatType::Fat12 | FatType::Fat16 => None,
+        };
+
+        let next_free_cluster = fs_info.as_ref().map_or(2, |f| f.next_free_cluster());
+
         Ok(Self {
             block_device,
             sector_size: boot_record.bytes_per_sector() as u32,
             boot_record,
+            fat_type,
             fs_info,
+            fs_info_dirty: false,
+            next_free_cluster,
             fat_buffer: DataBuffer::empty(),
             data_buffer: DataBuffer::empty(),
         })
     }
 
-    pub fn open_dir(&mut self, path: &str) -> DriverResult<VFATDirectory> {
+    pub fn open_dir(&mut self, path: &str) -> DriverResult<VFATDirectory, D::Error> {
         if Self::iter_path_segments(path).next().is_none() {
             // This is empty of real path segments, but maybe not completely empty
             if !path.is_empty() {
@@ -187,13 +550,16 @@ impl<D: BlockDevice> VFAT32Driver<D> {
     pub fn read_dir(
         &mut self,
         directory: VFATDirectory,
-    ) -> DriverResult<impl Iterator<Item = DriverResult<VFATDirectoryEntry>> + use<'_, D>> {
+    ) -> DriverResult<
+        impl Iterator<Item = DriverResult<VFATDirectoryEntry, D::Error>> + use<'_, D>,
+        D::Error,
+    > {
         Ok(self
             .iter_directory_entries(directory.start_cluster)
             .map(|e| e.map(|e| VFATDirectoryEntry { named_entry: e })))
     }
 
-    pub fn open(&mut self, path: &str) -> DriverResult<VFATFile> {
+    pub fn open(&mut self, path: &str) -> DriverResult<VFATFile, D::Error> {
         let found_entry = self.open_entry(path)?;
 
         if !found_entry.entry.is_file() {
@@ -203,228 +569,1297 @@ impl<D: BlockDevice> VFAT32Driver<D> {
         Ok(VFATFile {
             start_cluster: found_entry.entry.start_cluster(),
             size_bytes: found_entry.entry.file_size(),
+            metadata: Metadata::new(&found_entry.entry),
+            dir_cluster: found_entry.dir_cluster,
+            entry_index: found_entry.index,
         })
     }
 
-    pub fn read_file(
-        &mut self,
-        file: VFATFile,
-        offset: usize,
-        buffer: &mut [u8],
-    ) -> DriverResult<usize> {
-        let start_sector_index = offset / self.boot_record.bytes_per_sector() as usize;
-        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
-
-        let mut current_file_offset = offset;
-        let mut current_sector = 0;
-        let mut next_cluster = file.start_cluster;
-        let mut bytes_read = 0;
+    /// Creates a new, empty file
+    pub fn create(&mut self, path: &str) -> DriverResult<VFATFile, D::Error> {
+        let (parent, name) = Self::split_path(path).ok_or(DriverError::PathNotFound)?;
+        let dir_cluster = self.open_dir(parent)?.start_cluster;
 
-        while !Self::is_end(next_cluster) {
-            for sector_in_cluster in 0..self.boot_record.sectors_per_cluster() {
-                self.read_data_cluster(next_cluster, sector_in_cluster)?;
+        let new_name = self.prepare_name(dir_cluster, name, None)?;
+        let entry = RealEntry::new([b' '; 11], Attributes::Archive as u8);
 
-                // This sector is special because we could have an offset in it
-                if current_sector == start_sector_index {
-                    // If the offset is 0, then this just starts at 0
-                    let start = current_file_offset % bytes_per_sector;
-                    let end = bytes_per_sector.min(buffer.len() - bytes_read);
+        let entry_index = self.add_entry(dir_cluster, &new_name, entry)?;
+        self.write_fs_info()?;
 
-                    for i in start..end {
-                        buffer[bytes_read] = self.data_buffer.as_slice()[i];
-                        bytes_read += 1;
-                    }
+        Ok(VFATFile {
+            start_cluster: 0,
+            size_bytes: 0,
+            metadata: Metadata::new(&entry),
+            dir_cluster,
+            entry_index,
+        })
+    }
 
-                    current_file_offset = 0;
-                } else {
-                    let end = bytes_per_sector.min(buffer.len() - bytes_read);
+    /// Creates a new, empty directory containing only the `.` and `..` entries
+    pub fn mkdir(&mut self, path: &str) -> DriverResult<VFATDirectory, D::Error> {
+        let (parent, name) = Self::split_path(path).ok_or(DriverError::PathNotFound)?;
+        let parent_cluster = self.open_dir(parent)?.start_cluster;
 
-                    for i in 0..end {
-                        buffer[bytes_read] = self.data_buffer.as_slice()[i];
-                        bytes_read += 1;
-                    }
-                }
+        let new_name = self.prepare_name(parent_cluster, name, None)?;
 
-                if bytes_read >= buffer.len() {
-                    return Ok(bytes_read);
-                }
+        let cluster = self.allocate_cluster(None)?;
 
-                current_sector += 1;
-            }
+        if let Err(e) = self.init_directory(cluster, parent_cluster, &new_name) {
+            self.free_chain(cluster)?;
+            self.write_fs_info()?;
 
-            next_cluster = self.read_fat_entry(next_cluster)?;
+            return Err(e);
         }
 
-        Ok(bytes_read)
-    }
+        self.write_fs_info()?;
 
-    fn open_entry<'a>(&'a mut self, path: &str) -> DriverResult<NamedEntry> {
-        let mut segments = Self::iter_path_segments(path).peekable();
+        Ok(VFATDirectory {
+            start_cluster: cluster,
+        })
+    }
 
-        let mut current_dir_cluster = self.boot_record.root_directory_cluster();
+    /// Removes a directory, which has to be empty
+    pub fn rmdir(&mut self, path: &str) -> DriverResult<(), D::Error> {
+        let found_entry = self.open_entry(path)?;
 
-        while let Some(segment) = segments.next() {
-            let dir_entry = self
-                .find_dir_entry(segment, current_dir_cluster)?
-                .ok_or(DriverError::PathNotFound)?;
+        if !found_entry.entry.is_dir() {
+            return Err(DriverError::IsNotADirectory);
+        }
 
-            if segments.peek().is_none() {
-                // This is the last segment of the path
-                return Ok(dir_entry);
-            } else {
-                // This is a directory segment
-                if !dir_entry.entry.is_dir() {
-                    return Err(DriverError::PathNotFound)?;
-                }
+        for entry in self.iter_directory_entries(found_entry.entry.start_cluster()) {
+            let entry = entry?;
 
-                current_dir_cluster = dir_entry.entry.start_cluster();
+            if entry.name() != "." && entry.name() != ".." {
+                return Err(DriverError::DirectoryNotEmpty);
             }
         }
 
-        Err(DriverError::PathNotFound)
+        self.delete_entries(&found_entry)?;
+        self.free_chain(found_entry.entry.start_cluster())?;
+        self.write_fs_info()
     }
 
-    fn iter_path_segments(path: &str) -> impl Iterator<Item = &str> {
-        path.split('/').filter(|s| !s.is_empty())
+    /// Removes a file and frees its clusters
+    pub fn unlink(&mut self, path: &str) -> DriverResult<(), D::Error> {
+        let found_entry = self.open_entry(path)?;
+
+        if !found_entry.entry.is_file() {
+            return Err(DriverError::IsADirectory);
+        }
+
+        self.delete_entries(&found_entry)?;
+        self.free_chain(found_entry.entry.start_cluster())?;
+        self.write_fs_info()
     }
 
-    fn find_dir_entry(
-        &mut self,
-        name: &str,
-        dir_start_cluster: u32,
-    ) -> Result<Option<NamedEntry>, DriverError> {
-        for entry in self.iter_directory_entries(dir_start_cluster) {
-            let entry = entry?;
+    /// Moves a file or directory to a new path, which must not exist yet. The parent of the new
+    /// path can be a different directory.
+    pub fn rename(&mut self, from: &str, to: &str) -> DriverResult<(), D::Error> {
+        let found_entry = self.open_entry(from)?;
 
-            if entry.name() == name {
-                return Ok(Some(entry));
-            }
+        let (parent, name) = Self::split_path(to).ok_or(DriverError::PathNotFound)?;
+        let new_parent_cluster = self.open_dir(parent)?.start_cluster;
+
+        if found_entry.entry.is_dir()
+            && self.is_ancestor(found_entry.entry.start_cluster(), new_parent_cluster)?
+        {
+            return Err(DriverError::InvalidRename);
         }
 
-        return Ok(None);
+        // The file can be renamed to its own name with different case
+        let new_name = self.prepare_name(new_parent_cluster, name, Some(&found_entry))?;
+
+        // The new entry is written before the old one is removed, so a failure in between leaves
+        // two names for the file rather than none
+        self.add_entry(new_parent_cluster, &new_name, found_entry.entry)?;
+        self.delete_entries(&found_entry)?;
+
+        if found_entry.entry.is_dir() && found_entry.dir_cluster != new_parent_cluster {
+            self.set_parent_link(found_entry.entry.start_cluster(), new_parent_cluster)?;
+        }
+
+        self.write_fs_info()
     }
 
-    fn iter_directory_entries(
+    /// Writes `buffer` into the file at `offset`, allocating clusters as needed. Writing past
+    /// the end of the file fills the gap with zeros.
+    pub fn write_file(
         &mut self,
-        dir_start_cluster: u32,
-    ) -> impl Iterator<Item = DriverResult<NamedEntry>> + use<'_, D> {
-        NamedEntryIterator::new(self, dir_start_cluster)
-    }
+        file: &mut VFATFile,
+        offset: usize,
+        buffer: &[u8],
+    ) -> DriverResult<usize, D::Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
 
-    fn read_dir_entry_from_buffer(&self, index: usize) -> Option<DirectoryEntry> {
-        let byte_index = index * DIRECTORY_ENTRY_SIZE;
-        let entry_slice = &self.data_buffer.as_slice()[byte_index..];
-        let entry = DirectoryEntry::read(entry_slice);
+        let end = offset
+            .checked_add(buffer.len())
+            .filter(|&end| end <= u32::MAX as usize)
+            .ok_or(DriverError::FileTooLarge)?;
 
-        match entry {
-            DirectoryEntry::Real(real) => {
-                if real.is_empty() {
-                    None
-                } else {
-                    Some(entry)
-                }
-            }
-            lfn => Some(lfn),
+        if offset > file.size() {
+            self.write_zeros(file, file.size(), offset)?;
         }
+
+        self.write_at(file, offset, buffer)?;
+
+        file.size_bytes = file.size_bytes.max(end as u32);
+        self.update_file_entry(file)?;
+        self.write_fs_info()?;
+
+        Ok(buffer.len())
     }
 
-    fn read_fat_sector(&mut self, cluster: u32) -> Result<usize, DriverError> {
-        let (sector, offset) = self.sector_in_fat(cluster);
+    /// Changes the size of the file, freeing the clusters past the new end when it shrinks and
+    /// filling the new space with zeros when it grows
+    pub fn truncate(&mut self, file: &mut VFATFile, len: usize) -> DriverResult<(), D::Error> {
+        if len > u32::MAX as usize {
+            return Err(DriverError::FileTooLarge);
+        }
 
-        if let Some(buffered_sector) = self.fat_buffer.location {
-            if sector == buffered_sector {
-                return Ok(offset);
+        let size = file.size();
+
+        if len > size {
+            self.write_zeros(file, size, len)?;
+        } else if len < size {
+            let clusters_to_keep = len.div_ceil(self.bytes_per_cluster());
+
+            if clusters_to_keep == 0 {
+                self.free_chain(file.start_cluster)?;
+                file.start_cluster = 0;
+            } else {
+                self.check_data_cluster(file.start_cluster)?;
+
+                let mut walker = ChainWalker::new(file.start_cluster);
+
+                for _ in 1..clusters_to_keep {
+                    self.walk_chain(&mut walker)?
+                        .ok_or(DriverError::FileSystemInvalid)?;
+                }
+
+                let last_cluster = walker.cluster;
+
+                if let Some(rest) = self.next_cluster(last_cluster)? {
+                    self.write_fat_entry(last_cluster, END_OF_CHAIN)?;
+                    self.free_chain(rest)?;
+                }
             }
         }
 
-        self.fat_buffer.location = Some(sector);
-        self.block_device
-            .read_block(sector, self.fat_buffer.as_slice_mut())
-            .map_err(|_| DriverError::DiskError)?;
+        file.size_bytes = len as u32;
+        self.update_file_entry(file)?;
+        self.write_fs_info()?;
 
-        Ok(offset)
+        Ok(())
     }
 
-    fn read_dir_entry(
+    /// Reads from `offset` until the buffer is full or the file ends, and returns how many bytes
+    /// were read
+    pub fn read_file(
         &mut self,
-        start_cluster: u32,
-        entry_index: usize,
-    ) -> Result<Option<DirectoryEntry>, DriverError> {
-        let sector_index =
-            (entry_index * DIRECTORY_ENTRY_SIZE) / self.boot_record.bytes_per_sector() as usize;
-        let entries_per_sector =
-            self.boot_record.bytes_per_sector() as usize / DIRECTORY_ENTRY_SIZE;
+        file: &VFATFile,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> DriverResult<usize, D::Error> {
+        let mut cursor = ClusterCursor::new(file);
+        self.read_file_at(file, offset, buffer, &mut cursor)
+    }
 
-        let mut current_sector = 0;
-        let mut next_cluster = start_cluster;
+    /// Reads like `read_file`, but starts following the cluster chain at `cursor` unless the
+    /// read starts before it, and leaves it at the cluster the read ended in
+    pub(crate) fn read_file_at(
+        &mut self,
+        file: &VFATFile,
+        offset: usize,
+        buffer: &mut [u8],
+        cursor: &mut ClusterCursor,
+    ) -> DriverResult<usize, D::Error> {
+        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
+        let bytes_per_cluster = bytes_per_sector * self.boot_record.sectors_per_cluster() as usize;
 
-        while !Self::is_end(next_cluster) {
-            for sector_in_cluster in 0..self.boot_record.sectors_per_cluster() {
-                self.read_data_cluster(next_cluster, sector_in_cluster)?;
+        let end = offset.saturating_add(buffer.len()).min(file.size());
 
-                if current_sector == sector_index {
-                    let index_into_buffer = entry_index % entries_per_sector;
+        if offset >= end {
+            return Ok(0);
+        }
 
-                    return Ok(self.read_dir_entry_from_buffer(index_into_buffer));
-                }
+        self.check_data_cluster(file.start_cluster)?;
 
-                current_sector += 1;
-            }
+        let first_index = offset / bytes_per_cluster;
 
-            next_cluster = self.read_fat_entry(next_cluster)?;
+        if cursor.index > first_index {
+            *cursor = ClusterCursor::new(file);
         }
 
-        Ok(None)
-    }
+        while cursor.index < first_index {
+            self.advance_cursor(cursor)?;
+        }
 
-    fn is_end(cluster: u32) -> bool {
-        cluster >= 0x0FFFFFF8
-    }
+        let mut position = offset;
+        let mut cluster_start = first_index * bytes_per_cluster;
 
-    fn read_fat_entry(&mut self, cluster: u32) -> Result<u32, DriverError> {
-        let fat_entry_offset = self.read_fat_sector(cluster)?;
+        loop {
+            let cluster_end = cluster_start + bytes_per_cluster;
+            let first_sector = self.data_sector_from_cluster(cursor.walker.cluster);
 
-        let data_slice = self.fat_buffer.as_slice();
-        let entry = read_u32_le(data_slice, fat_entry_offset);
+            while position < end.min(cluster_end) {
+                let offset_in_cluster = position - cluster_start;
+                let sector = first_sector + (offset_in_cluster / bytes_per_sector) as u64;
+                let offset_in_sector = offset_in_cluster % bytes_per_sector;
+                let bytes_read = position - offset;
 
-        Ok(entry & 0x0FFFFFFF)
-    }
+                let whole_sectors = if offset_in_sector == 0 {
+                    (end.min(cluster_end) - position) / bytes_per_sector
+                } else {
+                    0
+                };
+
+                if whole_sectors > 0 {
+                    // Read as many whole sectors as possible straight into the caller's buffer
+                    let len = whole_sectors * bytes_per_sector;
+
+                    self.block_device
+                        .read_blocks(
+                            sector,
+                            whole_sectors as u64,
+                            &mut buffer[bytes_read..bytes_read + len],
+                        )
+                        .map_err(DriverError::Device)?;
+
+                    position += len;
+                } else {
+                    // This sector is special because we could have an offset in it, or it could
+                    // be the last, partial sector
+                    self.read_data_sector(sector)?;
 
-    fn read_data_cluster(&mut self, cluster: u32, sector_offset: u64) -> Result<(), DriverError> {
-        let sector = self.data_sector_from_cluster(cluster);
+                    let len = (bytes_per_sector - offset_in_sector).min(end - position);
 
-        self.read_data_sector(sector + sector_offset)?;
+                    buffer[bytes_read..bytes_read + len].copy_from_slice(
+                        &self.data_buffer.as_slice()[offset_in_sector..offset_in_sector + len],
+                    );
 
-        Ok(())
-    }
+                    position += len;
+                }
+            }
 
-    fn read_data_sector(&mut self, sector: u64) -> Result<(), DriverError> {
-        if let Some(buffered_sector) = self.data_buffer.location {
-            if sector == buffered_sector {
-                return Ok(());
+            if position == end {
+                break;
             }
+
+            self.advance_cursor(cursor)?;
+            cluster_start = cluster_end;
         }
 
-        self.data_buffer.location = Some(sector);
-        self.block_device
-            .read_block(sector, self.data_buffer.as_slice_mut())
-            .map_err(|_| DriverError::DiskError)
+        Ok(position - offset)
     }
 
-    fn cluster_to_relative_sector(&self, cluster: u32) -> u64 {
-        ((cluster - 2) * self.boot_record.sectors_per_cluster() as u32) as u64
-    }
+    /// Moves the cursor to the next cluster of the file, which has to have one because the file
+    /// goes on after the cursor
+    fn advance_cursor(&mut self, cursor: &mut ClusterCursor) -> DriverResult<(), D::Error> {
+        // The cluster chain can't be shorter than the file
+        self.walk_chain(&mut cursor.walker)?
+            .ok_or(DriverError::FileSystemInvalid)?;
+        cursor.index += 1;
 
-    fn data_sector_from_cluster(&self, cluster: u32) -> u64 {
-        self.cluster_to_relative_sector(cluster) + self.boot_record.first_data_sector()
+        Ok(())
     }
 
-    fn sector_in_fat(&self, cluster: u32) -> (u64, usize) {
-        let fat_start_sector = self.boot_record.first_fat_sector() as u64;
-        let fat_offset = cluster * 4; // 4 bytes per 32-bit entry
+    /// Checks that `name` is not taken in the directory yet, and works out how to store it. The
+    /// entry being renamed, if any, doesn't count, as it is removed once the new name is added.
+    fn prepare_name(
+        &mut self,
+        dir_cluster: u32,
+        name: &str,
+        renamed: Option<&NamedEntry>,
+    ) -> DriverResult<EncodedName, D::Error> {
+        for entry in self.iter_directory_entries(dir_cluster) {
+            let entry = entry?;
 
-        let sector = fat_start_sector + (fat_offset as u64 / self.sector_size as u64);
-        let offset_into_sector = fat_offset % (self.sector_size);
+            // Names that only differ in case refer to the same file, and the volume label isn't a
+            // file
+            if !entry.entry.is_volume_label()
+                && !renamed.is_some_and(|renamed| renamed.is_same_entry(&entry))
+                && entry.matches(name)
+            {
+                return Err(DriverError::AlreadyExists);
+            }
+        }
+
+        encode_name(name, |short_name| {
+            self.short_name_exists(dir_cluster, short_name, renamed)
+        })
+    }
+
+    fn short_name_exists(
+        &mut self,
+        dir_cluster: u32,
+        short_name: &[u8; 11],
+        renamed: Option<&NamedEntry>,
+    ) -> DriverResult<bool, D::Error> {
+        for entry in self.iter_directory_entries(dir_cluster) {
+            let entry = entry?;
+
+            if entry.entry.name_bytes() == short_name
+                && !renamed.is_some_and(|renamed| renamed.is_same_entry(&entry))
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Stores `entry` under a new name in the directory, along with any long file name entries,
+    /// returning the index of the entry
+    fn add_entry(
+        &mut self,
+        dir_cluster: u32,
+        name: &EncodedName,
+        mut entry: RealEntry,
+    ) -> DriverResult<usize, D::Error> {
+        entry.set_short_name(*name.short_name());
+        entry.set_entry_case(name.entry_case());
+
+        let first_index = self.find_free_slots(dir_cluster, name.entry_count())?;
+
+        for (i, lfn_entry) in name.long_entries().enumerate() {
+            self.write_dir_entry(
+                dir_cluster,
+                first_index + i,
+                &DirectoryEntry::LFN(lfn_entry),
+            )?;
+        }
+
+        let entry_index = first_index + name.entry_count() - 1;
+        self.write_dir_entry(dir_cluster, entry_index, &DirectoryEntry::Real(entry))?;
+
+        Ok(entry_index)
+    }
+
+    /// Marks the entry and all of its long file name entries as deleted
+    fn delete_entries(&mut self, entry: &NamedEntry) -> DriverResult<(), D::Error> {
+        for index in entry.first_index..=entry.index {
+            let (sector, offset) = self
+                .dir_entry_location(entry.dir_cluster, index)?
+                .ok_or(DriverError::FileSystemInvalid)?;
+
+            self.read_data_sector(sector)?;
+            self.data_buffer.as_slice_mut()[offset] = DELETED_ENTRY_MARKER;
+            self.write_data_sector(sector)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fills in the `.` and `..` entries of a newly allocated directory cluster, then adds the
+    /// directory to its parent
+    fn init_directory(
+        &mut self,
+        cluster: u32,
+        parent_cluster: u32,
+        name: &EncodedName,
+    ) -> DriverResult<(), D::Error> {
+        let mut dot = RealEntry::new(*b".          ", Attributes::Directory as u8);
+        dot.set_start_cluster(cluster);
+        self.write_dir_entry(cluster, 0, &DirectoryEntry::Real(dot))?;
+
+        let mut dot_dot = RealEntry::new(*b"..         ", Attributes::Directory as u8);
+        dot_dot.set_start_cluster(self.parent_link_cluster(parent_cluster));
+        self.write_dir_entry(cluster, 1, &DirectoryEntry::Real(dot_dot))?;
+
+        let mut entry = RealEntry::new([b' '; 11], Attributes::Directory as u8);
+        entry.set_start_cluster(cluster);
+        self.add_entry(parent_cluster, name, entry)?;
+
+        Ok(())
+    }
+
+    /// `..` entries store 0 instead of the root directory's cluster
+    fn parent_link_cluster(&self, parent_cluster: u32) -> u32 {
+        if parent_cluster == self.boot_record.root_directory_cluster() {
+            0
+        } else {
+            parent_cluster
+        }
+    }
+
+    /// Points the `..` entry of a directory at a new parent
+    fn set_parent_link(
+        &mut self,
+        dir_cluster: u32,
+        parent_cluster: u32,
+    ) -> DriverResult<(), D::Error> {
+        let link = self.parent_link_cluster(parent_cluster);
+
+        let (sector, offset) = self
+            .dir_entry_location(dir_cluster, 1)?
+            .ok_or(DriverError::FileSystemInvalid)?;
+
+        self.read_data_sector(sector)?;
+
+        let slice = &mut self.data_buffer.as_slice_mut()[offset..offset + DIRECTORY_ENTRY_SIZE];
+        let mut entry = RealEntry::read(slice);
+
+        if entry.name_bytes() != b"..         " {
+            return Err(DriverError::FileSystemInvalid);
+        }
+
+        entry.set_start_cluster(link);
+        entry.write(slice);
+
+        self.write_data_sector(sector)
+    }
+
+    /// Whether the directory starting at `ancestor` is `dir_cluster` or one of its parents,
+    /// found by following the `..` entries up to the root
+    fn is_ancestor(&mut self, ancestor: u32, dir_cluster: u32) -> DriverResult<bool, D::Error> {
+        let root_cluster = self.boot_record.root_directory_cluster();
+        let mut current = dir_cluster;
+
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            if current == ancestor {
+                return Ok(true);
+            }
+
+            if current == root_cluster {
+                return Ok(false);
+            }
+
+            current = match self.read_dir_entry(current, 1)? {
+                Some(DirectoryEntry::Real(entry)) if entry.name_bytes() == b"..         " => {
+                    match entry.start_cluster() {
+                        0 => root_cluster,
+                        cluster => cluster,
+                    }
+                }
+                _ => return Err(DriverError::FileSystemInvalid),
+            };
+        }
+
+        Err(DriverError::FileSystemInvalid)
+    }
+
+    /// Fills the file with zeros from `from` to `to`, extending the chain as needed. Clusters
+    /// are zeroed when they are allocated, so only the ones the file already has are written.
+    fn write_zeros(
+        &mut self,
+        file: &mut VFATFile,
+        from: usize,
+        to: usize,
+    ) -> DriverResult<(), D::Error> {
+        if from >= to {
+            return Ok(());
+        }
+
+        let bytes_per_cluster = self.bytes_per_cluster();
+
+        if file.start_cluster == 0 {
+            file.start_cluster = self.allocate_cluster(None)?;
+            return self.extend_chain(file.start_cluster, to.div_ceil(bytes_per_cluster) - 1);
+        }
+
+        self.check_data_cluster(file.start_cluster)?;
+
+        let mut walker = ChainWalker::new(file.start_cluster);
+        let mut cluster_start = 0;
+
+        loop {
+            let cluster_end = cluster_start + bytes_per_cluster;
+
+            // The old contents of the file's clusters past its end could be anything
+            if from < cluster_end {
+                self.zero_in_cluster(
+                    walker.cluster,
+                    from.max(cluster_start) - cluster_start,
+                    to.min(cluster_end) - cluster_start,
+                )?;
+            }
+
+            if to <= cluster_end {
+                return Ok(());
+            }
+
+            if self.walk_chain(&mut walker)?.is_none() {
+                let remaining = (to - cluster_end).div_ceil(bytes_per_cluster);
+                return self.extend_chain(walker.cluster, remaining);
+            }
+
+            cluster_start = cluster_end;
+        }
+    }
+
+    /// Links `count` newly allocated clusters after `last_cluster`
+    fn extend_chain(&mut self, last_cluster: u32, count: usize) -> DriverResult<(), D::Error> {
+        let mut cluster = last_cluster;
+
+        for _ in 0..count {
+            cluster = self.allocate_cluster(Some(cluster))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes into the file's clusters, extending the chain as needed, without touching the
+    /// directory entry
+    fn write_at(
+        &mut self,
+        file: &mut VFATFile,
+        offset: usize,
+        buffer: &[u8],
+    ) -> DriverResult<(), D::Error> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
+        let bytes_per_cluster = self.bytes_per_cluster();
+
+        if file.start_cluster == 0 {
+            file.start_cluster = self.allocate_cluster(None)?;
+        }
+
+        let end = offset + buffer.len();
+
+        let mut position = offset;
+        let mut cluster_start = 0;
+        let mut cluster = file.start_cluster;
+
+        while cluster_start + bytes_per_cluster <= offset {
+            cluster = self.next_cluster_or_allocate(cluster)?;
+            cluster_start += bytes_per_cluster;
+        }
+
+        loop {
+            let cluster_end = cluster_start + bytes_per_cluster;
+            let first_sector = self.data_sector_from_cluster(cluster);
+
+            while position < end.min(cluster_end) {
+                let offset_in_cluster = position - cluster_start;
+                let sector = first_sector + (offset_in_cluster / bytes_per_sector) as u64;
+                let offset_in_sector = offset_in_cluster % bytes_per_sector;
+                let bytes_written = position - offset;
+
+                let whole_sectors = if offset_in_sector == 0 {
+                    (end.min(cluster_end) - position) / bytes_per_sector
+                } else {
+                    0
+                };
+
+                if whole_sectors > 0 {
+                    let len = whole_sectors * bytes_per_sector;
+
+                    self.block_device
+                        .write_blocks(
+                            sector,
+                            whole_sectors as u64,
+                            &buffer[bytes_written..bytes_written + len],
+                        )
+                        .map_err(DriverError::Device)?;
+
+                    // The data buffer could be holding an old copy of one of these sectors
+                    if let Some(buffered_sector) = self.data_buffer.location {
+                        if (sector..sector + whole_sectors as u64).contains(&buffered_sector) {
+                            self.data_buffer.location = None;
+                        }
+                    }
+
+                    position += len;
+                } else {
+                    // Only part of this sector changes, so the rest has to be read first
+                    self.read_data_sector(sector)?;
+
+                    let len = (bytes_per_sector - offset_in_sector).min(end - position);
+
+                    self.data_buffer.as_slice_mut()[offset_in_sector..offset_in_sector + len]
+                        .copy_from_slice(&buffer[bytes_written..bytes_written + len]);
+
+                    self.write_data_sector(sector)?;
+
+                    position += len;
+                }
+            }
+
+            if position >= end {
+                return Ok(());
+            }
+
+            cluster = self.next_cluster_or_allocate(cluster)?;
+            cluster_start = cluster_end;
+        }
+    }
+
+    fn update_file_entry(&mut self, file: &VFATFile) -> DriverResult<(), D::Error> {
+        let (sector, offset) = self
+            .dir_entry_location(file.dir_cluster, file.entry_index)?
+            .ok_or(DriverError::FileSystemInvalid)?;
+
+        self.read_data_sector(sector)?;
+
+        let slice = &mut self.data_buffer.as_slice_mut()[offset..offset + DIRECTORY_ENTRY_SIZE];
+        let mut entry = RealEntry::read(slice);
+        entry.set_start_cluster(file.start_cluster);
+        entry.set_file_size(file.size_bytes);
+        entry.write(slice);
+
+        self.write_data_sector(sector)
+    }
+
+    fn write_dir_entry(
+        &mut self,
+        dir_cluster: u32,
+        entry_index: usize,
+        entry: &DirectoryEntry,
+    ) -> DriverResult<(), D::Error> {
+        let (sector, offset) = self
+            .dir_entry_location(dir_cluster, entry_index)?
+            .ok_or(DriverError::FileSystemInvalid)?;
+
+        self.read_data_sector(sector)?;
+        entry.write(&mut self.data_buffer.as_slice_mut()[offset..]);
+        self.write_data_sector(sector)
+    }
+
+    /// Finds `count` consecutive unused entries in a directory, growing it if there is not
+    /// enough room. Returns the index of the first one.
+    fn find_free_slots(&mut self, dir_cluster: u32, count: usize) -> DriverResult<usize, D::Error> {
+        let mut index = 0;
+        let mut run_start = 0;
+        let mut run_len = 0;
+
+        while index < MAX_DIRECTORY_ENTRIES {
+            let Some((sector, offset)) = self.dir_entry_location(dir_cluster, index)? else {
+                // The fixed root directory of FAT12 and FAT16 can't grow
+                if dir_cluster == 0 {
+                    return Err(DriverError::DirectoryFull);
+                }
+
+                // Out of room, so add a cluster, which is zeroed and so full of free entries
+                let last_cluster = self.last_cluster(dir_cluster)?;
+                self.allocate_cluster(Some(last_cluster))?;
+
+                continue;
+            };
+
+            self.read_data_sector(sector)?;
+
+            let first_byte = self.data_buffer.as_slice()[offset];
+
+            if first_byte == 0 || first_byte == DELETED_ENTRY_MARKER {
+                if run_len == 0 {
+                    run_start = index;
+                }
+
+                run_len += 1;
+
+                if run_len == count {
+                    return Ok(run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+
+            index += 1;
+        }
+
+        Err(DriverError::DirectoryFull)
+    }
+
+    fn last_cluster(&mut self, start_cluster: u32) -> DriverResult<u32, D::Error> {
+        self.check_data_cluster(start_cluster)?;
+
+        let mut walker = ChainWalker::new(start_cluster);
+
+        while self.walk_chain(&mut walker)?.is_some() {}
+
+        Ok(walker.cluster)
+    }
+
+    fn next_cluster_or_allocate(&mut self, cluster: u32) -> DriverResult<u32, D::Error> {
+        match self.next_cluster(cluster)? {
+            Some(next) => Ok(next),
+            None => self.allocate_cluster(Some(cluster)),
+        }
+    }
+
+    /// Takes a free cluster, zeroes it, and links it after `previous` if given
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> DriverResult<u32, D::Error> {
+        let cluster = self.find_free_cluster()?;
+
+        self.write_fat_entry(cluster, END_OF_CHAIN)?;
+
+        if let Some(previous) = previous {
+            self.write_fat_entry(previous, cluster)?;
+        }
+
+        self.zero_cluster(cluster)?;
+
+        self.next_free_cluster = cluster;
+
+        if let Some(fs_info) = &mut self.fs_info {
+            let free_clusters = fs_info.free_cluster_count();
+            if free_clusters != FS_INFO_UNKNOWN {
+                fs_info.set_free_cluster_count(free_clusters.saturating_sub(1));
+            }
+            fs_info.set_next_free_cluster(cluster);
+            self.fs_info_dirty = true;
+        }
+
+        Ok(cluster)
+    }
+
+    fn find_free_cluster(&mut self) -> DriverResult<u32, D::Error> {
+        let first = 2;
+        let end = self.cluster_end();
+
+        // Start from the hint, wrapping around to the start
+        let hint = self.next_free_cluster;
+        let start = if (first..end).contains(&hint) {
+            hint
+        } else {
+            first
+        };
+
+        for cluster in (start..end).chain(first..start) {
+            if self.read_fat_entry(cluster)? == 0 {
+                return Ok(cluster);
+            }
+        }
+
+        Err(DriverError::NoSpace)
+    }
+
+    /// Frees every cluster of a chain. Empty files have a start cluster of 0, which is no chain
+    /// at all.
+    fn free_chain(&mut self, start_cluster: u32) -> DriverResult<(), D::Error> {
+        if start_cluster == 0 {
+            return Ok(());
+        }
+
+        self.check_data_cluster(start_cluster)?;
+
+        // The whole chain is checked before anything is freed, so a damaged chain is left as it
+        // is for fsck to look at
+        let mut walker = ChainWalker::new(start_cluster);
+
+        while self.walk_chain(&mut walker)?.is_some() {}
+
+        let mut cluster = Some(start_cluster);
+        let mut freed = 0;
+
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current)?;
+
+            self.write_fat_entry(current, 0)?;
+            freed += 1;
+        }
+
+        if let Some(fs_info) = &mut self.fs_info {
+            let free_clusters = fs_info.free_cluster_count();
+            if freed > 0 && free_clusters != FS_INFO_UNKNOWN {
+                fs_info.set_free_cluster_count(free_clusters + freed);
+                self.fs_info_dirty = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Zeroes the bytes of a cluster from `start` up to `end`
+    fn zero_in_cluster(
+        &mut self,
+        cluster: u32,
+        start: usize,
+        end: usize,
+    ) -> DriverResult<(), D::Error> {
+        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
+        let first_sector = self.data_sector_from_cluster(cluster);
+
+        let mut position = start;
+
+        while position < end {
+            let sector = first_sector + (position / bytes_per_sector) as u64;
+            let offset_in_sector = position % bytes_per_sector;
+            let len = (bytes_per_sector - offset_in_sector).min(end - position);
+
+            if len < bytes_per_sector {
+                self.read_data_sector(sector)?;
+            }
+
+            self.data_buffer.as_slice_mut()[offset_in_sector..offset_in_sector + len].fill(0);
+            self.write_data_sector(sector)?;
+
+            position += len;
+        }
+
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> DriverResult<(), D::Error> {
+        let first_sector = self.data_sector_from_cluster(cluster);
+
+        self.data_buffer.as_slice_mut().fill(0);
+
+        for sector in 0..self.boot_record.sectors_per_cluster() {
+            self.write_data_sector(first_sector + sector)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_fs_info(&mut self) -> DriverResult<(), D::Error> {
+        let Some(fs_info) = &self.fs_info else {
+            return Ok(());
+        };
+
+        if !self.fs_info_dirty {
+            return Ok(());
+        }
+
+        let sector = self.boot_record.fs_info_sector();
+        let mut buffer = [0u8; 512];
+
+        fs_info.write(&mut buffer);
+
+        self.block_device
+            .write_block(sector, &buffer)
+            .map_err(DriverError::Device)?;
+
+        self.fs_info_dirty = false;
+
+        Ok(())
+    }
+
+    fn bytes_per_cluster(&self) -> usize {
+        self.boot_record.bytes_per_sector() as usize
+            * self.boot_record.sectors_per_cluster() as usize
+    }
+
+    /// One past the last valid cluster number
+    fn cluster_end(&self) -> u32 {
+        let fat_bits = self.boot_record.sectors_per_fat() as u64 * self.sector_size as u64 * 8;
+        let fat_entries = fat_bits / self.fat_type.entry_bits() as u64;
+
+        (self.boot_record.cluster_count() as u64 + 2).min(fat_entries) as u32
+    }
+
+    fn open_entry(&mut self, path: &str) -> DriverResult<NamedEntry, D::Error> {
+        let mut segments = Self::iter_path_segments(path).peekable();
+
+        let mut current_dir_cluster = self.boot_record.root_directory_cluster();
+
+        while let Some(segment) = segments.next() {
+            let dir_entry = self
+                .find_dir_entry(segment, current_dir_cluster)?
+                .ok_or(DriverError::PathNotFound)?;
+
+            if segments.peek().is_none() {
+                // This is the last segment of the path
+                return Ok(dir_entry);
+            } else {
+                // This is a directory segment
+                if !dir_entry.entry.is_dir() {
+                    return Err(DriverError::PathNotFound)?;
+                }
+
+                current_dir_cluster = dir_entry.entry.start_cluster();
+            }
+        }
+
+        Err(DriverError::PathNotFound)
+    }
+
+    /// The names in the path, with `.` segments dropped and `..` segments taking out the name
+    /// before them. `..` in the root directory stays in the root directory.
+    fn iter_path_segments(path: &str) -> impl Iterator<Item = &str> {
+        let mut segments = path.split('/');
+
+        core::iter::from_fn(move || {
+            while let Some(segment) = segments.next() {
+                match segment {
+                    "" | "." | ".." => {}
+                    name if !Self::is_taken_out(segments.clone()) => return Some(name),
+                    _ => {}
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Whether a `..` in the segments after a name takes it out, which happens once there are
+    /// more `..` segments than names after it
+    fn is_taken_out<'a>(rest: impl Iterator<Item = &'a str>) -> bool {
+        let mut depth = 1usize;
+
+        for segment in rest {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    depth -= 1;
+
+                    if depth == 0 {
+                        return true;
+                    }
+                }
+                _ => depth += 1,
+            }
+        }
+
+        false
+    }
+
+    /// Splits a path into its parent directory and the name of the last segment
+    fn split_path(path: &str) -> Option<(&str, &str)> {
+        let path = path.trim_end_matches('/');
+
+        let (parent, name) = match path.rfind('/') {
+            Some(i) => (&path[..=i], &path[i + 1..]),
+            None => ("/", path),
+        };
+
+        if name.is_empty() {
+            None
+        } else {
+            Some((parent, name))
+        }
+    }
+
+    fn find_dir_entry(
+        &mut self,
+        name: &str,
+        dir_start_cluster: u32,
+    ) -> Result<Option<NamedEntry>, DriverError<D::Error>> {
+        for entry in self.iter_directory_entries(dir_start_cluster) {
+            let entry = entry?;
+
+            // The volume label isn't a file, even though it has a name
+            if !entry.entry.is_volume_label() && entry.matches(name) {
+                return Ok(Some(entry));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    fn iter_directory_entries(
+        &mut self,
+        dir_start_cluster: u32,
+    ) -> impl Iterator<Item = DriverResult<NamedEntry, D::Error>> + use<'_, D> {
+        NamedEntryIterator::new(self, dir_start_cluster)
+    }
+
+    fn read_dir_entry_from_buffer(&self, index: usize) -> Option<DirectoryEntry> {
+        let byte_index = index * DIRECTORY_ENTRY_SIZE;
+        let entry_slice = &self.data_buffer.as_slice()[byte_index..];
+        let entry = DirectoryEntry::read(entry_slice);
+
+        match entry {
+            DirectoryEntry::Real(real) => {
+                if real.is_empty() {
+                    None
+                } else {
+                    Some(entry)
+                }
+            }
+            lfn => Some(lfn),
+        }
+    }
+
+    /// Loads the sector of the first FAT that holds a byte of it into the FAT buffer, and returns
+    /// the byte's offset in the buffer
+    fn read_fat_sector(&mut self, fat_offset: u64) -> Result<usize, DriverError<D::Error>> {
+        let (sector, offset) = self.sector_in_fat(fat_offset);
+
+        if let Some(buffered_sector) = self.fat_buffer.location {
+            if sector == buffered_sector {
+                return Ok(offset);
+            }
+        }
+
+        // A failed read leaves the buffer holding part of any sector, so it holds none until the
+        // read succeeds
+        self.fat_buffer.location = None;
+        self.block_device
+            .read_block(sector, self.fat_buffer.as_slice_mut())
+            .map_err(DriverError::Device)?;
+        self.fat_buffer.location = Some(sector);
+
+        Ok(offset)
+    }
+
+    /// Writes the FAT buffer to its sector in every copy of the FAT
+    fn write_fat_sector(&mut self) -> Result<(), DriverError<D::Error>> {
+        let Some(sector) = self.fat_buffer.location else {
+            return Ok(());
+        };
+
+        for fat in 0..self.boot_record.num_file_allocation_tables() as u64 {
+            let fat_sector = sector + fat * self.boot_record.sectors_per_fat() as u64;
+
+            if let Err(error) = self
+                .block_device
+                .write_block(fat_sector, self.fat_buffer.as_slice())
+            {
+                // The change didn't reach the disk, so it is read again from there next time
+                self.fat_buffer.location = None;
+                return Err(DriverError::Device(error));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads bytes of the FAT one at a time, because FAT12 entries can cross sector boundaries
+    fn read_fat_bytes<const N: usize>(
+        &mut self,
+        fat_offset: u64,
+    ) -> DriverResult<[u8; N], D::Error> {
+        let mut bytes = [0u8; N];
+
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let offset = self.read_fat_sector(fat_offset + i as u64)?;
+            *byte = self.fat_buffer.as_slice()[offset];
+        }
+
+        Ok(bytes)
+    }
+
+    /// Writes bytes of the FAT, writing each sector out once all of its bytes have been set
+    fn write_fat_bytes(&mut self, fat_offset: u64, bytes: &[u8]) -> DriverResult<(), D::Error> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let offset = self.read_fat_sector(fat_offset + i as u64)?;
+            self.fat_buffer.as_slice_mut()[offset] = byte;
+
+            if i + 1 == bytes.len() || offset + 1 == self.sector_size as usize {
+                self.write_fat_sector()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_dir_entry(
+        &mut self,
+        start_cluster: u32,
+        entry_index: usize,
+    ) -> Result<Option<DirectoryEntry>, DriverError<D::Error>> {
+        let Some((sector, offset)) = self.dir_entry_location(start_cluster, entry_index)? else {
+            return Ok(None);
+        };
+
+        self.read_data_sector(sector)?;
+
+        Ok(self.read_dir_entry_from_buffer(offset / DIRECTORY_ENTRY_SIZE))
+    }
+
+    /// Finds the sector that holds an entry of a directory and the entry's byte offset in it,
+    /// or None if the directory's clusters end before the entry
+    fn dir_entry_location(
+        &mut self,
+        start_cluster: u32,
+        entry_index: usize,
+    ) -> DriverResult<Option<(u64, usize)>, D::Error> {
+        let bytes_per_sector = self.boot_record.bytes_per_sector() as usize;
+        let sectors_per_cluster = self.boot_record.sectors_per_cluster() as usize;
+
+        let byte_index = entry_index * DIRECTORY_ENTRY_SIZE;
+        let sector_index = byte_index / bytes_per_sector;
+
+        // The root directory of FAT12 and FAT16 is a fixed number of entries before the clusters
+        if start_cluster == 0 {
+            if entry_index >= self.boot_record.num_root_directory_entries() as usize {
+                return Ok(None);
+            }
+
+            let sector = self.boot_record.root_directory_sector() + sector_index as u64;
+
+            return Ok(Some((sector, byte_index % bytes_per_sector)));
+        }
+
+        if entry_index >= MAX_DIRECTORY_ENTRIES {
+            return Ok(None);
+        }
+
+        self.check_data_cluster(start_cluster)?;
+
+        let mut walker = ChainWalker::new(start_cluster);
+
+        for _ in 0..sector_index / sectors_per_cluster {
+            if self.walk_chain(&mut walker)?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let sector = self.data_sector_from_cluster(walker.cluster)
+            + (sector_index % sectors_per_cluster) as u64;
+
+        Ok(Some((sector, byte_index % bytes_per_sector)))
+    }
+
+    fn is_end(cluster: u32) -> bool {
+        cluster >= 0x0FFFFFF8
+    }
+
+    /// The cluster after `cluster` in its chain, or None if it is the last one. Free and bad
+    /// clusters can't be part of a chain, and neither can clusters outside of the data region.
+    fn next_cluster(&mut self, cluster: u32) -> DriverResult<Option<u32>, D::Error> {
+        let next = self.read_fat_entry(cluster)?;
+
+        match next {
+            0 => Err(DriverError::FreeClusterInChain(cluster)),
+            BAD_CLUSTER => Err(DriverError::BadClusterInChain(cluster)),
+            next if Self::is_end(next) => Ok(None),
+            next => {
+                self.check_data_cluster(next)?;
+                Ok(Some(next))
+            }
+        }
+    }
+
+    /// Moves the walker to the next cluster of its chain, and returns it, or None at the end of
+    /// the chain
+    fn walk_chain(&mut self, walker: &mut ChainWalker) -> DriverResult<Option<u32>, D::Error> {
+        let Some(next) = self.next_cluster(walker.cluster)? else {
+            return Ok(None);
+        };
+
+        if !walker.step(next) {
+            return Err(DriverError::ClusterChainLoop(next));
+        }
+
+        Ok(Some(next))
+    }
+
+    /// Reads an entry of the first FAT. FAT12 and FAT16 entries are widened so that their end of
+    /// chain and bad cluster markers have the same values as on FAT32.
+    fn read_fat_entry(&mut self, cluster: u32) -> Result<u32, DriverError<D::Error>> {
+        let fat_offset = self.fat_entry_offset(cluster);
+
+        let entry = match self.fat_type {
+            FatType::Fat12 => {
+                let pair = u16::from_le_bytes(self.read_fat_bytes(fat_offset)?);
+
+                // Two entries share three bytes, the odd one has the upper 12 bits
+                let entry = if cluster.is_multiple_of(2) {
+                    pair & 0x0FFF
+                } else {
+                    pair >> 4
+                } as u32;
+
+                if entry >= 0x0FF7 {
+                    entry | 0x0FFFF000
+                } else {
+                    entry
+                }
+            }
+            FatType::Fat16 => {
+                let entry = u16::from_le_bytes(self.read_fat_bytes(fat_offset)?) as u32;
+
+                if entry >= 0xFFF7 {
+                    entry | 0x0FFF0000
+                } else {
+                    entry
+                }
+            }
+            FatType::Fat32 => u32::from_le_bytes(self.read_fat_bytes(fat_offset)?) & 0x0FFFFFFF,
+        };
+
+        Ok(entry)
+    }
+
+    /// Sets a FAT entry in every copy of the FAT. Values are cut down to the width of the
+    /// entries, and the reserved top four bits of FAT32 entries are kept.
+    fn write_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), DriverError<D::Error>> {
+        let fat_offset = self.fat_entry_offset(cluster);
+
+        match self.fat_type {
+            FatType::Fat12 => {
+                let old_pair = u16::from_le_bytes(self.read_fat_bytes(fat_offset)?);
+                let value = (value & 0x0FFF) as u16;
+
+                let new_pair = if cluster.is_multiple_of(2) {
+                    (old_pair & 0xF000) | value
+                } else {
+                    (old_pair & 0x000F) | (value << 4)
+                };
+
+                self.write_fat_bytes(fat_offset, &new_pair.to_le_bytes())
+            }
+            FatType::Fat16 => self.write_fat_bytes(fat_offset, &(value as u16).to_le_bytes()),
+            FatType::Fat32 => {
+                let old_entry = u32::from_le_bytes(self.read_fat_bytes(fat_offset)?);
+                let new_entry = (old_entry & 0xF0000000) | (value & 0x0FFFFFFF);
+
+                self.write_fat_bytes(fat_offset, &new_entry.to_le_bytes())
+            }
+        }
+    }
+
+    /// Where a cluster's entry starts in the FAT, in bytes
+    fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn read_data_sector(&mut self, sector: u64) -> Result<(), DriverError<D::Error>> {
+        if let Some(buffered_sector) = self.data_buffer.location {
+            if sector == buffered_sector {
+                return Ok(());
+            }
+        }
+
+        // A failed read leaves the buffer holding part of any sector, so it holds none until the
+        // read succeeds
+        self.data_buffer.location = None;
+        self.block_device
+            .read_block(sector, self.data_buffer.as_slice_mut())
+            .map_err(DriverError::Device)?;
+        self.data_buffer.location = Some(sector);
+
+        Ok(())
+    }
+
+    /// Writes the data buffer to `sector`, which it then holds
+    fn write_data_sector(&mut self, sector: u64) -> Result<(), DriverError<D::Error>> {
+        // The buffer doesn't match what is on the disk if the write fails
+        self.data_buffer.location = None;
+        self.block_device
+            .write_block(sector, self.data_buffer.as_slice())
+            .map_err(DriverError::Device)?;
+        self.data_buffer.location = Some(sector);
+
+        Ok(())
+    }
+
+    /// Checks that the cluster is in the data region, where the clusters are numbered from 2,
+    /// and has an entry in the FAT
+    fn check_data_cluster(&self, cluster: u32) -> DriverResult<(), D::Error> {
+        if (2..self.cluster_end()).contains(&cluster) {
+            Ok(())
+        } else {
+            Err(DriverError::ClusterOutOfRange(cluster))
+        }
+    }
+
+    fn cluster_to_relative_sector(&self, cluster: u32) -> u64 {
+        ((cluster - 2) * self.boot_record.sectors_per_cluster() as u32) as u64
+    }
+
+    fn data_sector_from_cluster(&self, cluster: u32) -> u64 {
+        self.cluster_to_relative_sector(cluster) + self.boot_record.first_data_sector()
+    }
+
+    fn sector_in_fat(&self, fat_offset: u64) -> (u64, usize) {
+        let fat_start_sector = self.boot_record.first_fat_sector();
+
+        let sector = fat_start_sector + fat_offset / self.sector_size as u64;
+        let offset_into_sector = fat_offset % self.sector_size as u64;
 
         (sector, offset_into_sector as usize)
     }
@@ -437,6 +1872,9 @@ where
     driver: &'a mut VFAT32Driver<D>,
     start_cluster: u32,
     next_index: usize,
+    /// Set at the end of the directory or after an error, which would come up again if the
+    /// same entry was read again
+    done: bool,
 }
 
 impl<'a, D> NamedEntryIterator<'a, D>
@@ -448,98 +1886,419 @@ where
             driver,
             start_cluster,
             next_index: 0,
+            done: false,
         }
     }
-}
-
-impl<'a, D> Iterator for NamedEntryIterator<'a, D>
-where
-    D: BlockDevice,
-{
-    type Item = DriverResult<NamedEntry>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut current_file_name_index = 0;
-        let mut current_file_name = [0u8; 256];
+    fn next_entry(&mut self) -> Option<DriverResult<NamedEntry, D::Error>> {
         let mut lfn_first = None;
 
         loop {
-            if let Ok(entry) = self
+            let entry = match self
                 .driver
                 .read_dir_entry(self.start_cluster, self.next_index)
                 .transpose()?
             {
-                match entry {
-                    DirectoryEntry::LFN(_) => {
-                        if lfn_first.is_none() {
-                            lfn_first = Some(self.next_index);
-                        }
-                        self.next_index += 1;
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match entry {
+                DirectoryEntry::LFN(lfn_entry) if lfn_entry.is_deleted() => {
+                    lfn_first = None;
+                    self.next_index += 1;
+                }
+                DirectoryEntry::Real(entry) if entry.is_deleted() => {
+                    lfn_first = None;
+                    self.next_index += 1;
+                }
+                DirectoryEntry::LFN(lfn_entry) => {
+                    // A run of long name entries starts again at the entry with the end of a name
+                    if lfn_first.is_none() || lfn_entry.sequence_number() & LAST_LONG_ENTRY != 0 {
+                        lfn_first = Some(self.next_index);
                     }
-                    DirectoryEntry::Real(entry) => {
-                        let current_index = self.next_index;
-                        self.next_index += 1;
-
-                        if let Some(first_idx) = lfn_first {
-                            for lfn_entry_idx in (first_idx..current_index).rev() {
-                                if let Ok(DirectoryEntry::LFN(lfn_entry)) = self
-                                    .driver
-                                    .read_dir_entry(self.start_cluster, lfn_entry_idx)
-                                    .transpose()?
-                                {
-                                    for c in lfn_entry.name() {
-                                        current_file_name[current_file_name_index] = c as u8;
-                                        current_file_name_index += 1;
-                                    }
-                                } else {
-                                    panic!();
-                                }
+                    self.next_index += 1;
+                }
+                DirectoryEntry::Real(entry) => {
+                    let current_index = self.next_index;
+                    self.next_index += 1;
+
+                    if let Some(first_idx) = lfn_first {
+                        let checksum = short_name_checksum(entry.name_bytes());
+                        let mut units = [0u16; MAX_LONG_NAME_UNITS];
+                        let mut len = 0;
+                        let mut ended = false;
+                        let mut belongs = true;
+
+                        // The entries are stored in reverse, the first part of the name is in
+                        // the entry right before the real one
+                        for (i, lfn_entry_idx) in (first_idx..current_index).rev().enumerate() {
+                            let lfn_entry = match self
+                                .driver
+                                .read_dir_entry(self.start_cluster, lfn_entry_idx)
+                            {
+                                Ok(Some(DirectoryEntry::LFN(lfn_entry))) => lfn_entry,
+                                Err(e) => return Some(Err(e)),
+                                // It was a long file name entry when it was first read
+                                Ok(_) => return Some(Err(DriverError::FileSystemInvalid)),
+                            };
+
+                            // Left behind by something that didn't know about long names if
+                            // the ordinals don't count up to the real entry or the checksum is
+                            // for another short name
+                            let mut sequence_number = (i + 1) as u8;
+                            if lfn_entry_idx == first_idx {
+                                sequence_number |= LAST_LONG_ENTRY;
                             }
-
-                            return Some(Ok(NamedEntry::new(current_file_name, entry)));
-                        } else {
-                            let name_bytes = entry.name_bytes();
-
-                            // Get the file name
-                            for i in 0..8 {
-                                let c = name_bytes[i];
-
-                                if c != b' ' {
-                                    if entry.is_name_lowercase() {
-                                        current_file_name[current_file_name_index] =
-                                            c.to_ascii_lowercase();
-                                    } else {
-                                        current_file_name[current_file_name_index] = c;
-                                    }
-                                    current_file_name_index += 1;
-                                }
+                            if i >= MAX_LONG_NAME_UNITS / LONG_NAME_CHARS_PER_ENTRY
+                                || lfn_entry.sequence_number() != sequence_number
+                                || lfn_entry.short_name_checksum() != checksum
+                            {
+                                belongs = false;
+                                break;
                             }
-                            if entry.has_extension() {
-                                // If the file name has an extension, add a dot
-                                current_file_name[current_file_name_index] = b'.';
-                                current_file_name_index += 1;
-
-                                // Get the extension
-                                for i in 8..11 {
-                                    let c = name_bytes[i];
-
-                                    if c != b' ' {
-                                        if entry.is_extension_lowercase() {
-                                            current_file_name[current_file_name_index] =
-                                                c.to_ascii_lowercase();
-                                        } else {
-                                            current_file_name[current_file_name_index] = c;
-                                        }
-                                        current_file_name_index += 1;
-                                    }
+
+                            for &unit in lfn_entry.name_units() {
+                                // The name ends with a 0 unless it fills the last entry
+                                if ended || unit == 0 || len == MAX_LONG_NAME_UNITS {
+                                    ended = true;
+                                    break;
                                 }
+
+                                units[len] = unit;
+                                len += 1;
                             }
+                        }
 
-                            return Some(Ok(NamedEntry::new(current_file_name, entry)));
+                        if belongs {
+                            return Some(Ok(NamedEntry::new(
+                                EntryName::from_long_name(&units[..len]),
+                                entry,
+                                self.start_cluster,
+                                current_index,
+                                first_idx,
+                            )));
                         }
                     }
+
+                    return Some(Ok(NamedEntry::new(
+                        EntryName::from_short_name(&entry),
+                        entry,
+                        self.start_cluster,
+                        current_index,
+                        current_index,
+                    )));
                 }
             }
         }
     }
 }
+
+impl<'a, D> Iterator for NamedEntryIterator<'a, D>
+where
+    D: BlockDevice,
+{
+    type Item = DriverResult<NamedEntry, D::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.next_entry();
+
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_device::memory::MemoryBlockDevice;
+    use vfat32_core::{
+        format::{format, FormatOptions},
+        fs_info::FSInfo,
+    };
+
+    use crate::{DriverError, VFAT32Driver, VFATFile};
+
+    type Driver = VFAT32Driver<MemoryBlockDevice<Vec<u8>>>;
+
+    fn formatted() -> Driver {
+        let mut device = MemoryBlockDevice::zeroed(20480, 512).unwrap();
+        format(&mut device, &FormatOptions::default()).unwrap();
+
+        VFAT32Driver::new(device).unwrap()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    fn read_all(driver: &mut Driver, file: &VFATFile) -> Vec<u8> {
+        let mut buffer = vec![0u8; file.size()];
+        assert_eq!(driver.read_file(file, 0, &mut buffer).unwrap(), file.size());
+
+        buffer
+    }
+
+    fn cluster_count(driver: &mut Driver, file: &VFATFile) -> usize {
+        if file.start_cluster == 0 {
+            return 0;
+        }
+
+        let mut count = 1;
+        let mut cluster = file.start_cluster;
+
+        while let Some(next) = driver.next_cluster(cluster).unwrap() {
+            cluster = next;
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Checks that every copy of the FAT is the same, and that the FS info sector on the disk
+    /// matches the FAT
+    fn check_allocation(driver: &mut Driver) {
+        let bytes_per_sector = driver.sector_size as usize;
+        let fat_len = driver.boot_record.sectors_per_fat() as usize * bytes_per_sector;
+        let first_fat = driver.boot_record.first_fat_sector() as usize * bytes_per_sector;
+        let disk = driver.block_device.as_slice();
+
+        let fat = &disk[first_fat..first_fat + fat_len];
+        for copy in 1..driver.boot_record.num_file_allocation_tables() as usize {
+            let start = first_fat + copy * fat_len;
+            assert!(fat == &disk[start..start + fat_len], "FAT copy {copy} differs");
+        }
+
+        let fs_info_start = driver.boot_record.fs_info_sector() as usize * bytes_per_sector;
+        let fs_info = FSInfo::read(&disk[fs_info_start..fs_info_start + 512]);
+
+        let free = (2..driver.cluster_end())
+            .filter(|&cluster| driver.read_fat_entry(cluster).unwrap() == 0)
+            .count();
+
+        assert_eq!(fs_info.free_cluster_count() as usize, free);
+        assert_eq!(fs_info.next_free_cluster(), driver.next_free_cluster);
+
+        let report = driver.fsck(false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+    }
+
+    #[test]
+    fn written_data_reads_back() {
+        let mut driver = formatted();
+        let bytes_per_cluster = driver.bytes_per_cluster();
+
+        let mut file = driver.create("/file.bin").unwrap();
+        assert_eq!(file.size(), 0);
+        check_allocation(&mut driver);
+
+        let data = pattern(5000);
+        assert_eq!(driver.write_file(&mut file, 0, &data).unwrap(), 5000);
+        // Overwrite across a sector boundary
+        driver.write_file(&mut file, 1000, &[0xEE; 100]).unwrap();
+        // Write past the end, leaving a gap
+        driver.write_file(&mut file, 9000, &data[..700]).unwrap();
+
+        let mut expected = data.clone();
+        expected[1000..1100].fill(0xEE);
+        expected.resize(9000, 0);
+        expected.extend_from_slice(&data[..700]);
+
+        assert_eq!(read_all(&mut driver, &file), expected);
+        assert_eq!(
+            cluster_count(&mut driver, &file),
+            9700usize.div_ceil(bytes_per_cluster)
+        );
+        check_allocation(&mut driver);
+
+        // The directory entry has the new size and start cluster
+        let reopened = driver.open("/file.bin").unwrap();
+        assert_eq!(reopened.size(), 9700);
+        assert_eq!(read_all(&mut driver, &reopened), expected);
+    }
+
+    #[test]
+    fn truncate_grows_with_zeros_and_shrinks() {
+        let mut driver = formatted();
+        let bytes_per_cluster = driver.bytes_per_cluster();
+
+        let mut file = driver.create("/file.bin").unwrap();
+        let data = pattern(3000);
+        driver.write_file(&mut file, 0, &data).unwrap();
+
+        // Stop part of the way into a cluster, so the rest of it still has the old data
+        driver.truncate(&mut file, 1000).unwrap();
+        assert_eq!(read_all(&mut driver, &file), data[..1000]);
+        assert_eq!(
+            cluster_count(&mut driver, &file),
+            1000usize.div_ceil(bytes_per_cluster)
+        );
+        check_allocation(&mut driver);
+
+        driver.truncate(&mut file, 20000).unwrap();
+        let mut expected = data[..1000].to_vec();
+        expected.resize(20000, 0);
+        assert_eq!(read_all(&mut driver, &file), expected);
+        assert_eq!(
+            cluster_count(&mut driver, &file),
+            20000usize.div_ceil(bytes_per_cluster)
+        );
+        check_allocation(&mut driver);
+
+        driver.truncate(&mut file, 0).unwrap();
+        assert_eq!(file.start_cluster, 0);
+        check_allocation(&mut driver);
+
+        // Growing an empty file allocates its first cluster too
+        driver.truncate(&mut file, 700).unwrap();
+        assert_eq!(read_all(&mut driver, &file), [0u8; 700]);
+        check_allocation(&mut driver);
+
+        let reopened = driver.open("/file.bin").unwrap();
+        assert_eq!(reopened.size(), 700);
+    }
+
+    #[test]
+    fn read_dir_stops_at_broken_chain() {
+        let mut driver = formatted();
+
+        let directory = driver.mkdir("/d").unwrap();
+        for i in 0..40 {
+            driver.create(&format!("/d/file{i}")).unwrap();
+        }
+
+        // A reserved value, which can't be the next cluster of a chain
+        driver
+            .write_fat_entry(directory.start_cluster, 0x0FFFFFF0)
+            .unwrap();
+
+        let entries: Vec<_> = driver.read_dir(directory).unwrap().take(1000).collect();
+        let errors = entries.iter().filter(|e| e.is_err()).count();
+
+        assert_eq!(errors, 1);
+        assert!(entries.last().unwrap().is_err());
+        assert!(entries.len() < 40);
+    }
+
+    #[test]
+    fn resolves_dot_segments_in_paths() {
+        for (path, expected) in [
+            ("/", &[][..]),
+            ("/a/b/c", &["a", "b", "c"][..]),
+            ("//a/./b/", &["a", "b"][..]),
+            ("/a/../b", &["b"][..]),
+            ("/a/b/../../c", &["c"][..]),
+            ("/a/b/c/../d/..", &["a", "b"][..]),
+            ("/../a/../../b", &["b"][..]),
+            ("/a/..", &[][..]),
+        ] {
+            let segments: Vec<_> = Driver::iter_path_segments(path).collect();
+            assert_eq!(segments, expected, "{path}");
+        }
+
+        let mut driver = formatted();
+        driver.mkdir("/a").unwrap();
+        driver.create("/a/file").unwrap();
+
+        assert!(driver.open("/a/../a/./file").is_ok());
+        assert!(driver.open("/../a/file").is_ok());
+        assert!(matches!(
+            driver.open("/a/file/../../file"),
+            Err(DriverError::PathNotFound)
+        ));
+    }
+
+    /// Changes the directory entries in the first sector of the root directory
+    fn edit_root_entries(driver: &mut Driver, edit: impl FnOnce(&mut [u8])) {
+        let sector = driver.data_sector_from_cluster(driver.boot_record.root_directory_cluster());
+
+        driver.read_data_sector(sector).unwrap();
+        edit(driver.data_buffer.as_slice_mut());
+        driver.write_data_sector(sector).unwrap();
+    }
+
+    /// The offset of the short entry with a name, and of the long name entries in front of it
+    fn find_entries(sector: &[u8], name: &[u8; 11]) -> (usize, Vec<usize>) {
+        let offset = (0..sector.len())
+            .step_by(32)
+            .find(|&offset| &sector[offset..offset + 11] == name)
+            .unwrap();
+        let long_entries = (0..offset)
+            .step_by(32)
+            .rev()
+            .take_while(|&offset| sector[offset + 11] == 0x0F)
+            .collect();
+
+        (offset, long_entries)
+    }
+
+    fn root_names(driver: &mut Driver) -> Vec<String> {
+        let root = driver.open_dir("/").unwrap();
+
+        driver
+            .read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ignores_long_names_that_belong_to_another_entry() {
+        let mut driver = formatted();
+        driver.create("/Long Name.txt").unwrap();
+        driver.create("/Other Long Name.txt").unwrap();
+
+        // Renamed by something that doesn't know about long names, which leaves them behind
+        edit_root_entries(&mut driver, |sector| {
+            let (offset, _) = find_entries(sector, b"LONGNA~1TXT");
+            sector[offset..offset + 11].copy_from_slice(b"SHORT   TXT");
+        });
+
+        assert_eq!(root_names(&mut driver), ["SHORT.TXT", "Other Long Name.txt"]);
+        assert!(matches!(
+            driver.open("/Long Name.txt"),
+            Err(DriverError::PathNotFound)
+        ));
+        assert!(driver.open("/short.txt").is_ok());
+
+        // Only the short entry is removed, the long name entries in front of it aren't its own
+        driver.unlink("/SHORT.TXT").unwrap();
+        edit_root_entries(&mut driver, |sector| {
+            let (_, long_entries) = find_entries(sector, b"OTHERL~1TXT");
+            let orphans = (0..long_entries.last().unwrap().saturating_sub(32))
+                .step_by(32)
+                .filter(|&offset| sector[offset + 11] == 0x0F && sector[offset] != 0xE5)
+                .count();
+            assert_eq!(orphans, 1);
+        });
+        assert_eq!(root_names(&mut driver), ["Other Long Name.txt"]);
+    }
+
+    #[test]
+    fn ignores_long_names_with_broken_ordinals() {
+        let mut driver = formatted();
+        driver.create("/A Name That Takes Two Entries").unwrap();
+        driver.create("/Long Name.txt").unwrap();
+        driver.create("/Other Long Name.txt").unwrap();
+
+        edit_root_entries(&mut driver, |sector| {
+            // Take the end of the name off the first entry's run
+            let (_, long_entries) = find_entries(sector, b"ANAMET~1   ");
+            assert_eq!(long_entries.len(), 3);
+            sector[*long_entries.last().unwrap()] &= !0x40;
+
+            // Leave a run without its short entry right in front of another run
+            let (offset, long_entries) = find_entries(sector, b"LONGNA~1TXT");
+            sector.copy_within(long_entries[0]..long_entries[0] + 32, offset);
+        });
+
+        assert_eq!(root_names(&mut driver), ["ANAMET~1", "Other Long Name.txt"]);
+        assert!(driver.open("/Other Long Name.txt").is_ok());
+    }
+}
@@ -27,7 +27,7 @@ fn main() {
     println!("File size: {}", file.size());
 
     let mut buffer = vec![0; file.size()];
-    let read = driver.read_file(file, 0, &mut buffer).unwrap();
+    let read = driver.read_file(&file, 0, &mut buffer).unwrap();
     buffer.truncate(read);
 
     let contents = String::from_utf8(buffer).unwrap();
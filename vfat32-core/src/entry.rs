@@ -1,11 +1,32 @@
 use core::str;
 
-use bin_tools::{read_u16_le, read_u32_le};
+use bin_tools::{read_u16_le, read_u32_le, write_u16_le, write_u32_le};
 
-use crate::read_padded_str;
+use crate::{
+    read_padded_str,
+    time::{DosDate, DosDateTime},
+};
 
 pub const DIRECTORY_ENTRY_SIZE: usize = 32;
 
+/// The first byte of an entry that has been deleted and can be reused
+pub const DELETED_ENTRY_MARKER: u8 = 0xE5;
+
+/// The attributes byte of a long file name entry
+pub const LONG_NAME_ATTRIBUTES: u8 = 0x0F;
+
+/// Set in the sequence number of the long file name entry that holds the end of the name,
+/// which is stored first
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+
+/// How many UTF-16 code units of the name each long file name entry holds
+pub const LONG_NAME_CHARS_PER_ENTRY: usize = 13;
+
+/// Set in the entry case field when the name part of the short name is lower case
+pub const LOWERCASE_NAME: u8 = 1 << 3;
+/// Set in the entry case field when the extension part of the short name is lower case
+pub const LOWERCASE_EXTENSION: u8 = 1 << 4;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum Attributes {
@@ -26,12 +47,20 @@ pub enum DirectoryEntry {
 impl DirectoryEntry {
     pub fn read(input: &[u8]) -> Self {
         // Attribute field, if it has all lower bits set, it is a long file name entry
-        if input[0x0B] == 0x0F {
+        if input[0x0B] == LONG_NAME_ATTRIBUTES {
             Self::LFN(LongFileNameEntry::read(input))
         } else {
             Self::Real(RealEntry::read(input))
         }
     }
+
+    /// Writes the entry into the first 32 bytes of `output`
+    pub fn write(&self, output: &mut [u8]) {
+        match self {
+            Self::LFN(entry) => entry.write(output),
+            Self::Real(entry) => entry.write(output),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -42,8 +71,12 @@ pub struct LongFileNameEntry {
     /// 6 - offset 0x0E
     /// 2 - offset 0x1C
     name: [u16; 13],
+    /// offset 0x0C, always 0
+    entry_type: u8,
     /// offset 0x0D
     short_name_checksum: u8,
+    /// offset 0x1A, always 0
+    first_cluster: u16,
 }
 
 impl LongFileNameEntry {
@@ -51,10 +84,36 @@ impl LongFileNameEntry {
         Self {
             sequence_number: input[0x00],
             name: Self::read_name(input),
+            entry_type: input[0x0C],
             short_name_checksum: input[0x0D],
+            first_cluster: read_u16_le(input, 0x1A),
+        }
+    }
+
+    /// Creates an entry holding 13 UTF-16 code units of a name. Unused units after the end of
+    /// the name should be 0x0000 for the first and 0xFFFF for the rest.
+    pub fn new(sequence_number: u8, name: [u16; 13], short_name_checksum: u8) -> Self {
+        Self {
+            sequence_number,
+            name,
+            entry_type: 0,
+            short_name_checksum,
+            first_cluster: 0,
         }
     }
 
+    /// Writes the entry into the first 32 bytes of `output`
+    pub fn write(&self, output: &mut [u8]) {
+        output[0x00] = self.sequence_number;
+        Self::write_name_part(output, 0x01, &self.name[0..5]);
+        output[0x0B] = LONG_NAME_ATTRIBUTES;
+        output[0x0C] = self.entry_type;
+        output[0x0D] = self.short_name_checksum;
+        Self::write_name_part(output, 0x0E, &self.name[5..11]);
+        write_u16_le(output, 0x1A, self.first_cluster);
+        Self::write_name_part(output, 0x1C, &self.name[11..13]);
+    }
+
     pub fn is_deleted(&self) -> bool {
         self.sequence_number == 0xE5
     }
@@ -63,10 +122,27 @@ impl LongFileNameEntry {
         self.sequence_number
     }
 
+    pub fn set_sequence_number(&mut self, sequence_number: u8) {
+        self.sequence_number = sequence_number;
+    }
+
     pub fn short_name_checksum(&self) -> u8 {
         self.short_name_checksum
     }
 
+    pub fn set_short_name_checksum(&mut self, short_name_checksum: u8) {
+        self.short_name_checksum = short_name_checksum;
+    }
+
+    /// The raw UTF-16 code units of this part of the name, including the terminator and padding
+    pub fn name_units(&self) -> &[u16; 13] {
+        &self.name
+    }
+
+    pub fn set_name_units(&mut self, name: [u16; 13]) {
+        self.name = name;
+    }
+
     pub fn name(&self) -> impl Iterator<Item = char> {
         char::decode_utf16(self.name.into_iter().filter(|v| *v != 0xFFFF)).filter_map(|e| e.ok())
     }
@@ -94,6 +170,12 @@ impl LongFileNameEntry {
 
         buffer
     }
+
+    fn write_name_part(output: &mut [u8], offset: usize, part: &[u16]) {
+        for (i, c) in part.iter().enumerate() {
+            write_u16_le(output, offset + i * 2, *c);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -145,10 +227,113 @@ impl RealEntry {
         }
     }
 
+    /// Creates an empty entry with the given short name, as stored on disk (space padded, upper
+    /// case)
+    pub fn new(short_name: [u8; 11], attributes: u8) -> Self {
+        Self {
+            short_name,
+            attributes,
+            entry_case: 0,
+            creation_time_ms: 0,
+            creation_time_whole: 0,
+            creation_date: 0,
+            access_date: 0,
+            cluster_number: 0,
+            modified_time: 0,
+            modified_date: 0,
+            file_size: 0,
+        }
+    }
+
+    /// Writes the entry into the first 32 bytes of `output`
+    pub fn write(&self, output: &mut [u8]) {
+        output[0x00..0x0B].copy_from_slice(&self.short_name);
+        output[0x0B] = self.attributes;
+        output[0x0C] = self.entry_case;
+        output[0x0D] = self.creation_time_ms;
+        write_u16_le(output, 0x0E, self.creation_time_whole);
+        write_u16_le(output, 0x10, self.creation_date);
+        write_u16_le(output, 0x12, self.access_date);
+        write_u16_le(output, 0x14, (self.cluster_number >> 16) as u16);
+        write_u16_le(output, 0x16, self.modified_time);
+        write_u16_le(output, 0x18, self.modified_date);
+        write_u16_le(output, 0x1A, self.cluster_number as u16);
+        write_u32_le(output, 0x1C, self.file_size);
+    }
+
     pub fn start_cluster(&self) -> u32 {
         self.cluster_number
     }
 
+    pub fn set_start_cluster(&mut self, cluster: u32) {
+        self.cluster_number = cluster;
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    pub fn set_attributes(&mut self, attributes: u8) {
+        self.attributes = attributes;
+    }
+
+    pub fn entry_case(&self) -> u8 {
+        self.entry_case
+    }
+
+    /// Units of 10 ms to add to the two second resolution creation time, 0 to 199
+    pub fn creation_time_ms(&self) -> u8 {
+        self.creation_time_ms
+    }
+
+    pub fn set_creation_time_ms(&mut self, creation_time_ms: u8) {
+        self.creation_time_ms = creation_time_ms;
+    }
+
+    pub fn creation_time(&self) -> u16 {
+        self.creation_time_whole
+    }
+
+    pub fn set_creation_time(&mut self, creation_time: u16) {
+        self.creation_time_whole = creation_time;
+    }
+
+    pub fn creation_date(&self) -> u16 {
+        self.creation_date
+    }
+
+    pub fn set_creation_date(&mut self, creation_date: u16) {
+        self.creation_date = creation_date;
+    }
+
+    pub fn access_date(&self) -> u16 {
+        self.access_date
+    }
+
+    pub fn set_access_date(&mut self, access_date: u16) {
+        self.access_date = access_date;
+    }
+
+    pub fn modified_time(&self) -> u16 {
+        self.modified_time
+    }
+
+    pub fn set_modified_time(&mut self, modified_time: u16) {
+        self.modified_time = modified_time;
+    }
+
+    pub fn modified_date(&self) -> u16 {
+        self.modified_date
+    }
+
+    pub fn set_modified_date(&mut self, modified_date: u16) {
+        self.modified_date = modified_date;
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.short_name[0] == DELETED_ENTRY_MARKER
+    }
+
     pub fn is_dir(&self) -> bool {
         (self.attributes & (Attributes::Directory as u8)) != 0
     }
@@ -160,6 +345,11 @@ impl RealEntry {
         not_dir & not_label
     }
 
+    /// The entry holds the volume label rather than a file or directory
+    pub fn is_volume_label(&self) -> bool {
+        (self.attributes & (Attributes::VolumeId as u8)) != 0
+    }
+
     pub fn is_empty(&self) -> bool {
         self.short_name[0] == 0
     }
@@ -173,11 +363,19 @@ impl RealEntry {
     }
 
     pub fn is_name_lowercase(&self) -> bool {
-        (&self.entry_case & (1 << 3)) != 0
+        (self.entry_case & LOWERCASE_NAME) != 0
     }
 
     pub fn is_extension_lowercase(&self) -> bool {
-        (&self.entry_case & (1 << 4)) != 0
+        (self.entry_case & LOWERCASE_EXTENSION) != 0
+    }
+
+    pub fn set_entry_case(&mut self, entry_case: u8) {
+        self.entry_case = entry_case;
+    }
+
+    pub fn set_short_name(&mut self, short_name: [u8; 11]) {
+        self.short_name = short_name;
     }
 
     pub fn short_name(&self) -> &str {
@@ -187,4 +385,63 @@ impl RealEntry {
     pub fn file_size(&self) -> u32 {
         self.file_size
     }
+
+    pub fn set_file_size(&mut self, size: u32) {
+        self.file_size = size;
+    }
+
+    /// The creation date and time, including the 10 ms units, or None if it isn't set
+    pub fn created(&self) -> Option<DosDateTime> {
+        DosDateTime::from_raw(
+            self.creation_date,
+            self.creation_time_whole,
+            self.creation_time_ms,
+        )
+    }
+
+    pub fn modified(&self) -> Option<DosDateTime> {
+        DosDateTime::from_raw(self.modified_date, self.modified_time, 0)
+    }
+
+    /// Only the date of the last access is stored
+    pub fn accessed(&self) -> Option<DosDate> {
+        DosDate::from_raw(self.access_date)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    proptest! {
+        #[test]
+        fn real_entry_round_trips(
+            mut bytes in vec(any::<u8>(), 32),
+            attributes in any::<u8>().prop_filter("LFN attributes", |&a| a != LONG_NAME_ATTRIBUTES),
+        ) {
+            bytes[0x0B] = attributes;
+
+            let entry = DirectoryEntry::read(&bytes);
+            prop_assert!(matches!(entry, DirectoryEntry::Real(_)));
+
+            let mut output = [0u8; 32];
+            entry.write(&mut output);
+
+            prop_assert_eq!(&output[..], &bytes[..]);
+        }
+
+        #[test]
+        fn long_file_name_entry_round_trips(mut bytes in vec(any::<u8>(), 32)) {
+            bytes[0x0B] = LONG_NAME_ATTRIBUTES;
+
+            let entry = DirectoryEntry::read(&bytes);
+            prop_assert!(matches!(entry, DirectoryEntry::LFN(_)));
+
+            let mut output = [0u8; 32];
+            entry.write(&mut output);
+
+            prop_assert_eq!(&output[..], &bytes[..]);
+        }
+    }
 }
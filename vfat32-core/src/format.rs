@@ -0,0 +1,303 @@
+use core::fmt::{Debug, Display};
+
+use block_device::{BlockDevice, MAX_BLOCK_SIZE};
+
+use crate::{
+    entry::{Attributes, RealEntry},
+    fs_info::FSInfo,
+    name::SHORT_NAME_PUNCTUATION,
+    record::BootRecord,
+};
+
+/// The sector that the backup of the FS info sector goes in, right after the backup boot sector
+const BACKUP_FS_INFO_SECTOR: u16 = 7;
+
+/// FAT32 can't address more clusters than this
+const MAX_CLUSTERS: u64 = 0x0FFFFFF5;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+/// The label a volume without a label has
+const NO_LABEL: [u8; 11] = *b"NO NAME    ";
+
+#[derive(Debug, Clone, Copy)]
+pub enum FormatError<E> {
+    /// The underlying device returned an error
+    Device(E),
+    /// Sectors have to be a power of two between 512 and 4096 bytes
+    UnsupportedBlockSize(u64),
+    /// Sectors per cluster has to be a power of two, and clusters can be at most 32 KiB
+    InvalidClusterSize(u8),
+    /// There have to be at least 9 reserved sectors for the boot sector, FS info sector and their
+    /// backups
+    TooFewReservedSectors(u16),
+    /// There has to be at least one FAT
+    NoFileAllocationTables,
+    /// The device doesn't have room for the reserved sectors, the FATs and the data clusters
+    TooSmall,
+    /// The device has more sectors or clusters than FAT32 can address
+    TooLarge,
+}
+
+impl<E: Debug> Display for FormatError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => {
+                write!(f, "Block device error: {e:?}")
+            }
+            Self::UnsupportedBlockSize(size) => {
+                write!(
+                    f,
+                    "Unsupported sector size of {size} bytes, it has to be a power of two between 512 and {MAX_BLOCK_SIZE}."
+                )
+            }
+            Self::InvalidClusterSize(sectors) => {
+                write!(
+                    f,
+                    "Invalid cluster size of {sectors} sectors, it has to be a power of two and at most 32 KiB."
+                )
+            }
+            Self::TooFewReservedSectors(sectors) => {
+                write!(
+                    f,
+                    "Too few reserved sectors, there are {sectors} but at least {} are needed.",
+                    BACKUP_FS_INFO_SECTOR + 2
+                )
+            }
+            Self::NoFileAllocationTables => {
+                write!(f, "There has to be at least one FAT.")
+            }
+            Self::TooSmall => {
+                write!(f, "The device is too small for a FAT32 filesystem.")
+            }
+            Self::TooLarge => {
+                write!(f, "The device is too large for a FAT32 filesystem.")
+            }
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for FormatError<E> {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FormatOptions {
+    /// Space padded and upper case, see `volume_label`
+    pub volume_label: [u8; 11],
+    /// 0 picks a cluster size based on the size of the device, like mkfs.fat does
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_file_allocation_tables: u8,
+    pub volume_serial_number: u32,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            volume_label: NO_LABEL,
+            sectors_per_cluster: 0,
+            reserved_sectors: 32,
+            num_file_allocation_tables: 2,
+            volume_serial_number: 0,
+        }
+    }
+}
+
+/// Converts a volume label to the form stored on disk: upper case and padded with spaces.
+/// Returns None if it is longer than 11 characters or has characters that aren't allowed.
+pub fn volume_label(label: &str) -> Option<[u8; 11]> {
+    if label.len() > 11 {
+        return None;
+    }
+
+    let mut volume_label = [b' '; 11];
+
+    for (i, c) in label.bytes().enumerate() {
+        if !(c.is_ascii_alphanumeric() || c == b' ' || SHORT_NAME_PUNCTUATION.contains(&c)) {
+            return None;
+        }
+
+        volume_label[i] = c.to_ascii_uppercase();
+    }
+
+    Some(volume_label)
+}
+
+/// Creates an empty FAT32 filesystem covering the whole device.
+///
+/// This writes the boot record and its backup, the FS info sector and its backup, zeroed FATs
+/// with the media descriptor and end of chain entries, and an empty root directory in cluster 2.
+/// Returns the boot record that was written.
+pub fn format<D: BlockDevice>(
+    device: &mut D,
+    options: &FormatOptions,
+) -> Result<BootRecord, FormatError<D::Error>> {
+    let bytes_per_sector = device.block_size();
+
+    if !(512..=MAX_BLOCK_SIZE as u64).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+    {
+        return Err(FormatError::UnsupportedBlockSize(bytes_per_sector));
+    }
+
+    let num_sectors = device.num_blocks();
+
+    if num_sectors > u32::MAX as u64 {
+        return Err(FormatError::TooLarge);
+    }
+
+    if options.reserved_sectors <= BACKUP_FS_INFO_SECTOR + 1 {
+        return Err(FormatError::TooFewReservedSectors(options.reserved_sectors));
+    }
+
+    if options.num_file_allocation_tables == 0 {
+        return Err(FormatError::NoFileAllocationTables);
+    }
+
+    let sectors_per_cluster = match options.sectors_per_cluster {
+        0 => default_sectors_per_cluster(num_sectors, bytes_per_sector),
+        n => n,
+    };
+
+    if !sectors_per_cluster.is_power_of_two()
+        || sectors_per_cluster as u64 * bytes_per_sector > 32 * 1024
+    {
+        return Err(FormatError::InvalidClusterSize(sectors_per_cluster));
+    }
+
+    let (sectors_per_fat, cluster_count) = fat_size(
+        num_sectors,
+        bytes_per_sector,
+        options.reserved_sectors as u64,
+        options.num_file_allocation_tables as u64,
+        sectors_per_cluster as u64,
+    )
+    .ok_or(FormatError::TooSmall)?;
+
+    if cluster_count > MAX_CLUSTERS {
+        return Err(FormatError::TooLarge);
+    }
+
+    let mut boot_record = BootRecord::new();
+    boot_record.set_bytes_per_sector(bytes_per_sector as u16);
+    boot_record.set_sectors_per_cluster(sectors_per_cluster);
+    boot_record.set_num_reserved_sectors(options.reserved_sectors);
+    boot_record.set_num_file_allocation_tables(options.num_file_allocation_tables);
+    boot_record.set_num_sectors(num_sectors as u32);
+    boot_record.set_sectors_per_fat(sectors_per_fat as u32);
+    boot_record.set_volume_serial_number(options.volume_serial_number);
+    boot_record.set_volume_label(options.volume_label);
+
+    let mut sector = [0u8; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..bytes_per_sector as usize];
+
+    // Start from a clean reserved area and clean FATs
+    let fat_end = boot_record.first_data_sector();
+
+    for lba in 0..fat_end {
+        device
+            .write_block(lba, sector)
+            .map_err(FormatError::Device)?;
+    }
+
+    boot_record.write(sector);
+    device.write_block(0, sector).map_err(FormatError::Device)?;
+    device
+        .write_block(boot_record.backup_boot_sector(), sector)
+        .map_err(FormatError::Device)?;
+
+    // The root directory takes up the first cluster
+    let fs_info = FSInfo::new(cluster_count as u32 - 1, 3);
+
+    sector.fill(0);
+    fs_info.write(sector);
+    device
+        .write_block(boot_record.fs_info_sector(), sector)
+        .map_err(FormatError::Device)?;
+    device
+        .write_block(BACKUP_FS_INFO_SECTOR as u64, sector)
+        .map_err(FormatError::Device)?;
+
+    // The first two FAT entries hold the media descriptor and an end of chain marker, and the
+    // third is the end of the root directory's chain
+    sector.fill(0);
+    sector[0..4]
+        .copy_from_slice(&(0x0FFFFF00 | boot_record.media_descriptor() as u32).to_le_bytes());
+    sector[4..8].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    sector[8..12].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+
+    for fat in 0..options.num_file_allocation_tables as u64 {
+        let fat_start = boot_record.first_fat_sector() + fat * sectors_per_fat;
+
+        device
+            .write_block(fat_start, sector)
+            .map_err(FormatError::Device)?;
+    }
+
+    // An empty root directory, with the volume label entry if there is one
+    let root_start = boot_record.first_data_sector();
+
+    for i in 0..sectors_per_cluster as u64 {
+        sector.fill(0);
+
+        if i == 0 && options.volume_label != NO_LABEL {
+            RealEntry::new(options.volume_label, Attributes::VolumeId as u8).write(sector);
+        }
+
+        device
+            .write_block(root_start + i, sector)
+            .map_err(FormatError::Device)?;
+    }
+
+    Ok(boot_record)
+}
+
+/// The cluster sizes Microsoft's format uses for FAT32 volumes
+fn default_sectors_per_cluster(num_sectors: u64, bytes_per_sector: u64) -> u8 {
+    let volume_bytes = num_sectors * bytes_per_sector;
+
+    let cluster_bytes = if volume_bytes <= 260 * MIB {
+        512
+    } else if volume_bytes <= 8 * GIB {
+        4096
+    } else if volume_bytes <= 16 * GIB {
+        8192
+    } else if volume_bytes <= 32 * GIB {
+        16384
+    } else {
+        32768
+    };
+
+    (cluster_bytes / bytes_per_sector).max(1) as u8
+}
+
+/// Works out how many sectors each FAT needs, which in turn decides how many clusters are left
+/// for data. Returns the sectors per FAT and the cluster count.
+fn fat_size(
+    num_sectors: u64,
+    bytes_per_sector: u64,
+    reserved_sectors: u64,
+    num_fats: u64,
+    sectors_per_cluster: u64,
+) -> Option<(u64, u64)> {
+    let mut sectors_per_fat = 1;
+
+    loop {
+        let data_sectors =
+            num_sectors.checked_sub(reserved_sectors + num_fats * sectors_per_fat)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        // Two entries at the start of the FAT are reserved
+        let needed = ((cluster_count + 2) * 4).div_ceil(bytes_per_sector);
+
+        if needed <= sectors_per_fat {
+            return if cluster_count == 0 {
+                None
+            } else {
+                Some((sectors_per_fat, cluster_count))
+            };
+        }
+
+        sectors_per_fat = needed;
+    }
+}
@@ -1,4 +1,9 @@
-use bin_tools::read_u32_le;
+use bin_tools::{read_u32_le, write_u32_le};
+
+use crate::{read_padded_str, RawBytes};
+
+/// Stored in the free cluster count or next free cluster fields when the value is not known
+pub const FS_INFO_UNKNOWN: u32 = 0xFFFFFFFF;
 
 const FS_INFO_FIRST_SIGNATURE: u32 = 0x41615252;
 const FS_INFO_SECOND_SIGNATURE: u32 = 0x61417272;
@@ -8,30 +13,95 @@ const FS_INFO_END_SIGNATURE: u32 = 0xAA550000;
 pub struct FSInfo {
     /// offset 0x000
     first_signature: u32,
+    /// offset 0x004
+    reserved_1: RawBytes<480>,
     /// offset 0x1E4
     second_signature: u32,
     /// offset 0x1E8
     last_free_cluster_count: u32,
     /// offset 0x1EC
     next_available_cluster: u32,
+    /// offset 0x1F0
+    reserved_2: [u8; 12],
     /// offset 0x1FC
     end_signature: u32,
 }
 
 impl FSInfo {
+    /// Creates a valid FS info sector with the given hints, either of which can be
+    /// FS_INFO_UNKNOWN
+    pub fn new(free_cluster_count: u32, next_free_cluster: u32) -> Self {
+        Self {
+            first_signature: FS_INFO_FIRST_SIGNATURE,
+            reserved_1: RawBytes([0; 480]),
+            second_signature: FS_INFO_SECOND_SIGNATURE,
+            last_free_cluster_count: free_cluster_count,
+            next_available_cluster: next_free_cluster,
+            reserved_2: [0; 12],
+            end_signature: FS_INFO_END_SIGNATURE,
+        }
+    }
+
     pub fn read(buffer: &[u8]) -> Self {
         Self {
             first_signature: read_u32_le(buffer, 0x000),
+            reserved_1: RawBytes(read_padded_str(buffer, 0x004)),
             second_signature: read_u32_le(buffer, 0x1E4),
             last_free_cluster_count: read_u32_le(buffer, 0x1E8),
             next_available_cluster: read_u32_le(buffer, 0x1EC),
+            reserved_2: read_padded_str(buffer, 0x1F0),
             end_signature: read_u32_le(buffer, 0x1FC),
         }
     }
 
+    /// Writes the sector into the first 512 bytes of `buffer`
+    pub fn write(&self, buffer: &mut [u8]) {
+        write_u32_le(buffer, 0x000, self.first_signature);
+        buffer[0x004..0x1E4].copy_from_slice(&self.reserved_1.0);
+        write_u32_le(buffer, 0x1E4, self.second_signature);
+        write_u32_le(buffer, 0x1E8, self.last_free_cluster_count);
+        write_u32_le(buffer, 0x1EC, self.next_available_cluster);
+        buffer[0x1F0..0x1FC].copy_from_slice(&self.reserved_2);
+        write_u32_le(buffer, 0x1FC, self.end_signature);
+    }
+
     pub fn is_valid(&self) -> bool {
         (self.first_signature == FS_INFO_FIRST_SIGNATURE)
             & (self.second_signature == FS_INFO_SECOND_SIGNATURE)
             & (self.end_signature == FS_INFO_END_SIGNATURE)
     }
+
+    /// The last known number of free clusters, or FS_INFO_UNKNOWN
+    pub fn free_cluster_count(&self) -> u32 {
+        self.last_free_cluster_count
+    }
+
+    pub fn set_free_cluster_count(&mut self, count: u32) {
+        self.last_free_cluster_count = count;
+    }
+
+    /// A hint for where to start looking for free clusters, or FS_INFO_UNKNOWN
+    pub fn next_free_cluster(&self) -> u32 {
+        self.next_available_cluster
+    }
+
+    pub fn set_next_free_cluster(&mut self, cluster: u32) {
+        self.next_available_cluster = cluster;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    proptest! {
+        #[test]
+        fn round_trips(sector in vec(any::<u8>(), 512)) {
+            let mut output = [0u8; 512];
+            FSInfo::read(&sector).write(&mut output);
+
+            prop_assert_eq!(&output[..], &sector[..]);
+        }
+    }
 }
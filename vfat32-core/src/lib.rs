@@ -1,8 +1,11 @@
 #![no_std]
 
 pub mod entry;
+pub mod format;
 pub mod fs_info;
+pub mod name;
 pub mod record;
+pub mod time;
 
 use core::fmt::Debug;
 use core::fmt::Display;
@@ -10,6 +13,10 @@ use core::fmt::Display;
 #[derive(Debug, Clone, Copy)]
 pub enum Error {
     BufferSizeTooSmall(u32),
+    /// The name can't be used for a file or directory
+    InvalidName,
+    /// Every numeric tail for a short name alias is already taken
+    ShortNamesExhausted,
 }
 
 impl Display for Error {
@@ -18,10 +25,29 @@ impl Display for Error {
             Self::BufferSizeTooSmall(size) => {
                 write!(f, "Buffer size too small, was only {size} bytes.")
             }
+            Self::InvalidName => {
+                write!(f, "Invalid file name.")
+            }
+            Self::ShortNamesExhausted => {
+                write!(f, "No unused short name alias is left.")
+            }
         }
     }
 }
 
+impl core::error::Error for Error {}
+
+/// Bytes that are kept as-is, like reserved areas and boot code, which only show their length
+/// when debug printed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RawBytes<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Debug for RawBytes<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[{N} bytes]")
+    }
+}
+
 fn read_padded_str<const N: usize>(buffer: &[u8], offset: usize) -> [u8; N] {
     let mut label = [0; N];
 
@@ -0,0 +1,419 @@
+use crate::{
+    entry::{
+        LongFileNameEntry, LAST_LONG_ENTRY, LONG_NAME_CHARS_PER_ENTRY, LOWERCASE_EXTENSION,
+        LOWERCASE_NAME,
+    },
+    Error,
+};
+
+/// Punctuation that is allowed in a short name besides letters and digits
+pub(crate) const SHORT_NAME_PUNCTUATION: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Converts `name` into an on-disk 8.3 short name if it can be stored as one without losing
+/// anything: at most 8 characters before the dot and 3 after it, only characters that are
+/// valid in short names, and each part entirely upper or lower case.
+///
+/// Returns the space padded, upper case short name and the entry case bits that bring back
+/// the original case.
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let bytes = name.as_bytes();
+
+    let (base, extension) = match bytes.iter().position(|&b| b == b'.') {
+        Some(dot) => (&bytes[..dot], &bytes[dot + 1..]),
+        None => (bytes, &[][..]),
+    };
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    // A trailing dot would not survive the round trip
+    if extension.is_empty() && base.len() != bytes.len() {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut entry_case = 0;
+
+    if !copy_part(base, &mut short_name[..8], &mut entry_case, LOWERCASE_NAME) {
+        return None;
+    }
+
+    if !copy_part(
+        extension,
+        &mut short_name[8..],
+        &mut entry_case,
+        LOWERCASE_EXTENSION,
+    ) {
+        return None;
+    }
+
+    Some((short_name, entry_case))
+}
+
+fn copy_part(part: &[u8], output: &mut [u8], entry_case: &mut u8, lowercase_bit: u8) -> bool {
+    let has_upper = part.iter().any(|b| b.is_ascii_uppercase());
+    let has_lower = part.iter().any(|b| b.is_ascii_lowercase());
+
+    if has_upper && has_lower {
+        return false;
+    }
+
+    for (i, &b) in part.iter().enumerate() {
+        if !(b.is_ascii_alphanumeric() || SHORT_NAME_PUNCTUATION.contains(&b)) {
+            return false;
+        }
+
+        output[i] = b.to_ascii_uppercase();
+    }
+
+    if has_lower {
+        *entry_case |= lowercase_bit;
+    }
+
+    true
+}
+
+/// The most UTF-16 code units a long file name can have
+pub const MAX_LONG_NAME_LEN: usize = 255;
+
+/// Numeric tails go from ~1 up to this
+const MAX_NUMERIC_TAIL: u32 = 999999;
+
+/// Characters that can't appear in long names, besides control characters
+const INVALID_LONG_NAME_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// How a name is stored in a directory
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedName {
+    short_name: [u8; 11],
+    entry_case: u8,
+    /// Names that don't fit in the short name entry by themselves are stored in long file name
+    /// entries in front of it, and the short name is an alias
+    long_name: Option<LongFileName>,
+}
+
+impl EncodedName {
+    pub fn short_name(&self) -> &[u8; 11] {
+        &self.short_name
+    }
+
+    pub fn entry_case(&self) -> u8 {
+        self.entry_case
+    }
+
+    pub fn long_name(&self) -> Option<&LongFileName> {
+        self.long_name.as_ref()
+    }
+
+    /// The number of directory entries the name takes up, including the short name entry
+    pub fn entry_count(&self) -> usize {
+        self.long_name
+            .map_or(0, |long_name| long_name.entry_count())
+            + 1
+    }
+
+    /// The long file name entries in the order they are stored, which go right before the short
+    /// name entry
+    pub fn long_entries(&self) -> impl Iterator<Item = LongFileNameEntry> + '_ {
+        self.long_name
+            .iter()
+            .flat_map(|long_name| long_name.entries(&self.short_name))
+    }
+}
+
+/// Works out how to store `name` in a directory. `exists` is called with candidate short names
+/// and returns whether the directory already has an entry with that short name.
+///
+/// Names that fit in 8.3 are stored as just a short name when possible. Others get long file
+/// name entries and a short name alias made from the basis name, with a `~N` numeric tail if the
+/// basis name lost information or is taken.
+pub fn encode_name<E: From<Error>>(
+    name: &str,
+    mut exists: impl FnMut(&[u8; 11]) -> Result<bool, E>,
+) -> Result<EncodedName, E> {
+    if let Some((short_name, entry_case)) = exact_short_name(name) {
+        if !exists(&short_name)? {
+            return Ok(EncodedName {
+                short_name,
+                entry_case,
+                long_name: None,
+            });
+        }
+    }
+
+    let long_name = LongFileName::new(name).ok_or(Error::InvalidName)?;
+    let (basis, lossy) = basis_name(name);
+
+    if !lossy && !exists(&basis)? {
+        return Ok(EncodedName {
+            short_name: basis,
+            entry_case: 0,
+            long_name: Some(long_name),
+        });
+    }
+
+    for n in 1..=MAX_NUMERIC_TAIL {
+        let short_name = with_numeric_tail(&basis, n);
+
+        if !exists(&short_name)? {
+            return Ok(EncodedName {
+                short_name,
+                entry_case: 0,
+                long_name: Some(long_name),
+            });
+        }
+    }
+
+    Err(Error::ShortNamesExhausted.into())
+}
+
+/// A name encoded as UTF-16, ready to be split into long file name entries
+#[derive(Debug, Clone, Copy)]
+pub struct LongFileName {
+    units: [u16; MAX_LONG_NAME_LEN],
+    len: usize,
+}
+
+impl LongFileName {
+    /// Returns None if the name is empty, `.` or `..`, too long, ends with a period or space,
+    /// or has characters that aren't allowed
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name == "." || name == ".." || name.ends_with(['.', ' ']) {
+            return None;
+        }
+
+        let mut units = [0u16; MAX_LONG_NAME_LEN];
+        let mut len = 0;
+
+        for c in name.chars() {
+            if c < ' ' || INVALID_LONG_NAME_CHARS.contains(&c) {
+                return None;
+            }
+
+            let mut buffer = [0u16; 2];
+
+            for unit in c.encode_utf16(&mut buffer) {
+                if len == MAX_LONG_NAME_LEN {
+                    return None;
+                }
+
+                units[len] = *unit;
+                len += 1;
+            }
+        }
+
+        Some(Self { units, len })
+    }
+
+    pub fn as_utf16(&self) -> &[u16] {
+        &self.units[..self.len]
+    }
+
+    /// The number of long file name entries needed to store the name
+    pub fn entry_count(&self) -> usize {
+        self.len.div_ceil(LONG_NAME_CHARS_PER_ENTRY)
+    }
+
+    /// Splits the name into entries for a file with the given short name, in the order they
+    /// are stored: the entry with the end of the name, flagged with LAST_LONG_ENTRY, comes
+    /// first
+    pub fn entries(&self, short_name: &[u8; 11]) -> impl Iterator<Item = LongFileNameEntry> + '_ {
+        let checksum = short_name_checksum(short_name);
+        let count = self.entry_count();
+
+        (1..=count).rev().map(move |ordinal| {
+            let start = (ordinal - 1) * LONG_NAME_CHARS_PER_ENTRY;
+            let part = &self.units[start..self.len.min(start + LONG_NAME_CHARS_PER_ENTRY)];
+
+            let mut name = [0xFFFF; LONG_NAME_CHARS_PER_ENTRY];
+            name[..part.len()].copy_from_slice(part);
+
+            if part.len() < LONG_NAME_CHARS_PER_ENTRY {
+                name[part.len()] = 0;
+            }
+
+            let mut sequence_number = ordinal as u8;
+
+            if ordinal == count {
+                sequence_number |= LAST_LONG_ENTRY;
+            }
+
+            LongFileNameEntry::new(sequence_number, name, checksum)
+        })
+    }
+}
+
+/// The upper half of code page 437, the OEM code page short names are usually stored in. The
+/// lower half is ASCII.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Set as the first byte of a short name whose first character really is 0xE5, which would
+/// otherwise mark the entry as deleted
+const ESCAPED_E5: u8 = 0x05;
+
+/// Decodes one byte of a short name
+pub fn oem_char(b: u8) -> char {
+    if b.is_ascii() {
+        b as char
+    } else {
+        CP437_HIGH[(b - 0x80) as usize]
+    }
+}
+
+/// The characters of a short name as it is displayed: without the padding, with a `.` before the
+/// extension if there is one, and each part lower case if the entry case bits say so
+pub fn short_name_chars(short_name: &[u8; 11], entry_case: u8) -> impl Iterator<Item = char> + '_ {
+    let has_extension = short_name[8] != b' ';
+
+    let base = short_name[..8].iter().enumerate().map(move |(i, &b)| {
+        let b = if i == 0 && b == ESCAPED_E5 { 0xE5 } else { b };
+        (b, entry_case & LOWERCASE_NAME != 0)
+    });
+    let dot = has_extension.then_some((b'.', false));
+    let extension = short_name[8..]
+        .iter()
+        .map(move |&b| (b, entry_case & LOWERCASE_EXTENSION != 0));
+
+    base.chain(dot)
+        .chain(extension)
+        .filter(|&(b, _)| b != b' ')
+        .map(|(b, lowercase)| {
+            if lowercase {
+                oem_char(b.to_ascii_lowercase())
+            } else {
+                oem_char(b)
+            }
+        })
+}
+
+/// Compares long file names the way Windows does, ignoring case with simple case folding, where
+/// each character is compared as one other character
+pub fn long_names_equal(a: &str, b: &str) -> bool {
+    a.chars().map(fold_case).eq(b.chars().map(fold_case))
+}
+
+/// Going through upper case first also folds characters like 'ſ' and 'ς' into the same lower case
+/// character as their upper case version. Characters whose mappings expand into several, like
+/// 'ß', stay as they are.
+fn fold_case(c: char) -> char {
+    let upper = c.to_uppercase();
+
+    if upper.len() != 1 {
+        return c;
+    }
+
+    let upper = upper.last().unwrap_or(c);
+    let lower = upper.to_lowercase();
+
+    if lower.len() != 1 {
+        return c;
+    }
+
+    lower.last().unwrap_or(c)
+}
+
+/// The checksum of a short name that long file name entries store to tie them to their short
+/// name entry
+pub fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Makes the basis for a short name alias out of a long name: upper case, without spaces or
+/// leading periods, with characters that are not valid in short names replaced by underscores,
+/// and cut down to 8.3 around the last period.
+///
+/// Also returns whether any of this lost information, other than the case.
+pub fn basis_name(name: &str) -> ([u8; 11], bool) {
+    let trimmed = name.trim_start_matches(['.', ' ']);
+    let mut lossy = trimmed.len() != name.len();
+
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+
+    let mut short_name = [b' '; 11];
+
+    lossy |= basis_part(base, &mut short_name[..8]);
+    lossy |= basis_part(extension, &mut short_name[8..]);
+
+    if short_name[0] == b' ' {
+        short_name[0] = b'_';
+        lossy = true;
+    }
+
+    (short_name, lossy)
+}
+
+/// Appends `~n` to the name part of a basis name, cutting the name part short to make room
+pub fn with_numeric_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut num_digits = 0;
+    let mut remaining = n;
+
+    loop {
+        digits[num_digits] = b'0' + (remaining % 10) as u8;
+        num_digits += 1;
+        remaining /= 10;
+
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    let base_len = basis[..8].iter().position(|&c| c == b' ').unwrap_or(8);
+    let keep = base_len.min(8 - 1 - num_digits);
+
+    let mut short_name = *basis;
+    short_name[keep..8].fill(b' ');
+    short_name[keep] = b'~';
+
+    for i in 0..num_digits {
+        short_name[keep + 1 + i] = digits[num_digits - 1 - i];
+    }
+
+    short_name
+}
+
+/// Converts part of a long name for basis_name, returning whether it lost information
+fn basis_part(part: &str, output: &mut [u8]) -> bool {
+    let mut lossy = false;
+    let mut len = 0;
+
+    for c in part.chars() {
+        if c == ' ' || c == '.' {
+            lossy = true;
+            continue;
+        }
+
+        if len == output.len() {
+            lossy = true;
+            break;
+        }
+
+        // Anything outside of ASCII is replaced, `c as u8` would keep only its low byte
+        output[len] = if c.is_ascii()
+            && (c.is_ascii_alphanumeric() || SHORT_NAME_PUNCTUATION.contains(&(c as u8)))
+        {
+            c.to_ascii_uppercase() as u8
+        } else {
+            lossy = true;
+            b'_'
+        };
+
+        len += 1;
+    }
+
+    lossy
+}
@@ -1,9 +1,53 @@
-use bin_tools::{read_u16_le, read_u32_le};
+use bin_tools::{read_u16_le, read_u32_le, write_u16_le, write_u32_le};
 
-use crate::read_padded_str;
+use crate::{read_padded_str, RawBytes};
+
+/// The last two bytes of a boot sector
+pub const BOOT_SIGNATURE: u16 = 0xAA55;
+
+/// The extended boot signature that says the serial number, label and system identifier are
+/// present
+pub const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
+
+/// Volumes with fewer clusters than this are FAT12
+pub const MAX_FAT12_CLUSTERS: u32 = 4085;
+/// Volumes with fewer clusters than this, and at least MAX_FAT12_CLUSTERS, are FAT16
+pub const MAX_FAT16_CLUSTERS: u32 = 65525;
+
+/// Where the extended boot record starts on FAT12 and FAT16 volumes. On FAT32 it comes after the
+/// FAT32 fields of the BIOS parameter block, at 0x40.
+const FAT16_EXTENDED_BOOT_RECORD: usize = 0x24;
+const FAT32_EXTENDED_BOOT_RECORD: usize = 0x40;
+
+/// The boot code fills the rest of the sector after the extended boot record, so how much of it
+/// there is depends on the layout
+const FAT16_BOOT_CODE_LEN: usize = 448;
+const FAT32_BOOT_CODE_LEN: usize = 420;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// How many bits each FAT entry has
+    pub fn entry_bits(&self) -> u32 {
+        match self {
+            Self::Fat12 => 12,
+            Self::Fat16 => 16,
+            Self::Fat32 => 32,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct BootRecord {
+    /// offset 0x00
+    jump_boot: [u8; 3],
+    /// offset 0x03
+    oem_name: [u8; 8],
     // ----- BIOS Parameter Block -----
     /// offset 0x0B
     bytes_per_sector: u16,
@@ -13,21 +57,23 @@ pub struct BootRecord {
     num_reserved_sectors: u16,
     /// offset 0x10
     num_file_allocation_tables: u8,
-    // /// offset 0x11
-    // num_root_directory_entries: u16,
+    /// offset 0x11
+    num_root_directory_entries: u16,
     /// offset 0x13
     total_sectors: u16,
-    // /// offset 0x15
-    // media_descriptor: u8,
-    // /// offset 0x18
-    // sectors_per_track: u16,
-    // /// offset 0x1A
-    // num_heads: u16,
+    /// offset 0x15
+    media_descriptor: u8,
+    /// offset 0x16, always 0 on FAT32, which is how the layout of the rest is told apart
+    sectors_per_fat_16: u16,
+    /// offset 0x18
+    sectors_per_track: u16,
+    /// offset 0x1A
+    num_heads: u16,
     /// offset 0x1C
     num_hidden_sectors: u32,
     /// offset 0x20
     large_total_sectors: u32,
-    // ----- Extended Boot Record -----
+    // ----- FAT32 only, all 0 on FAT12 and FAT16 -----
     /// offset 0x24
     sectors_per_fat: u32,
     /// offset 0x28
@@ -40,60 +86,345 @@ pub struct BootRecord {
     fs_info_sector: u16,
     /// offset 0x32
     backup_boot_data_sector: u16,
-    // /// offset 0x40
-    // drive_number: u8,
+    /// offset 0x34
+    reserved: [u8; 12],
+    // ----- Extended Boot Record, offsets are for FAT32 and 0x1C less on FAT12 and FAT16 -----
+    /// offset 0x40
+    drive_number: u8,
+    /// offset 0x41
+    nt_flags: u8,
     /// offset 0x42
     signature: u8, // Must be 0x28 or 0x29
     /// offset 0x43
     volume_serial_number: u32,
     /// offset 0x47
     volume_label: [u8; 11],
+    /// offset 0x52
+    system_identifier: [u8; 8],
+    /// offset 0x5A, only the first 420 bytes are used on FAT32
+    boot_code: RawBytes<FAT16_BOOT_CODE_LEN>,
+    /// offset 0x1FE
+    boot_signature: u16,
 }
 
 impl BootRecord {
-    pub fn read(buffer: &[u8]) -> Self {
+    /// Creates a FAT32 boot record with the usual values for everything that doesn't depend on
+    /// the size of the volume, which has to be filled in with the setters
+    pub fn new() -> Self {
         Self {
+            // jmp short 0x5A; nop
+            jump_boot: [0xEB, 0x58, 0x90],
+            oem_name: *b"WUSTITE ",
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            num_reserved_sectors: 32,
+            num_file_allocation_tables: 2,
+            num_root_directory_entries: 0,
+            total_sectors: 0,
+            media_descriptor: 0xF8,
+            sectors_per_fat_16: 0,
+            sectors_per_track: 32,
+            num_heads: 64,
+            num_hidden_sectors: 0,
+            large_total_sectors: 0,
+            sectors_per_fat: 0,
+            flags: 0,
+            fat_version: 0,
+            root_directory_cluster: 2,
+            fs_info_sector: 1,
+            backup_boot_data_sector: 6,
+            reserved: [0; 12],
+            drive_number: 0x80,
+            nt_flags: 0,
+            signature: EXTENDED_BOOT_SIGNATURE,
+            volume_serial_number: 0,
+            volume_label: *b"NO NAME    ",
+            system_identifier: *b"FAT32   ",
+            boot_code: RawBytes([0; FAT16_BOOT_CODE_LEN]),
+            boot_signature: BOOT_SIGNATURE,
+        }
+    }
+
+    pub fn read(buffer: &[u8]) -> Self {
+        let sectors_per_fat_16 = read_u16_le(buffer, 0x16);
+        let is_fat32 = sectors_per_fat_16 == 0;
+
+        let mut record = Self {
+            jump_boot: read_padded_str(buffer, 0x00),
+            oem_name: read_padded_str(buffer, 0x03),
             bytes_per_sector: read_u16_le(buffer, 0x0B),
             sectors_per_cluster: buffer[0x0D],
             num_reserved_sectors: read_u16_le(buffer, 0x0E),
             num_file_allocation_tables: buffer[0x10],
-            // num_root_directory_entries: read_u16_le(buffer, 0x11),
+            num_root_directory_entries: read_u16_le(buffer, 0x11),
             total_sectors: read_u16_le(buffer, 0x13),
-            // media_descriptor: buffer[0x15],
-            // sectors_per_track: read_u16_le(buffer, 0x18),
-            // num_heads: read_u16_le(buffer, 0x1A),
+            media_descriptor: buffer[0x15],
+            sectors_per_fat_16,
+            sectors_per_track: read_u16_le(buffer, 0x18),
+            num_heads: read_u16_le(buffer, 0x1A),
             num_hidden_sectors: read_u32_le(buffer, 0x1C),
             large_total_sectors: read_u32_le(buffer, 0x20),
-            sectors_per_fat: read_u32_le(buffer, 0x24),
-            flags: read_u16_le(buffer, 0x28),
-            fat_version: read_u16_le(buffer, 0x2A),
-            root_directory_cluster: read_u32_le(buffer, 0x2C),
-            fs_info_sector: read_u16_le(buffer, 0x30),
-            backup_boot_data_sector: read_u16_le(buffer, 0x32),
-            // drive_number: buffer[0x40],
-            signature: buffer[0x42], // Must be 0x28 or 0x29
-            volume_serial_number: read_u32_le(buffer, 0x43),
-            volume_label: read_padded_str(buffer, 0x47),
+            sectors_per_fat: 0,
+            flags: 0,
+            fat_version: 0,
+            root_directory_cluster: 0,
+            fs_info_sector: 0,
+            backup_boot_data_sector: 0,
+            reserved: [0; 12],
+            drive_number: 0,
+            nt_flags: 0,
+            signature: 0,
+            volume_serial_number: 0,
+            volume_label: [0; 11],
+            system_identifier: [0; 8],
+            boot_code: RawBytes([0; FAT16_BOOT_CODE_LEN]),
+            boot_signature: read_u16_le(buffer, 0x1FE),
+        };
+
+        if is_fat32 {
+            record.sectors_per_fat = read_u32_le(buffer, 0x24);
+            record.flags = read_u16_le(buffer, 0x28);
+            record.fat_version = read_u16_le(buffer, 0x2A);
+            record.root_directory_cluster = read_u32_le(buffer, 0x2C);
+            record.fs_info_sector = read_u16_le(buffer, 0x30);
+            record.backup_boot_data_sector = read_u16_le(buffer, 0x32);
+            record.reserved = read_padded_str(buffer, 0x34);
         }
+
+        let ebr = record.extended_boot_record_offset();
+
+        record.drive_number = buffer[ebr];
+        record.nt_flags = buffer[ebr + 0x01];
+        record.signature = buffer[ebr + 0x02];
+        record.volume_serial_number = read_u32_le(buffer, ebr + 0x03);
+        record.volume_label = read_padded_str(buffer, ebr + 0x07);
+        record.system_identifier = read_padded_str(buffer, ebr + 0x12);
+
+        let boot_code_len = record.boot_code_len();
+        record.boot_code.0[..boot_code_len]
+            .copy_from_slice(&buffer[ebr + 0x1A..ebr + 0x1A + boot_code_len]);
+
+        record
+    }
+
+    /// Writes the record into the first 512 bytes of `buffer`
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0x00..0x03].copy_from_slice(&self.jump_boot);
+        buffer[0x03..0x0B].copy_from_slice(&self.oem_name);
+        write_u16_le(buffer, 0x0B, self.bytes_per_sector);
+        buffer[0x0D] = self.sectors_per_cluster;
+        write_u16_le(buffer, 0x0E, self.num_reserved_sectors);
+        buffer[0x10] = self.num_file_allocation_tables;
+        write_u16_le(buffer, 0x11, self.num_root_directory_entries);
+        write_u16_le(buffer, 0x13, self.total_sectors);
+        buffer[0x15] = self.media_descriptor;
+        write_u16_le(buffer, 0x16, self.sectors_per_fat_16);
+        write_u16_le(buffer, 0x18, self.sectors_per_track);
+        write_u16_le(buffer, 0x1A, self.num_heads);
+        write_u32_le(buffer, 0x1C, self.num_hidden_sectors);
+        write_u32_le(buffer, 0x20, self.large_total_sectors);
+        if self.is_fat32_layout() {
+            write_u32_le(buffer, 0x24, self.sectors_per_fat);
+            write_u16_le(buffer, 0x28, self.flags);
+            write_u16_le(buffer, 0x2A, self.fat_version);
+            write_u32_le(buffer, 0x2C, self.root_directory_cluster);
+            write_u16_le(buffer, 0x30, self.fs_info_sector);
+            write_u16_le(buffer, 0x32, self.backup_boot_data_sector);
+            buffer[0x34..0x40].copy_from_slice(&self.reserved);
+        }
+
+        let ebr = self.extended_boot_record_offset();
+        let boot_code_len = self.boot_code_len();
+
+        buffer[ebr] = self.drive_number;
+        buffer[ebr + 0x01] = self.nt_flags;
+        buffer[ebr + 0x02] = self.signature;
+        write_u32_le(buffer, ebr + 0x03, self.volume_serial_number);
+        buffer[ebr + 0x07..ebr + 0x12].copy_from_slice(&self.volume_label);
+        buffer[ebr + 0x12..ebr + 0x1A].copy_from_slice(&self.system_identifier);
+        buffer[ebr + 0x1A..ebr + 0x1A + boot_code_len]
+            .copy_from_slice(&self.boot_code.0[..boot_code_len]);
+        write_u16_le(buffer, 0x1FE, self.boot_signature);
+    }
+
+    pub fn jump_boot(&self) -> &[u8; 3] {
+        &self.jump_boot
+    }
+
+    pub fn set_jump_boot(&mut self, jump_boot: [u8; 3]) {
+        self.jump_boot = jump_boot;
+    }
+
+    pub fn oem_name(&self) -> &[u8; 8] {
+        &self.oem_name
+    }
+
+    pub fn set_oem_name(&mut self, oem_name: [u8; 8]) {
+        self.oem_name = oem_name;
     }
 
     pub fn bytes_per_sector(&self) -> u16 {
         self.bytes_per_sector
     }
 
+    pub fn set_bytes_per_sector(&mut self, bytes_per_sector: u16) {
+        self.bytes_per_sector = bytes_per_sector;
+    }
+
     pub fn sectors_per_cluster(&self) -> u64 {
         self.sectors_per_cluster as u64
     }
 
+    pub fn set_sectors_per_cluster(&mut self, sectors_per_cluster: u8) {
+        self.sectors_per_cluster = sectors_per_cluster;
+    }
+
+    pub fn num_reserved_sectors(&self) -> u16 {
+        self.num_reserved_sectors
+    }
+
+    pub fn set_num_reserved_sectors(&mut self, num_reserved_sectors: u16) {
+        self.num_reserved_sectors = num_reserved_sectors;
+    }
+
+    pub fn num_root_directory_entries(&self) -> u16 {
+        self.num_root_directory_entries
+    }
+
+    pub fn set_num_root_directory_entries(&mut self, num_root_directory_entries: u16) {
+        self.num_root_directory_entries = num_root_directory_entries;
+    }
+
+    pub fn media_descriptor(&self) -> u8 {
+        self.media_descriptor
+    }
+
+    pub fn set_media_descriptor(&mut self, media_descriptor: u8) {
+        self.media_descriptor = media_descriptor;
+    }
+
+    pub fn sectors_per_track(&self) -> u16 {
+        self.sectors_per_track
+    }
+
+    pub fn set_sectors_per_track(&mut self, sectors_per_track: u16) {
+        self.sectors_per_track = sectors_per_track;
+    }
+
+    pub fn num_heads(&self) -> u16 {
+        self.num_heads
+    }
+
+    pub fn set_num_heads(&mut self, num_heads: u16) {
+        self.num_heads = num_heads;
+    }
+
+    pub fn num_hidden_sectors(&self) -> u32 {
+        self.num_hidden_sectors
+    }
+
+    pub fn set_num_hidden_sectors(&mut self, num_hidden_sectors: u32) {
+        self.num_hidden_sectors = num_hidden_sectors;
+    }
+
     pub fn first_data_sector(&self) -> u64 {
+        self.root_directory_sector() + self.root_directory_sectors()
+    }
+
+    /// Where the fixed root directory region of FAT12 and FAT16 starts, right after the FATs
+    pub fn root_directory_sector(&self) -> u64 {
         self.num_reserved_sectors as u64
-            + (self.num_file_allocation_tables as u64 * self.sectors_per_fat as u64)
+            + (self.num_file_allocation_tables as u64 * self.sectors_per_fat() as u64)
+    }
+
+    /// The size of the fixed root directory region, which is 0 on FAT32
+    pub fn root_directory_sectors(&self) -> u64 {
+        (self.num_root_directory_entries as u64 * 32).div_ceil(self.bytes_per_sector.max(1) as u64)
+    }
+
+    /// Whether the BIOS parameter block has the FAT32 fields, which is the case when the 16-bit
+    /// sectors per FAT field is 0
+    pub fn is_fat32_layout(&self) -> bool {
+        self.sectors_per_fat_16 == 0
+    }
+
+    /// The FAT type, which Microsoft's specification decides from the cluster count alone.
+    /// Volumes with the FAT32 layout are always FAT32 though, like Linux treats them, since
+    /// `mkfs.fat -F 32` and our own formatter make FAT32 volumes with fewer clusters than that.
+    pub fn fat_type(&self) -> FatType {
+        let cluster_count = self.cluster_count();
+
+        if self.is_fat32_layout() || cluster_count >= MAX_FAT16_CLUSTERS {
+            FatType::Fat32
+        } else if cluster_count < MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        }
+    }
+
+    fn extended_boot_record_offset(&self) -> usize {
+        if self.is_fat32_layout() {
+            FAT32_EXTENDED_BOOT_RECORD
+        } else {
+            FAT16_EXTENDED_BOOT_RECORD
+        }
+    }
+
+    fn boot_code_len(&self) -> usize {
+        if self.is_fat32_layout() {
+            FAT32_BOOT_CODE_LEN
+        } else {
+            FAT16_BOOT_CODE_LEN
+        }
     }
 
     pub fn first_fat_sector(&self) -> u64 {
         self.num_reserved_sectors as u64
     }
 
+    pub fn num_file_allocation_tables(&self) -> u8 {
+        self.num_file_allocation_tables
+    }
+
+    pub fn set_num_file_allocation_tables(&mut self, num_file_allocation_tables: u8) {
+        self.num_file_allocation_tables = num_file_allocation_tables;
+    }
+
+    /// From whichever field the layout uses
+    pub fn sectors_per_fat(&self) -> u32 {
+        if self.is_fat32_layout() {
+            self.sectors_per_fat
+        } else {
+            self.sectors_per_fat_16 as u32
+        }
+    }
+
+    /// Sets the 32-bit field, so this only works for the FAT32 layout. See
+    /// `set_sectors_per_fat_16` for FAT12 and FAT16.
+    pub fn set_sectors_per_fat(&mut self, sectors_per_fat: u32) {
+        self.sectors_per_fat = sectors_per_fat;
+    }
+
+    pub fn sectors_per_fat_16(&self) -> u16 {
+        self.sectors_per_fat_16
+    }
+
+    /// Setting this to anything but 0 switches to the FAT12 and FAT16 layout, which has no
+    /// FAT32 fields
+    pub fn set_sectors_per_fat_16(&mut self, sectors_per_fat: u16) {
+        self.sectors_per_fat_16 = sectors_per_fat;
+    }
+
+    /// The number of clusters in the data region, so valid clusters are 2..cluster_count() + 2
+    pub fn cluster_count(&self) -> u32 {
+        let data_sectors = self.num_sectors().saturating_sub(self.first_data_sector());
+
+        data_sectors
+            .checked_div(self.sectors_per_cluster())
+            .unwrap_or(0) as u32
+    }
+
     pub fn num_sectors(&self) -> u64 {
         if self.total_sectors == 0 {
             self.large_total_sectors as u64
@@ -102,11 +433,153 @@ impl BootRecord {
         }
     }
 
+    /// Stores the sector count in the 16-bit field if it fits, and in the 32-bit one otherwise
+    pub fn set_num_sectors(&mut self, num_sectors: u32) {
+        if num_sectors <= u16::MAX as u32 {
+            self.total_sectors = num_sectors as u16;
+            self.large_total_sectors = 0;
+        } else {
+            self.total_sectors = 0;
+            self.large_total_sectors = num_sectors;
+        }
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: u16) {
+        self.flags = flags;
+    }
+
+    pub fn fat_version(&self) -> u16 {
+        self.fat_version
+    }
+
+    pub fn set_fat_version(&mut self, fat_version: u16) {
+        self.fat_version = fat_version;
+    }
+
+    /// 0 on FAT12 and FAT16, where the root directory is in its own region before the clusters
     pub fn root_directory_cluster(&self) -> u32 {
         self.root_directory_cluster
     }
 
+    pub fn set_root_directory_cluster(&mut self, root_directory_cluster: u32) {
+        self.root_directory_cluster = root_directory_cluster;
+    }
+
     pub fn fs_info_sector(&self) -> u64 {
         self.fs_info_sector as u64
     }
+
+    pub fn set_fs_info_sector(&mut self, fs_info_sector: u16) {
+        self.fs_info_sector = fs_info_sector;
+    }
+
+    pub fn backup_boot_sector(&self) -> u64 {
+        self.backup_boot_data_sector as u64
+    }
+
+    pub fn set_backup_boot_sector(&mut self, backup_boot_sector: u16) {
+        self.backup_boot_data_sector = backup_boot_sector;
+    }
+
+    pub fn drive_number(&self) -> u8 {
+        self.drive_number
+    }
+
+    pub fn set_drive_number(&mut self, drive_number: u8) {
+        self.drive_number = drive_number;
+    }
+
+    pub fn signature(&self) -> u8 {
+        self.signature
+    }
+
+    pub fn set_signature(&mut self, signature: u8) {
+        self.signature = signature;
+    }
+
+    pub fn volume_serial_number(&self) -> u32 {
+        self.volume_serial_number
+    }
+
+    pub fn set_volume_serial_number(&mut self, volume_serial_number: u32) {
+        self.volume_serial_number = volume_serial_number;
+    }
+
+    pub fn volume_label(&self) -> &[u8; 11] {
+        &self.volume_label
+    }
+
+    pub fn set_volume_label(&mut self, volume_label: [u8; 11]) {
+        self.volume_label = volume_label;
+    }
+
+    pub fn system_identifier(&self) -> &[u8; 8] {
+        &self.system_identifier
+    }
+
+    pub fn set_system_identifier(&mut self, system_identifier: [u8; 8]) {
+        self.system_identifier = system_identifier;
+    }
+
+    /// 420 bytes with the FAT32 layout, 448 with the FAT12 and FAT16 one
+    pub fn boot_code(&self) -> &[u8] {
+        &self.boot_code.0[..self.boot_code_len()]
+    }
+
+    /// Copies as much of `boot_code` as fits in the boot code area of the layout
+    pub fn set_boot_code(&mut self, boot_code: &[u8]) {
+        let len = boot_code.len().min(self.boot_code_len());
+
+        self.boot_code.0[..len].copy_from_slice(&boot_code[..len]);
+    }
+
+    pub fn boot_signature(&self) -> u16 {
+        self.boot_signature
+    }
+
+    pub fn set_boot_signature(&mut self, boot_signature: u16) {
+        self.boot_signature = boot_signature;
+    }
+}
+
+impl Default for BootRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    proptest! {
+        #[test]
+        fn fat32_layout_round_trips(mut sector in vec(any::<u8>(), 512)) {
+            // A 16-bit sectors per FAT of 0 selects the FAT32 layout
+            sector[0x16..0x18].fill(0);
+
+            let mut output = [0u8; 512];
+            BootRecord::read(&sector).write(&mut output);
+
+            prop_assert_eq!(&output[..], &sector[..]);
+        }
+
+        #[test]
+        fn fat16_layout_round_trips(
+            mut sector in vec(any::<u8>(), 512),
+            sectors_per_fat in 1..=u16::MAX,
+        ) {
+            sector[0x16..0x18].copy_from_slice(&sectors_per_fat.to_le_bytes());
+
+            let mut output = [0u8; 512];
+            BootRecord::read(&sector).write(&mut output);
+
+            prop_assert_eq!(&output[..], &sector[..]);
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use core::fmt::Display;
+
+/// The year a date of 0 is in
+pub const DOS_EPOCH_YEAR: u16 = 1980;
+
+/// A date as stored in a directory entry: bits 15-9 are the years since 1980, 8-5 the month
+/// and 4-0 the day of the month
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DosDate {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl DosDate {
+    /// Returns None if the month or day is out of range, which includes a date of 0, the value
+    /// of dates that were never set
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Self> {
+        if !(DOS_EPOCH_YEAR..=DOS_EPOCH_YEAR + 127).contains(&year)
+            || !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+        {
+            return None;
+        }
+
+        Some(Self { year, month, day })
+    }
+
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        Self::new(
+            DOS_EPOCH_YEAR + (raw >> 9),
+            ((raw >> 5) & 0x0F) as u8,
+            (raw & 0x1F) as u8,
+        )
+    }
+
+    pub fn to_raw(&self) -> u16 {
+        ((self.year - DOS_EPOCH_YEAR) << 9) | ((self.month as u16) << 5) | self.day as u16
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// 1 to 12
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// 1 to 31
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+}
+
+impl Display for DosDate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// A time of day as stored in a directory entry: bits 15-11 are the hours, 10-5 the minutes and
+/// 4-0 the seconds divided by two. Creation times have an extra byte of 10 ms units, 0 to 199,
+/// which adds the odd second and the fraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DosTime {
+    hour: u8,
+    minute: u8,
+    second: u8,
+    millisecond: u16,
+}
+
+impl DosTime {
+    /// Returns None if any part is out of range
+    pub fn new(hour: u8, minute: u8, second: u8, millisecond: u16) -> Option<Self> {
+        if hour > 23 || minute > 59 || second > 59 || millisecond > 999 {
+            return None;
+        }
+
+        Some(Self {
+            hour,
+            minute,
+            second,
+            millisecond,
+        })
+    }
+
+    /// Decodes a time with two second resolution
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        Self::from_raw_fine(raw, 0)
+    }
+
+    /// Decodes a time together with its count of 10 ms units
+    pub fn from_raw_fine(raw: u16, centiseconds: u8) -> Option<Self> {
+        if centiseconds > 199 {
+            return None;
+        }
+
+        Self::new(
+            (raw >> 11) as u8,
+            ((raw >> 5) & 0x3F) as u8,
+            (raw & 0x1F) as u8 * 2 + centiseconds / 100,
+            (centiseconds % 100) as u16 * 10,
+        )
+    }
+
+    /// The time with two second resolution, rounded down
+    pub fn to_raw(&self) -> u16 {
+        ((self.hour as u16) << 11) | ((self.minute as u16) << 5) | (self.second / 2) as u16
+    }
+
+    /// The count of 10 ms units that goes with `to_raw`
+    pub fn to_raw_fine(&self) -> u8 {
+        (self.second % 2) * 100 + (self.millisecond / 10) as u8
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    pub fn millisecond(&self) -> u16 {
+        self.millisecond
+    }
+}
+
+impl Display for DosTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DosDateTime {
+    pub date: DosDate,
+    pub time: DosTime,
+}
+
+impl DosDateTime {
+    /// Returns None if the date is unset or either part is invalid
+    pub fn from_raw(date: u16, time: u16, centiseconds: u8) -> Option<Self> {
+        Some(Self {
+            date: DosDate::from_raw(date)?,
+            time: DosTime::from_raw_fine(time, centiseconds)?,
+        })
+    }
+}
+
+impl Display for DosDateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}